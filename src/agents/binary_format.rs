@@ -0,0 +1,321 @@
+//! A compact binary format for saved agents.
+//!
+//! Every file starts with the same header, all numbers little-endian:
+//!
+//! | Bytes | Content                                        |
+//! |-------|------------------------------------------------|
+//! | 4     | magic bytes `RLAG`                             |
+//! | 2     | format version, `u16`                          |
+//! | 1     | agent kind, see [`AgentKind`]                  |
+//! | 4 + n | environment id, `u32` length and UTF-8 bytes   |
+//! | 4 + n | [`ModelMetadata`] as JSON, since version 2      |
+//!
+//! The agent then writes its space shapes, its hyperparameters and its payload,
+//! e.g. the Q-table as `f32`s or the weights of a network as `f64`s. Sequences are
+//! written as a `u64` length followed by their elements.
+//!
+//! Files that do not start with the magic bytes are read as the legacy JSON.
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::agents::metadata::ModelMetadata;
+
+pub const MAGIC: [u8; 4] = *b"RLAG";
+/// The magic bytes of the replay buffer files that `DQNAgent` saves next to itself.
+pub const REPLAY_MAGIC: [u8; 4] = *b"RLRB";
+
+/// The version written by this build. Files with a newer version are rejected.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Which agent a file holds, so that loading the wrong agent fails early.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AgentKind {
+    Q = 1,
+    Dqn = 2,
+}
+
+impl AgentKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(AgentKind::Q),
+            2 => Some(AgentKind::Dqn),
+            _ => None,
+        }
+    }
+}
+
+/// The start of every binary file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub kind: AgentKind,
+    /// Version 1 files only hold the environment id of the metadata.
+    pub metadata: ModelMetadata,
+}
+
+/// How a saved agent is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Binary,
+}
+
+/// Looks at the first bytes of `reader` without consuming them.
+pub fn detect(reader: &mut impl BufRead) -> io::Result<Format> {
+    let start = reader.fill_buf()?;
+    Ok(if start.starts_with(&MAGIC) {
+        Format::Binary
+    } else {
+        Format::Json
+    })
+}
+
+pub(crate) fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Writes the values of the format in little-endian order.
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Writer { inner }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    pub fn header(&mut self, kind: AgentKind, metadata: &ModelMetadata) -> io::Result<()> {
+        self.inner.write_all(&MAGIC)?;
+        self.u16(FORMAT_VERSION)?;
+        self.inner.write_all(&[kind as u8])?;
+        self.str(&metadata.environment)?;
+        self.str(&serde_json::to_string(metadata)?)
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)
+    }
+
+    pub fn u16(&mut self, value: u16) -> io::Result<()> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub fn usize(&mut self, value: usize) -> io::Result<()> {
+        self.u64(value as u64)
+    }
+
+    pub fn f32(&mut self, value: f32) -> io::Result<()> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub fn f64(&mut self, value: f64) -> io::Result<()> {
+        self.inner.write_all(&value.to_le_bytes())
+    }
+
+    pub fn str(&mut self, value: &str) -> io::Result<()> {
+        let len = u32::try_from(value.len()).map_err(|_| invalid_data("string too long"))?;
+        self.u32(len)?;
+        self.inner.write_all(value.as_bytes())
+    }
+
+    pub fn usizes(&mut self, values: &[usize]) -> io::Result<()> {
+        self.usize(values.len())?;
+        values.iter().try_for_each(|&v| self.usize(v))
+    }
+
+    pub fn f32s(&mut self, values: &[f32]) -> io::Result<()> {
+        self.usize(values.len())?;
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.inner.write_all(&bytes)
+    }
+
+    pub fn f64s(&mut self, values: &[f64]) -> io::Result<()> {
+        self.usize(values.len())?;
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.inner.write_all(&bytes)
+    }
+}
+
+/// Reads the values written by [`Writer`].
+pub struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Reader { inner }
+    }
+
+    /// Reads the header and checks that it holds a supported version of `kind`.
+    pub fn header(&mut self, kind: AgentKind) -> io::Result<Header> {
+        let magic: [u8; 4] = self.bytes()?;
+        if magic != MAGIC {
+            return Err(invalid_data("not a binary agent file"));
+        }
+        let version = self.u16()?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported format version {version}, expected at most {FORMAT_VERSION}"
+            )));
+        }
+        let [byte] = self.bytes()?;
+        let found = AgentKind::from_byte(byte)
+            .ok_or_else(|| invalid_data(format!("unknown agent kind {byte}")))?;
+        if found != kind {
+            return Err(invalid_data(format!(
+                "the file holds a {found:?} agent, not a {kind:?} agent"
+            )));
+        }
+        let environment = self.str()?;
+        let metadata = if version >= 2 {
+            serde_json::from_str(&self.str()?)?
+        } else {
+            ModelMetadata {
+                environment,
+                ..Default::default()
+            }
+        };
+        Ok(Header {
+            version,
+            kind,
+            metadata,
+        })
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.inner.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    pub fn usize(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid_data("length does not fit into usize"))
+    }
+
+    pub fn f32(&mut self) -> io::Result<f32> {
+        self.bytes().map(f32::from_le_bytes)
+    }
+
+    pub fn f64(&mut self) -> io::Result<f64> {
+        self.bytes().map(f64::from_le_bytes)
+    }
+
+    pub fn str(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.exact(len)?;
+        String::from_utf8(bytes).map_err(|_| invalid_data("string is not UTF-8"))
+    }
+
+    /// Reads `len` bytes, without trusting `len` with a large allocation up front.
+    fn exact(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+
+    pub fn usizes(&mut self) -> io::Result<Vec<usize>> {
+        let len = self.usize()?;
+        (0..len).map(|_| self.usize()).collect()
+    }
+
+    pub fn f32s(&mut self) -> io::Result<Vec<f32>> {
+        let len = self.usize()?;
+        let bytes = self.exact(len.checked_mul(4).ok_or_else(|| invalid_data("too long"))?)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+
+    pub fn f64s(&mut self) -> io::Result<Vec<f64>> {
+        let len = self.usize()?;
+        let bytes = self.exact(len.checked_mul(8).ok_or_else(|| invalid_data("too long"))?)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_round_trip() {
+        let metadata = ModelMetadata {
+            environment: "grid".to_string(),
+            episodes: 12,
+            ..Default::default()
+        };
+        let mut writer = Writer::new(Vec::new());
+        writer.header(AgentKind::Q, &metadata).unwrap();
+        writer.usizes(&[3, 3]).unwrap();
+        writer.f32s(&[0.5, -1.25]).unwrap();
+        writer.f64(f64::MIN_POSITIVE).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(&bytes[..4], b"RLAG");
+        // Version 2 in little-endian order, then the agent kind.
+        assert_eq!(&bytes[4..7], &[2, 0, 1]);
+        assert_eq!(detect(&mut &bytes[..]).unwrap(), Format::Binary);
+        assert_eq!(detect(&mut &b"{\"q_table\":[]}"[..]).unwrap(), Format::Json);
+
+        let mut reader = Reader::new(&bytes[..]);
+        let header = reader.header(AgentKind::Q).unwrap();
+        assert_eq!(header.metadata, metadata);
+        assert_eq!(reader.usizes().unwrap(), vec![3, 3]);
+        assert_eq!(reader.f32s().unwrap(), vec![0.5, -1.25]);
+        assert_eq!(reader.f64().unwrap(), f64::MIN_POSITIVE);
+        assert!(reader.u16().is_err());
+
+        let wrong_kind = Reader::new(&bytes[..]).header(AgentKind::Dqn).unwrap_err();
+        assert_eq!(wrong_kind.kind(), io::ErrorKind::InvalidData);
+        let mut newer = bytes.clone();
+        newer[4] = 3;
+        assert!(Reader::new(&newer[..]).header(AgentKind::Q).is_err());
+        // Version 1 files have no metadata besides the environment id.
+        let mut old = Writer::new(Vec::new());
+        old.inner.write_all(&MAGIC).unwrap();
+        old.u16(1).unwrap();
+        old.inner.write_all(&[AgentKind::Q as u8]).unwrap();
+        old.str("grid").unwrap();
+        let old = Reader::new(&old.into_inner()[..])
+            .header(AgentKind::Q)
+            .unwrap();
+        assert_eq!(old.metadata.environment, "grid");
+        assert_eq!(old.metadata.episodes, 0);
+        // A length larger than the file fails instead of allocating.
+        let mut truncated = Writer::new(Vec::new());
+        truncated.u64(u64::MAX / 16).unwrap();
+        let truncated = truncated.into_inner();
+        assert!(Reader::new(&truncated[..]).f64s().is_err());
+    }
+}
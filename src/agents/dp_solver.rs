@@ -0,0 +1,351 @@
+use crate::{
+    agents::q_agent::{all_actions, all_elems_as_vec, QAgent, GAMMA_DEFAULT},
+    Agent, ModelEnvironment, SpaceElem,
+};
+
+pub const THETA_DEFAULT: f32 = 1e-6;
+pub const MAX_ITERATIONS_DEFAULT: usize = 10_000;
+
+/// The outcomes `(probability, reward, next state index)` of every action in
+/// every state, indexed like the Q-table of a [`QAgent`].
+struct TabularModel {
+    action_space_size: usize,
+    outcomes: Vec<Vec<(f32, f32, Option<usize>)>>,
+    /// States without outcomes, i.e. terminal or invalid states.
+    terminal: Vec<bool>,
+}
+
+impl TabularModel {
+    fn build<E: ModelEnvironment>(env: &E, agent: &QAgent) -> Self {
+        let action_space_size = agent.action_space_size;
+        let mut outcomes = Vec::with_capacity(agent.state_space_size * action_space_size);
+        let mut terminal = Vec::with_capacity(agent.state_space_size);
+        for state in all_elems_as_vec(&agent.state_space) {
+            let Some(state) = E::State::try_build(&agent.state_space.as_slice(), &state, &[])
+            else {
+                // Not a reachable state, e.g. a tic-tac-toe board with too many X's.
+                outcomes.extend(std::iter::repeat_n(vec![], action_space_size));
+                terminal.push(true);
+                continue;
+            };
+            let mut is_terminal = true;
+            for action in all_actions::<E::Action>(&agent.action_space) {
+                let transitions = env
+                    .transitions(&state, &action)
+                    .into_iter()
+                    .map(|t| {
+                        let next_i = t.next_state.map(|next| agent.state_index(&next));
+                        (t.probability, t.reward, next_i)
+                    })
+                    .collect::<Vec<_>>();
+                is_terminal &= transitions.is_empty();
+                outcomes.push(transitions);
+            }
+            terminal.push(is_terminal);
+        }
+        TabularModel {
+            action_space_size,
+            outcomes,
+            terminal,
+        }
+    }
+
+    fn state_count(&self) -> usize {
+        self.terminal.len()
+    }
+
+    /// `Q(s, a) = Σ p · (r + γ · V(s'))`
+    fn q_value(&self, values: &[f32], gamma: f32, state_i: usize, action_i: usize) -> f32 {
+        self.outcomes[state_i * self.action_space_size + action_i]
+            .iter()
+            .map(|&(p, r, next_i)| p * (r + gamma * next_i.map_or(0.0, |n| values[n])))
+            .sum()
+    }
+
+    /// The greedy action and its value, the first action wins ties.
+    fn greedy(&self, values: &[f32], gamma: f32, state_i: usize) -> (usize, f32) {
+        let mut best = (0, f32::MIN);
+        for action_i in 0..self.action_space_size {
+            let q = self.q_value(values, gamma, state_i, action_i);
+            if q > best.1 {
+                best = (action_i, q);
+            }
+        }
+        best
+    }
+}
+
+/// The result of a dynamic programming solver.
+#[derive(Debug, Clone)]
+pub struct Solution {
+    /// The optimal state values `V*(s)`, indexed like the states of a [`QAgent`].
+    pub values: Vec<f32>,
+    /// The optimal action index for every state.
+    pub policy: Vec<usize>,
+    /// Number of sweeps (value iteration) or improvement steps (policy iteration).
+    pub iterations: usize,
+    /// A Q-table holding `Q*(s, a)`, whose greedy policy is the optimal one.
+    pub agent: QAgent,
+    terminal: Vec<bool>,
+}
+
+/// How close the greedy policy of a learned agent is to the optimal policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolicyComparison {
+    /// Fraction of non-terminal states in which the agent picks an optimal action.
+    pub agreement: f32,
+    /// Mean of `V*(s) − Q*(s, π(s))` over the non-terminal states.
+    pub mean_regret: f32,
+    /// Largest `V*(s) − Q*(s, π(s))` over the non-terminal states.
+    pub max_regret: f32,
+}
+
+impl Solution {
+    /// Compares the greedy policy of `agent` with the optimal one.
+    /// The agent has to be initialized for the same environment as the solution.
+    pub fn compare(&self, agent: &QAgent) -> PolicyComparison {
+        assert_eq!(
+            agent.q_table.len(),
+            self.agent.q_table.len(),
+            "Agent was not trained on the solved environment"
+        );
+        let mut states = 0;
+        let mut agreeing = 0;
+        let mut total_regret = 0.0;
+        let mut max_regret: f32 = 0.0;
+        for (state_i, &terminal) in self.terminal.iter().enumerate() {
+            if terminal {
+                continue;
+            }
+            let row = state_i * self.agent.action_space_size;
+            let q_star = self.agent.q_table[row + agent.best_action_index(state_i)];
+            let regret = (self.values[state_i] - q_star).max(0.0);
+            states += 1;
+            if regret <= 1e-4 * self.values[state_i].abs().max(1.0) {
+                agreeing += 1;
+            }
+            total_regret += regret;
+            max_regret = max_regret.max(regret);
+        }
+        let states = states.max(1) as f32;
+        PolicyComparison {
+            agreement: agreeing as f32 / states,
+            mean_regret: total_regret / states,
+            max_regret,
+        }
+    }
+}
+
+/// Dynamic programming solvers for environments with a known model.
+///
+/// Every solver returns the exact optimal values and policy, up to the
+/// convergence threshold `theta`, as a [`Solution`] whose `agent` can be saved
+/// and served like any learned `QAgent`.
+#[derive(Debug, Clone)]
+pub struct DpSolver {
+    /// Discount factor 𝛾 where (0 ≤ γ < 1)
+    pub gamma: f32,
+    /// The solvers stop once no value changes by more than θ in a sweep.
+    pub theta: f32,
+    /// Upper bound on the number of sweeps or improvement steps.
+    pub max_iterations: usize,
+}
+
+impl Default for DpSolver {
+    fn default() -> Self {
+        Self::new(GAMMA_DEFAULT)
+    }
+}
+
+impl DpSolver {
+    pub fn new(gamma: f32) -> Self {
+        DpSolver {
+            gamma,
+            theta: THETA_DEFAULT,
+            max_iterations: MAX_ITERATIONS_DEFAULT,
+        }
+    }
+
+    /// Repeats the Bellman optimality backup
+    ///
+    /// ```math
+    /// V(s) ← maxₐ Σ p(s', r | s, a) · (r + γ · V(s'))
+    /// ```
+    ///
+    /// for all states until the values stop changing.
+    pub fn value_iteration<E: ModelEnvironment>(&self, env: &E) -> Solution {
+        let (agent, model) = self.build_model(env);
+        let mut values = vec![0.0; model.state_count()];
+        let mut iterations = 0;
+        while iterations < self.max_iterations {
+            iterations += 1;
+            let mut delta: f32 = 0.0;
+            for state_i in 0..model.state_count() {
+                if model.terminal[state_i] {
+                    continue;
+                }
+                let (_, value) = model.greedy(&values, self.gamma, state_i);
+                delta = delta.max((value - values[state_i]).abs());
+                values[state_i] = value;
+            }
+            if delta < self.theta {
+                break;
+            }
+        }
+        self.solution(agent, model, values, iterations)
+    }
+
+    /// Alternates full policy evaluation with greedy policy improvement,
+    /// until the policy no longer changes.
+    pub fn policy_iteration<E: ModelEnvironment>(&self, env: &E) -> Solution {
+        self.policy_iteration_inner(env, None)
+    }
+
+    /// Like policy iteration, but each evaluation only runs
+    /// `evaluation_sweeps` sweeps instead of running until convergence.
+    /// One sweep is close to value iteration, many sweeps to policy iteration.
+    pub fn modified_policy_iteration<E: ModelEnvironment>(
+        &self,
+        env: &E,
+        evaluation_sweeps: usize,
+    ) -> Solution {
+        assert!(evaluation_sweeps > 0, "Evaluation needs at least one sweep");
+        self.policy_iteration_inner(env, Some(evaluation_sweeps))
+    }
+
+    fn policy_iteration_inner<E: ModelEnvironment>(
+        &self,
+        env: &E,
+        evaluation_sweeps: Option<usize>,
+    ) -> Solution {
+        let (agent, model) = self.build_model(env);
+        let mut values = vec![0.0; model.state_count()];
+        let mut policy = vec![0; model.state_count()];
+        let mut iterations = 0;
+        while iterations < self.max_iterations {
+            iterations += 1;
+            // Policy evaluation, in place.
+            let mut sweeps = 0;
+            let mut delta = f32::MAX;
+            while delta >= self.theta && evaluation_sweeps.is_none_or(|max| sweeps < max) {
+                sweeps += 1;
+                delta = 0.0;
+                for state_i in 0..model.state_count() {
+                    if model.terminal[state_i] {
+                        continue;
+                    }
+                    let value = model.q_value(&values, self.gamma, state_i, policy[state_i]);
+                    delta = delta.max((value - values[state_i]).abs());
+                    values[state_i] = value;
+                }
+            }
+
+            // Policy improvement, an action is only replaced by a strictly better
+            // one so that ties cannot make the policy oscillate.
+            let mut stable = true;
+            let mut residual: f32 = 0.0;
+            for state_i in 0..model.state_count() {
+                if model.terminal[state_i] {
+                    continue;
+                }
+                let current = model.q_value(&values, self.gamma, state_i, policy[state_i]);
+                let (best_action, best_value) = model.greedy(&values, self.gamma, state_i);
+                residual = residual.max((best_value - values[state_i]).abs());
+                if best_value > current + self.theta {
+                    policy[state_i] = best_action;
+                    stable = false;
+                }
+            }
+            if stable && residual < self.theta {
+                break;
+            }
+        }
+        self.solution(agent, model, values, iterations)
+    }
+
+    fn build_model<E: ModelEnvironment>(&self, env: &E) -> (QAgent, TabularModel) {
+        let mut agent = QAgent::new();
+        assert!(
+            <QAgent as Agent<E>>::try_init(&mut agent, env),
+            "Dynamic programming needs discrete state and action spaces"
+        );
+        agent.gamma = self.gamma;
+        let model = TabularModel::build(env, &agent);
+        (agent, model)
+    }
+
+    /// Fills the Q-table of `agent` with `Q*(s, a)`, computed from the optimal values.
+    fn solution(
+        &self,
+        mut agent: QAgent,
+        model: TabularModel,
+        values: Vec<f32>,
+        iterations: usize,
+    ) -> Solution {
+        let mut policy = vec![0; model.state_count()];
+        for (state_i, action) in policy.iter_mut().enumerate() {
+            if model.terminal[state_i] {
+                continue;
+            }
+            for action_i in 0..model.action_space_size {
+                agent.q_table[state_i * model.action_space_size + action_i] =
+                    model.q_value(&values, self.gamma, state_i, action_i);
+            }
+            *action = agent.best_action_index(state_i);
+        }
+        Solution {
+            values,
+            policy,
+            iterations,
+            agent,
+            terminal: model.terminal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::move_to_center::{Board, GridEnvironment};
+
+    #[test]
+    fn test_solvers_agree_on_grid() {
+        let env = GridEnvironment::new(5, 5);
+        let solver = DpSolver::new(0.9);
+        let vi = solver.value_iteration(&env);
+        let pi = solver.policy_iteration(&env);
+        let mpi = solver.modified_policy_iteration(&env, 3);
+        for other in [&pi, &mpi] {
+            let comparison = vi.compare(&other.agent);
+            assert_eq!(comparison.agreement, 1.0);
+            for (a, b) in vi.values.iter().zip(&other.values) {
+                assert!((a - b).abs() < 1e-3);
+            }
+        }
+        // Policy iteration needs far fewer improvement steps than value iteration sweeps.
+        assert!(pi.iterations < vi.iterations);
+    }
+
+    #[test]
+    fn test_optimal_policy_moves_towards_center() {
+        let env = GridEnvironment::new(5, 5);
+        let solution = DpSolver::new(0.9).value_iteration(&env);
+        // A cell next to the center steps straight into it for the reward of 100.
+        let next_to_center = Board {
+            position: (1, 2),
+            done: false,
+        };
+        let state_i = solution.agent.state_index(&next_to_center);
+        assert!((solution.values[state_i] - 100.0).abs() < 1e-3);
+        // Every (row, col) pair maps to one prediction, the center included.
+        let predictions = solution.agent.predict_all::<GridEnvironment>();
+        assert_eq!(predictions.len(), 25);
+
+        // An untrained agent always goes up, which is optimal for some cells only.
+        let mut untrained = QAgent::new();
+        <QAgent as Agent<GridEnvironment>>::try_init(&mut untrained, &env);
+        let comparison = solution.compare(&untrained);
+        assert!(comparison.agreement < 1.0);
+        assert!(comparison.max_regret > 0.0);
+    }
+}
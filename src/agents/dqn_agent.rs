@@ -1,16 +1,35 @@
 use crate::{
     agents::{
+        binary_format::{self, invalid_data, AgentKind, Format, Reader, Writer},
+        metadata::{check_spaces, CompatibilityError, ModelMetadata},
+        n_step::N_STEPS_DEFAULT,
         network::{
-            memory_buffer::{Experience, MemoryBuffer},
-            nn::{ActivationFunction, LossFunction, NeuralNetwork},
+            builder::LayerSpec,
+            conv::PlaneShape,
+            hindsight::HindsightReplay,
+            initializer::Initializer,
+            matrix::Matrix,
+            memory_buffer::{Batch, MemoryBuffer, NStepAggregator},
+            nn::{ActivationFunction, GradientClipping, LossFunction, NeuralNetwork},
+            prioritized_replay::PrioritizedReplay,
+        },
+        persistence::{
+            backup_path, load_with_backup, save_all_atomically, with_suffix, FileWriter, Loaded,
+        },
+        q_agent::{
+            all_actions, exploration_rng, QAgent, ALPHA_DEFAULT, EPSILON_DEFAULT, GAMMA_DEFAULT,
         },
-        q_agent::{all_actions, ALPHA_DEFAULT, EPSILON_DEFAULT, GAMMA_DEFAULT},
     },
-    Action, Agent, Environment, Space, SpaceElem,
+    callback::{Silent, TrainingCallback},
+    Action, Agent, Environment, Space, State,
 };
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::ops::Range;
+use std::{
+    io::{Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DQNAgent {
@@ -23,6 +42,96 @@ pub struct DQNAgent {
     pub disc_state_space: Vec<usize>,
     pub cont_state_space: Vec<Range<f32>>,
     pub action_space: Vec<usize>,
+    /// Number of rewards summed before bootstrapping from the target network.
+    #[serde(default = "default_n_steps")]
+    pub n_steps: usize,
+    /// Number of learning steps between copies of the policy network into the target network.
+    #[serde(default = "default_target_update_interval")]
+    pub target_update_interval: usize,
+    /// Moves the target network towards the policy network by this fraction after every
+    /// learning step, instead of copying it every `target_update_interval` steps.
+    #[serde(default)]
+    pub soft_update: Option<f64>,
+    /// Whether the policy network picks the next action that the target network evaluates,
+    /// see `train_policy`.
+    #[serde(default)]
+    pub double: bool,
+    /// Whether `try_init` builds networks with a dueling head, see `LayerKind::Dueling`.
+    #[serde(default)]
+    pub dueling: bool,
+    /// Learning steps taken so far.
+    #[serde(default)]
+    learn_steps: usize,
+    /// The layers between the encoded state and the Q-values. The number of inputs
+    /// and outputs follows from the environment in `try_init`.
+    #[serde(default = "default_hidden_layers")]
+    pub hidden_layers: Vec<LayerSpec>,
+    /// Activation of the output layer, one neuron per action.
+    #[serde(default = "default_output_activation")]
+    pub output_activation: ActivationFunction,
+    /// Limits on the gradients of the policy network, against exploding TD errors.
+    #[serde(default)]
+    pub gradient_clipping: GradientClipping,
+    /// How states are turned into the inputs of the networks.
+    #[serde(default)]
+    pub encoding: InputEncoding,
+    /// Where and how the agent was trained, checked by `load_for`.
+    #[serde(default)]
+    pub metadata: ModelMetadata,
+    /// Whether saves also write the experiences in the memory buffer to a sidecar file,
+    /// see `replay_path`, from which `load_from_file` restores them.
+    #[serde(default)]
+    pub persist_replay: bool,
+    /// Also stores the transitions of every episode relabeled with the goals it reached,
+    /// for goal-conditioned environments, see `with_hindsight`.
+    #[serde(default)]
+    pub hindsight: Option<HindsightReplay>,
+    /// Threads that the batches of both networks are split across, see `NeuralNetwork::set_threads`.
+    #[serde(skip)]
+    threads: usize,
+    /// Transitions that are not yet n steps old, one aggregator per player.
+    #[serde(skip)]
+    n_step: Vec<NStepAggregator>,
+    /// The batch that learning steps sample into, kept to reuse its buffers.
+    #[serde(skip)]
+    batch: Batch,
+    /// Draws the exploratory actions, seeded by `Agent::seed`.
+    #[serde(skip, default = "exploration_rng")]
+    rng: StdRng,
+}
+
+/// How a `DQNAgent` turns states into network inputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputEncoding {
+    /// One input per state dimension, scaled to `[0, 1)`.
+    #[default]
+    Normalized,
+    /// The one-hot planes of `State::planes`, e.g. `tic_tac_toe::Board::PLANES`,
+    /// so that convolutional layers can see the layout of the board.
+    Planes(PlaneShape),
+}
+
+pub const TARGET_UPDATE_INTERVAL_DEFAULT: usize = 500;
+/// The agent kind recorded in the metadata.
+const AGENT_KIND: &str = "DQNAgent";
+/// Why a saved agent with `n_steps == 0` is rejected, as it could not learn.
+const ZERO_N_STEPS: &str = "n-step returns need at least one step";
+
+fn default_n_steps() -> usize {
+    N_STEPS_DEFAULT
+}
+
+fn default_target_update_interval() -> usize {
+    TARGET_UPDATE_INTERVAL_DEFAULT
+}
+
+/// One hidden layer of 64 Sigmoid neurons.
+fn default_hidden_layers() -> Vec<LayerSpec> {
+    vec![LayerSpec::dense(64, ActivationFunction::Sigmoid).initializer(Initializer::default())]
+}
+
+fn default_output_activation() -> ActivationFunction {
+    ActivationFunction::Sigmoid
 }
 
 impl DQNAgent {
@@ -47,10 +156,124 @@ impl DQNAgent {
             disc_state_space: Vec::new(),
             cont_state_space: Vec::new(),
             action_space: Vec::new(),
+            n_steps: N_STEPS_DEFAULT,
+            target_update_interval: TARGET_UPDATE_INTERVAL_DEFAULT,
+            soft_update: None,
+            double: false,
+            dueling: false,
+            learn_steps: 0,
+            n_step: Vec::new(),
+            hidden_layers: default_hidden_layers(),
+            output_activation: default_output_activation(),
+            gradient_clipping: GradientClipping::default(),
+            encoding: InputEncoding::default(),
+            metadata: ModelMetadata::default(),
+            persist_replay: false,
+            hindsight: None,
+            threads: 1,
+            batch: Batch::default(),
+            rng: exploration_rng(),
         }
     }
 
-    fn encode_input(&self, state: &impl SpaceElem) -> Vec<f64> {
+    /// Samples the memory buffer with prioritized replay instead of uniformly.
+    pub fn with_prioritized_replay(mut self, replay: PrioritizedReplay) -> Self {
+        self.memory_buffer.prioritized = Some(replay);
+        self
+    }
+
+    /// Saves and restores the memory buffer with the agent, so that training can resume
+    /// with the same experiences. Transitions of unfinished n-step windows are not saved.
+    pub fn with_replay_persistence(mut self, persist_replay: bool) -> Self {
+        self.persist_replay = persist_replay;
+        self
+    }
+
+    /// Relabels every episode with the goals it reached, see [`hindsight`](super::network::hindsight).
+    /// Sets `n_steps` to 1, as the relabeled experiences are one-step ones.
+    pub fn with_hindsight(mut self, hindsight: HindsightReplay) -> Self {
+        self.hindsight = Some(hindsight);
+        self.n_steps = 1;
+        self
+    }
+
+    /// The sidecar file that the memory buffer of an agent saved to `file_path` is saved to.
+    pub fn replay_path(file_path: &str) -> PathBuf {
+        with_suffix(file_path, ".replay")
+    }
+
+    /// Replaces the hidden layers and the output activation used by `try_init`.
+    pub fn with_architecture(
+        mut self,
+        hidden_layers: Vec<LayerSpec>,
+        output_activation: ActivationFunction,
+    ) -> Self {
+        self.hidden_layers = hidden_layers;
+        self.output_activation = output_activation;
+        self
+    }
+
+    /// Uses Double DQN targets, see `train_policy`.
+    pub fn with_double(mut self, double: bool) -> Self {
+        self.double = double;
+        self
+    }
+
+    /// Builds the networks with separate value and advantage outputs in `try_init`,
+    /// combined into the Q-values by a dueling head.
+    pub fn with_dueling(mut self, dueling: bool) -> Self {
+        self.dueling = dueling;
+        self
+    }
+
+    /// Updates the target network softly, `θ_target ← τ · θ_policy + (1 − τ) · θ_target`
+    /// after every learning step, in place of the hard copies of `target_update_interval`.
+    pub fn with_soft_update(mut self, tau: f64) -> Self {
+        assert!(tau > 0.0 && tau <= 1.0, "tau must be in (0, 1]");
+        self.soft_update = Some(tau);
+        self
+    }
+
+    /// Encodes the states of the environment as planes, see `InputEncoding::Planes`.
+    pub fn with_encoding(mut self, encoding: InputEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Splits the batches of the networks across up to `threads` threads, see `set_threads`.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.set_threads(threads);
+        self
+    }
+
+    /// Splits the target computation and the training batches across up to `threads`
+    /// threads, also for networks that `try_init` builds later.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+        self.policy_net.set_threads(threads);
+        self.target_net.set_threads(threads);
+    }
+
+    /// Number of network inputs for one state.
+    fn input_size(&self) -> usize {
+        match self.encoding {
+            InputEncoding::Normalized => self.disc_state_space.len() + self.cont_state_space.len(),
+            InputEncoding::Planes(shape) => shape.size(),
+        }
+    }
+
+    fn encode_input(&self, state: &impl State) -> Vec<f64> {
+        if let InputEncoding::Planes(shape) = self.encoding {
+            let planes = state
+                .planes()
+                .expect("the state cannot be encoded as planes");
+            assert_eq!(
+                planes.len(),
+                shape.size(),
+                "the planes do not have the shape {shape:?}"
+            );
+            return planes;
+        }
         let mut input = vec![];
         for d in 0..self.disc_state_space.len() {
             input.push(state.discrete(d).unwrap() as f64 / self.disc_state_space[d] as f64);
@@ -63,24 +286,377 @@ impl DQNAgent {
         input
     }
 
-    fn predict_network(&self, state: &impl SpaceElem) -> Vec<f64> {
+    fn predict_network(&self, state: &impl State) -> Vec<f64> {
         let input = self.encode_input(state);
         self.policy_net.predict(input)
     }
 
+    /// Samples a batch from the memory buffer and moves the policy network towards
+    /// the n-step targets
+    ///
+    /// ```math
+    /// y = r + γⁿ · maxₐ' Q_target(s', a')
+    /// ```
+    ///
+    /// where the bootstrapped term is dropped for terminal experiences. Double DQN
+    /// (van Hasselt et al., 2016) uses `Q_target(s', argmaxₐ' Q_policy(s', a'))` instead,
+    /// which does not overestimate the values of noisy actions as much. With prioritized
+    /// replay, the loss of every experience is scaled by its importance-sampling weight,
+    /// and its TD error `y − Q(s, a)` becomes its new priority.
+    ///
+    /// The loss of the batch is reported to `callback`, numbered by the learning step.
+    fn train_policy(&mut self, callback: &mut dyn TrainingCallback) {
+        // If the memory buffer is not full enough, we cannot learn yet
+        if self.memory_buffer.len() < self.batch_size {
+            return;
+        }
+        let mut batch = std::mem::take(&mut self.batch);
+        self.memory_buffer.sample_batch(self.batch_size, &mut batch);
+        let (targets, td_errors) = self.targets(&batch);
+        let loss = if batch.weights.is_empty() {
+            self.policy_net.train_matrix(&batch.states, &targets)
+        } else {
+            self.policy_net
+                .train_matrix_weighted(&batch.states, &targets, &batch.weights)
+        };
+        self.memory_buffer
+            .update_priorities(&batch.indices, &td_errors);
+        self.batch = batch;
+
+        self.learn_steps += 1;
+        callback.on_batch_end(self.learn_steps as u64, loss);
+        match self.soft_update {
+            Some(tau) => self.target_net.blend_from(&self.policy_net, tau),
+            None if self.learn_steps.is_multiple_of(self.target_update_interval) => {
+                self.target_net = self.policy_net.clone();
+            }
+            None => {}
+        }
+    }
+
+    /// The outputs the policy network is trained towards for `batch`, see `train_policy`,
+    /// and the TD errors of the actions that were taken.
+    fn targets(&self, batch: &Batch) -> (Matrix, Vec<f64>) {
+        let discount = self.gamma.powi(self.n_steps as i32) as f64;
+        let mut targets = self.policy_net.predict_matrix(&batch.states);
+        let next_values = self.target_net.predict_matrix(&batch.next_states);
+        let next_actions = self
+            .double
+            .then(|| self.policy_net.predict_matrix(&batch.next_states));
+        let mut td_errors = Vec::with_capacity(batch.len());
+        for (i, &action) in batch.actions.iter().enumerate() {
+            let mut value = batch.rewards[i] as f64;
+            if !batch.dones[i] {
+                let next_value = match &next_actions {
+                    Some(next_actions) => next_values[i][argmax(&next_actions[i])],
+                    None => next_values[i].iter().copied().fold(f64::MIN, f64::max),
+                };
+                value += discount * next_value;
+            }
+            td_errors.push(value - targets[i][action]);
+            // Only the action that was taken gets an error, the other outputs keep their value.
+            targets[i][action] = value;
+        }
+        (targets, td_errors)
+    }
+
+    /// Learns from a transition like `Agent::learn`, and reports the loss of the learning
+    /// step it takes to `callback`.
+    pub fn learn_with_callback<E: Environment>(
+        &mut self,
+        old_state: &E::State,
+        action: &E::Action,
+        reward: f32,
+        next_state: Option<&E::State>,
+        callback: &mut dyn TrainingCallback,
+    ) {
+        // Add experience to memory buffer, once it is n steps old or the episode ended.
+        // Players are kept apart, so that an agent playing both sides of a game
+        // does not mix the rewards of the two.
+        let player = old_state.current_player();
+        if self.n_step.len() <= player {
+            let (n_steps, gamma) = (self.n_steps, self.gamma);
+            self.n_step
+                .resize_with(player + 1, || NStepAggregator::new(n_steps, gamma));
+        }
+        let state = self.encode_input(old_state);
+        let next_state = next_state.map(|next_state| self.encode_input(next_state));
+        let action = QAgent::space_elem_as_int(action, &self.action_space);
+        let transition = self
+            .hindsight
+            .is_some()
+            .then(|| (state.clone(), next_state.clone()));
+        self.n_step[player].add(&mut self.memory_buffer, state, action, reward, next_state);
+        // The relabeled experiences of an episode follow the original ones.
+        if let (Some(hindsight), Some((state, next_state))) = (&mut self.hindsight, transition) {
+            hindsight.add(&mut self.memory_buffer, state, action, reward, next_state);
+        }
+
+        self.train_policy(callback);
+    }
+
+    /// Loads an agent saved by `save_binary` or by `save_to_file`, whichever format the file has.
+    /// Falls back to the backup of the previous save if the file cannot be loaded.
     pub fn load_from_file(file_path: &str) -> Result<Self, String> {
+        Self::load(file_path).map(|(agent, _)| agent)
+    }
+
+    /// Loads the agent together with the replay file of the same save, so that the backup of
+    /// the agent is loaded with the backup of the replay file.
+    fn load(file_path: &str) -> Result<(Self, Loaded), String> {
+        let replay_path = Self::replay_path(file_path);
+        load_with_backup(file_path, |path| {
+            let mut agent = Self::read_file(path)?;
+            if agent.persist_replay {
+                let replay_path = if path == Path::new(file_path) {
+                    replay_path.clone()
+                } else {
+                    backup_path(&replay_path)
+                };
+                let prioritized = agent.memory_buffer.prioritized.take();
+                agent.memory_buffer = MemoryBuffer {
+                    prioritized,
+                    ..agent.read_replay(&replay_path)?
+                };
+            }
+            Ok(agent)
+        })
+    }
+
+    fn read_replay(&self, replay_path: &Path) -> Result<MemoryBuffer, String> {
+        let buffer = std::fs::File::open(replay_path)
+            .and_then(|file| MemoryBuffer::read(&mut Reader::new(std::io::BufReader::new(file))))
+            .map_err(|e| format!("Failed to load {}: {e}", replay_path.display()))?;
+        let input_size = self.input_size();
+        let wrong_size = buffer
+            .buffer
+            .iter()
+            .any(|e| e.state.len() != input_size || e.next_state.len() != input_size);
+        if wrong_size {
+            return Err(format!(
+                "{} holds states that are not {input_size} inputs long",
+                replay_path.display()
+            ));
+        }
+        Ok(buffer)
+    }
+
+    /// Saves the agent with `write`, and the memory buffer if `persist_replay` is set. Both
+    /// are written before either replaces its previous save, so that an agent file is always
+    /// next to the replay file of the same save.
+    fn save_with_replay(&self, file_path: &str, write: FileWriter) -> Result<(), String> {
+        let replay_path = Self::replay_path(file_path);
+        let mut files = vec![(Path::new(file_path), write)];
+        if self.persist_replay {
+            files.push((
+                replay_path.as_path(),
+                Box::new(|file| self.memory_buffer.write(&mut Writer::new(file))),
+            ));
+        }
+        save_all_atomically(files).map_err(|e| e.to_string())
+    }
+
+    fn read_file(file_path: &Path) -> Result<Self, String> {
         let file = std::fs::File::open(file_path).map_err(|e| e.to_string())?;
-        let reader = std::io::BufReader::new(file);
-        serde_json::from_reader(reader).map_err(|e| e.to_string())
+        let mut reader = std::io::BufReader::new(file);
+        let agent: Self = match binary_format::detect(&mut reader).map_err(|e| e.to_string())? {
+            Format::Binary => {
+                Self::read_binary(&mut Reader::new(reader)).map_err(|e| e.to_string())?
+            }
+            Format::Json => {
+                let agent: Self = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+                if agent.n_steps == 0 {
+                    return Err(ZERO_N_STEPS.to_string());
+                }
+                agent
+            }
+        };
+        agent.policy_net.validate().map_err(|e| e.to_string())?;
+        agent.target_net.validate().map_err(|e| e.to_string())?;
+        Ok(agent)
+    }
+
+    /// Loads an agent like `load_from_file` and checks that it was trained for an environment
+    /// like `env`. Also returns whether the backup was loaded, for the caller to report.
+    pub fn load_for<E: Environment>(file_path: &str, env: &E) -> Result<(Self, Loaded), String> {
+        let (agent, loaded) = Self::load(file_path)?;
+        agent.check_compatible(env).map_err(|e| e.to_string())?;
+        Ok((agent, loaded))
+    }
+
+    /// Checks the metadata and the spaces of the agent against `env`.
+    pub fn check_compatible<E: Environment>(&self, env: &E) -> Result<(), CompatibilityError> {
+        self.metadata.check(AGENT_KIND, env)?;
+        check_spaces(
+            env,
+            &self.disc_state_space,
+            &self.cont_state_space,
+            &self.action_space,
+        )
+    }
+
+    /// Saves the agent as JSON. The experiences in the memory buffer are only saved, to
+    /// `replay_path`, with `persist_replay`; its capacity is always kept.
+    /// The save is atomic and keeps the previous file as a backup, see [`persistence`](super::persistence).
+    pub fn save_to_file(&self, file_path: &str) -> Result<(), String> {
+        self.save_with_replay(
+            file_path,
+            Box::new(|file| Ok(serde_json::to_writer(file, &self)?)),
+        )
+    }
+
+    /// Saves the agent in the binary format of [`binary_format`], with the weights of both
+    /// networks as little-endian `f64`s. The memory buffer is saved like in `save_to_file`.
+    pub fn save_binary(&self, file_path: &str) -> Result<(), String> {
+        self.save_with_replay(
+            file_path,
+            Box::new(|file| self.write_binary(&mut Writer::new(file))),
+        )
+    }
+
+    fn write_binary(&self, writer: &mut Writer<impl Write>) -> std::io::Result<()> {
+        writer.header(AgentKind::Dqn, &self.metadata)?;
+        writer.usizes(&self.disc_state_space)?;
+        writer.usize(self.cont_state_space.len())?;
+        for range in &self.cont_state_space {
+            writer.f32(range.start)?;
+            writer.f32(range.end)?;
+        }
+        writer.usizes(&self.action_space)?;
+        writer.usize(self.batch_size)?;
+        writer.f32(self.epsilon)?;
+        writer.f32(self.gamma)?;
+        writer.usize(self.n_steps)?;
+        writer.usize(self.target_update_interval)?;
+        writer.usize(self.learn_steps)?;
+
+        let without_weights = |network: &NeuralNetwork| {
+            let mut network = network.clone();
+            for layer in &mut network.layers {
+                layer.weights = Matrix::default();
+                layer.biases = Vec::new();
+            }
+            network
+        };
+        let architecture = Architecture {
+            hidden_layers: self.hidden_layers.clone(),
+            output_activation: self.output_activation.clone(),
+            gradient_clipping: self.gradient_clipping,
+            encoding: self.encoding,
+            policy_net: without_weights(&self.policy_net),
+            target_net: without_weights(&self.target_net),
+            buffer_capacity: self.memory_buffer.capacity,
+            prioritized: self.memory_buffer.prioritized.clone(),
+            persist_replay: self.persist_replay,
+            hindsight: self.hindsight.clone(),
+            soft_update: self.soft_update,
+            double: self.double,
+            dueling: self.dueling,
+        };
+        writer.str(&serde_json::to_string(&architecture)?)?;
+        for layer in self.policy_net.layers.iter().chain(&self.target_net.layers) {
+            writer.usize(layer.weights.rows())?;
+            writer.usize(layer.weights.cols())?;
+            writer.f64s(layer.weights.as_slice())?;
+            writer.f64s(&layer.biases)?;
+        }
+        Ok(())
     }
 
-    pub fn save_to_file(&mut self, file_path: &str) -> Result<(), String> {
-        let file = std::fs::File::create(file_path).map_err(|e| e.to_string())?;
-        self.memory_buffer = MemoryBuffer::new(0);
-        serde_json::to_writer(file, self).map_err(|e| e.to_string())
+    fn read_binary(reader: &mut Reader<impl Read>) -> std::io::Result<Self> {
+        let header = reader.header(AgentKind::Dqn)?;
+        let disc_state_space = reader.usizes()?;
+        let cont_state_space = (0..reader.usize()?)
+            .map(|_| Ok(reader.f32()?..reader.f32()?))
+            .collect::<std::io::Result<_>>()?;
+        let action_space = reader.usizes()?;
+        let batch_size = reader.usize()?;
+        let (epsilon, gamma) = (reader.f32()?, reader.f32()?);
+        let n_steps = reader.usize()?;
+        if n_steps == 0 {
+            return Err(invalid_data(ZERO_N_STEPS));
+        }
+        let target_update_interval = reader.usize()?;
+        let learn_steps = reader.usize()?;
+
+        let mut architecture: Architecture = serde_json::from_str(&reader.str()?)?;
+        for layer in architecture
+            .policy_net
+            .layers
+            .iter_mut()
+            .chain(&mut architecture.target_net.layers)
+        {
+            let (rows, cols) = (reader.usize()?, reader.usize()?);
+            let weights = reader.f64s()?;
+            if Some(weights.len()) != rows.checked_mul(cols) {
+                return Err(invalid_data(format!(
+                    "a layer has {} weights, not {rows} x {cols}",
+                    weights.len()
+                )));
+            }
+            layer.weights = Matrix::from_vec(rows, cols, weights);
+            layer.biases = reader.f64s()?;
+        }
+        Ok(DQNAgent {
+            policy_net: architecture.policy_net,
+            target_net: architecture.target_net,
+            memory_buffer: MemoryBuffer {
+                prioritized: architecture.prioritized,
+                ..MemoryBuffer::new(architecture.buffer_capacity)
+            },
+            batch_size,
+            epsilon,
+            gamma,
+            disc_state_space,
+            cont_state_space,
+            action_space,
+            n_steps,
+            target_update_interval,
+            soft_update: architecture.soft_update,
+            double: architecture.double,
+            dueling: architecture.dueling,
+            learn_steps,
+            hidden_layers: architecture.hidden_layers,
+            output_activation: architecture.output_activation,
+            gradient_clipping: architecture.gradient_clipping,
+            encoding: architecture.encoding,
+            metadata: header.metadata,
+            persist_replay: architecture.persist_replay,
+            hindsight: architecture.hindsight,
+            threads: 1,
+            n_step: Vec::new(),
+            batch: Batch::default(),
+            rng: exploration_rng(),
+        })
     }
 }
 
+/// The part of a `DQNAgent` that the binary format stores as JSON: the architecture of
+/// the networks, whose weights follow as `f64`s, and the settings of the memory buffer.
+#[derive(Serialize, Deserialize)]
+struct Architecture {
+    hidden_layers: Vec<LayerSpec>,
+    output_activation: ActivationFunction,
+    gradient_clipping: GradientClipping,
+    encoding: InputEncoding,
+    policy_net: NeuralNetwork,
+    target_net: NeuralNetwork,
+    #[serde(default)]
+    buffer_capacity: usize,
+    #[serde(default)]
+    prioritized: Option<PrioritizedReplay>,
+    #[serde(default)]
+    persist_replay: bool,
+    #[serde(default)]
+    hindsight: Option<HindsightReplay>,
+    #[serde(default)]
+    soft_update: Option<f64>,
+    #[serde(default)]
+    double: bool,
+    #[serde(default)]
+    dueling: bool,
+}
+
 impl<E: Environment> Agent<E> for DQNAgent {
     fn try_init(&mut self, env: &E) -> bool {
         let cont_action;
@@ -89,22 +665,49 @@ impl<E: Environment> Agent<E> for DQNAgent {
             return false; // DQN does not support continuous action spaces
         }
         (self.disc_state_space, self.cont_state_space) = env.state_space().as_vecs();
-        let input_dims = self.disc_state_space.len() + self.cont_state_space.len();
+        let input_dims = self.input_size();
         let output_dims = self.action_space.iter().product();
-        // First layer has size of total dimensions of state and action spaces
-        // Last Layer has only one ouput, the Q-value
-        self.policy_net.add_layers(&[input_dims, 64, output_dims]);
-        self.target_net.add_layers(&[input_dims, 64, output_dims]);
+        // One input per state dimension, and one output, the Q-value, per action
+        let output_layers = if self.dueling {
+            // The value and the advantages are combined into the Q-values by the head.
+            let streams = LayerSpec::dense(output_dims + 1, ActivationFunction::Linear)
+                .initializer(Initializer::default());
+            let head = LayerSpec {
+                activation: self.output_activation.clone(),
+                ..LayerSpec::dueling(output_dims)
+            };
+            vec![streams, head]
+        } else {
+            vec![
+                LayerSpec::dense(output_dims, self.output_activation.clone())
+                    .initializer(Initializer::default()),
+            ]
+        };
+        match NeuralNetwork::builder(input_dims)
+            .layers(self.hidden_layers.iter().cloned())
+            .layers(output_layers)
+            .learning_rate(ALPHA_DEFAULT as f64)
+            .loss(LossFunction::MeanSquaredError)
+            .clipping(self.gradient_clipping)
+            .threads(self.threads)
+            .build()
+        {
+            Ok(network) => self.policy_net = network,
+            Err(_) => return false,
+        }
+        // Both networks start out with the same weights
+        self.target_net = self.policy_net.clone();
+        self.metadata = ModelMetadata::new(AGENT_KIND, env);
+        self.n_step.clear();
         true
     }
 
     fn act(&mut self, state: &<E as Environment>::State) -> <E as Environment>::Action {
-        let mut rng = rand::rng();
-        if rng.random::<f32>() < self.epsilon {
+        if self.rng.random::<f32>() < self.epsilon {
             // Exploration: choose a random action
-            E::Action::gen_random(&&*self.action_space).unwrap()
+            E::Action::gen_random_with(&&*self.action_space, &mut self.rng).unwrap()
         } else {
-            <Self as Agent<E>>::predict(&self, state)
+            <Self as Agent<E>>::predict(self, state)
         }
     }
 
@@ -115,33 +718,493 @@ impl<E: Environment> Agent<E> for DQNAgent {
         reward: f32,
         next_state: Option<&<E as Environment>::State>,
     ) {
-        // Add experience to memory buffer
-        self.memory_buffer.add_experience(Experience::new(
-            self.encode_input(old_state),
-            action.discrete(0).unwrap(),
-            reward,
-            self.encode_input(next_state.unwrap()),
-            next_state.is_none(),
-        ));
-        // If the memory buffer is not full enough, we cannot learn yet
-        if self.memory_buffer.buffer.len() < self.batch_size {
-            return;
-        }
-        // Sample a batch of experiences from the memory buffer
-        todo!("Implement DQN learning algorithm");
-        // let (state_batch, action_batch, reward_batch, next_state_bach, done_batch) =
-        //     self.memory_buffer.sample_and_unpack(self.batch_size);
+        self.learn_with_callback::<E>(old_state, action, reward, next_state, &mut Silent);
     }
 
     fn predict(&self, state: &<E as Environment>::State) -> <E as Environment>::Action {
         // Exploitation: choose the best action based on Q-values
-        let action = self
-            .predict_network(state)
+        let action = argmax(&self.predict_network(state));
+        all_actions(&self.action_space).nth(action).unwrap()
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+}
+
+/// The index of the largest Q-value.
+fn argmax(values: &[f64]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_i, x), (_j, y)| x.partial_cmp(y).unwrap())
+        .unwrap()
+        .0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::{
+        network::memory_buffer::Experience,
+        persistence::backup_path,
+        test_utils::{cell, init_grid, test_dir},
+    };
+    use crate::environment::move_to_center::{GridEnvironment, MoveAction};
+
+    #[test]
+    fn test_architecture_is_configurable() {
+        let env = GridEnvironment::new(5, 5);
+        let mut agent = DQNAgent::new(16).with_architecture(
+            vec![
+                LayerSpec::dense(32, ActivationFunction::ReLU),
+                LayerSpec::dense(16, ActivationFunction::Tanh),
+            ],
+            ActivationFunction::Linear,
+        );
+        assert!(<DQNAgent as Agent<GridEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+        let sizes = agent
+            .policy_net
+            .layers
             .iter()
-            .enumerate()
-            .max_by(|(_i, x), (_j, y)| x.partial_cmp(y).unwrap())
-            .unwrap()
-            .0;
-        all_actions(&self.action_space).take(action).next().unwrap()
+            .map(|layer| (layer.input_size(), layer.output_size()))
+            .collect::<Vec<_>>();
+        // Two state dimensions, four actions.
+        assert_eq!(sizes, [(2, 32), (32, 16), (16, 4)]);
+        assert_eq!(agent.target_net.layers.len(), 3);
+
+        // An empty hidden layer cannot be built.
+        let mut agent = DQNAgent::new(16).with_architecture(
+            vec![LayerSpec::dense(0, ActivationFunction::ReLU)],
+            ActivationFunction::Linear,
+        );
+        assert!(!<DQNAgent as Agent<GridEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+    }
+
+    #[test]
+    fn test_n_step_experiences_flush_at_episode_end() {
+        let mut agent = DQNAgent::new(16);
+        agent.n_steps = 3;
+        let mut agent = init_grid(agent);
+
+        let path = [cell(0, 0), cell(0, 1), cell(0, 2), cell(1, 2)];
+        for step in path.windows(2) {
+            <DQNAgent as Agent<GridEnvironment>>::learn(
+                &mut agent,
+                &step[0],
+                &MoveAction::Right,
+                1.0,
+                Some(&step[1]),
+            );
+        }
+        // Only the first transition is three steps old.
+        assert_eq!(agent.memory_buffer.buffer.len(), 1);
+        assert!(!agent.memory_buffer.buffer[0].done);
+        assert_eq!(agent.memory_buffer.buffer[0].reward, 1.0 + 0.9 + 0.81);
+
+        <DQNAgent as Agent<GridEnvironment>>::learn(
+            &mut agent,
+            &cell(1, 2),
+            &MoveAction::Down,
+            100.0,
+            None,
+        );
+        // The remaining three transitions are flushed as terminal experiences.
+        assert_eq!(agent.memory_buffer.buffer.len(), 4);
+        assert!(agent.memory_buffer.buffer.iter().skip(1).all(|e| e.done));
+        assert_eq!(agent.memory_buffer.buffer[3].reward, 100.0);
+    }
+
+    #[test]
+    fn test_board_planes_feed_a_convolution() {
+        use crate::agents::network::conv::Conv2d;
+        use crate::environment::tic_tac_toe::{
+            Board as TicTacBoard, CellState, TicTacAction, TicTacEnvironment, TicTacPlayer,
+        };
+
+        let mut board = TicTacEnvironment::new().board;
+        board.cells[0][0] = CellState::X;
+        board.cells[1][2] = CellState::O;
+        board.player = TicTacPlayer::O;
+        let planes = board.planes().unwrap();
+        let plane = |p: usize| &planes[p * 9..(p + 1) * 9];
+        assert_eq!(plane(0), [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(plane(1), [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(plane(2).iter().sum::<f64>(), 7.0);
+        // O is to move.
+        assert_eq!(plane(3), [0.0; 9]);
+
+        let env = TicTacEnvironment::new();
+        let conv = Conv2d::new(TicTacBoard::PLANES, 8, 2);
+        let mut agent = DQNAgent::new(16)
+            .with_encoding(InputEncoding::Planes(TicTacBoard::PLANES))
+            .with_architecture(
+                vec![
+                    LayerSpec::conv2d(conv, ActivationFunction::ReLU),
+                    LayerSpec::dense(16, ActivationFunction::ReLU),
+                ],
+                ActivationFunction::Linear,
+            );
+        agent.batch_size = 2;
+        assert!(<DQNAgent as Agent<TicTacEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+        assert_eq!(agent.policy_net.input_size(), 36);
+        let mut next = board.clone();
+        next.cells[2][2] = CellState::O;
+        next.player = TicTacPlayer::X;
+        for _ in 0..3 {
+            <DQNAgent as Agent<TicTacEnvironment>>::learn(
+                &mut agent,
+                &board,
+                &TicTacAction::default(),
+                1.0,
+                Some(&next),
+            );
+        }
+        assert_eq!(agent.memory_buffer.buffer[0].state, planes);
+        let _: TicTacAction = <DQNAgent as Agent<TicTacEnvironment>>::predict(&agent, &board);
+    }
+
+    #[test]
+    fn test_binary_file_round_trips_and_json_still_loads() {
+        let dir = test_dir("dqn_agent");
+        let (binary, json) = (dir.join("dqn.bin"), dir.join("dqn.json"));
+        let (binary, json) = (binary.to_str().unwrap(), json.to_str().unwrap());
+        let weights = |network: &NeuralNetwork| {
+            let layers = network.layers.iter();
+            layers
+                .map(|l| (l.weights.clone(), l.biases.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        let env = GridEnvironment::new(4, 4);
+        let mut agent = DQNAgent::new(16);
+        assert!(<DQNAgent as Agent<GridEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+        agent.learn_steps = 7;
+        agent.metadata.record_training(3, None);
+        agent.save_binary(binary).unwrap();
+        agent.save_to_file(json).unwrap();
+        let loaded = DQNAgent::load_from_file(binary).unwrap();
+        assert_eq!(loaded.metadata.environment, "move_to_center");
+        assert_eq!(loaded.disc_state_space, agent.disc_state_space);
+        assert_eq!(loaded.learn_steps, 7);
+        // The binary format keeps every bit of the weights, unlike the JSON text.
+        assert_eq!(weights(&loaded.policy_net), weights(&agent.policy_net));
+        assert_eq!(weights(&loaded.target_net), weights(&agent.target_net));
+
+        assert_eq!(loaded.metadata, agent.metadata);
+        let loaded = DQNAgent::load_from_file(json).unwrap();
+        assert_eq!(loaded.metadata, agent.metadata);
+        assert_eq!(loaded.learn_steps, 7);
+        let input = vec![0.5, 0.25];
+        let (a, b) = (
+            loaded.policy_net.predict(input.clone()),
+            agent.policy_net.predict(input),
+        );
+        assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-12));
+        assert!(DQNAgent::load_for(json, &env).is_ok());
+        let error = DQNAgent::load_for(json, &GridEnvironment::new(4, 5)).unwrap_err();
+        assert!(error.contains("`cols`"), "{error}");
+        // A Q-agent file is not a DQN file, so the backup of the previous save is loaded.
+        QAgent::new().save_binary(binary).unwrap();
+        assert_eq!(DQNAgent::load_from_file(binary).unwrap().learn_steps, 7);
+        std::fs::remove_file(backup_path(binary)).unwrap();
+        assert!(DQNAgent::load_from_file(binary).is_err());
+        // An agent without n-step returns fails to load, instead of failing to learn.
+        agent.n_steps = 0;
+        agent.save_binary(binary).unwrap();
+        let error = DQNAgent::load_from_file(binary).unwrap_err();
+        assert!(error.contains("at least one step"), "{error}");
+    }
+
+    #[test]
+    fn test_json_file_without_n_step_returns_fails_to_load() {
+        let dir = test_dir("dqn_zero_n_steps");
+        let json = dir.join("dqn.json");
+        let json = json.to_str().unwrap();
+        let mut agent = DQNAgent::new(16);
+        <DQNAgent as Agent<GridEnvironment>>::try_init(&mut agent, &GridEnvironment::new(3, 3));
+        agent.n_steps = 0;
+        agent.save_to_file(json).unwrap();
+        let error = DQNAgent::load_from_file(json).unwrap_err();
+        assert!(error.contains("at least one step"), "{error}");
+    }
+
+    #[test]
+    fn test_replay_buffer_is_saved_next_to_the_agent_when_enabled() {
+        let dir = test_dir("dqn_replay");
+        let (binary, json) = (dir.join("dqn.bin"), dir.join("dqn.json"));
+        let (binary, json) = (binary.to_str().unwrap(), json.to_str().unwrap());
+        let experiences = |buffer: &MemoryBuffer| {
+            let buffer = buffer.buffer.iter();
+            buffer
+                .map(|e| {
+                    (
+                        e.state.clone(),
+                        e.action,
+                        e.reward,
+                        e.next_state.clone(),
+                        e.done,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let env = GridEnvironment::new(5, 5);
+        let mut agent = DQNAgent::new(16);
+        assert!(<DQNAgent as Agent<GridEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+        let path = [cell(0, 0), cell(0, 1), cell(0, 2), cell(1, 2)];
+        for (i, step) in path.windows(2).enumerate() {
+            let next = (i < 2).then_some(&step[1]);
+            <DQNAgent as Agent<GridEnvironment>>::learn(
+                &mut agent,
+                &step[0],
+                &MoveAction::Right,
+                0.1 * i as f32,
+                next,
+            );
+        }
+        assert_eq!(agent.memory_buffer.buffer.len(), 3);
+
+        // Without the option only the capacity is kept, and saving leaves the agent alone.
+        agent.save_to_file(json).unwrap();
+        assert_eq!(agent.memory_buffer.buffer.len(), 3);
+        assert!(!DQNAgent::replay_path(json).exists());
+        let loaded = DQNAgent::load_from_file(json).unwrap();
+        assert_eq!(loaded.memory_buffer.capacity, 16);
+        assert!(loaded.memory_buffer.buffer.is_empty());
+
+        agent.persist_replay = true;
+        for file in [json, binary] {
+            if file == json {
+                agent.save_to_file(file).unwrap();
+            } else {
+                agent.save_binary(file).unwrap();
+            }
+            let loaded = DQNAgent::load_from_file(file).unwrap();
+            assert!(loaded.persist_replay);
+            assert_eq!(loaded.memory_buffer.capacity, 16);
+            assert_eq!(
+                experiences(&loaded.memory_buffer),
+                experiences(&agent.memory_buffer)
+            );
+        }
+
+        // A corrupt agent file falls back to the previous save, and to its replay file.
+        let previous = experiences(&agent.memory_buffer);
+        <DQNAgent as Agent<GridEnvironment>>::learn(
+            &mut agent,
+            &path[0],
+            &MoveAction::Right,
+            0.0,
+            Some(&path[1]),
+        );
+        agent.save_to_file(json).unwrap();
+        std::fs::write(json, "{").unwrap();
+        let (loaded, from) = DQNAgent::load_for(json, &env).unwrap();
+        assert_eq!(from, Loaded::Backup);
+        assert_eq!(experiences(&loaded.memory_buffer), previous);
+
+        // A replay file with states of another length is rejected.
+        let mut other = DQNAgent::new(4).with_replay_persistence(true);
+        let experience = Experience::new(vec![0.0; 3], 0, 1.0, vec![0.0; 3], true);
+        other.memory_buffer.add_experience(experience);
+        other.save_to_file(json).unwrap();
+        std::fs::copy(DQNAgent::replay_path(json), DQNAgent::replay_path(binary)).unwrap();
+        let error = DQNAgent::load_from_file(binary).unwrap_err();
+        assert!(error.contains("inputs long"), "{error}");
+    }
+
+    #[test]
+    fn test_prioritized_replay_trains_and_is_saved() {
+        let dir = test_dir("dqn_per");
+        let (binary, json) = (dir.join("dqn.bin"), dir.join("dqn.json"));
+        let (binary, json) = (binary.to_str().unwrap(), json.to_str().unwrap());
+
+        let env = GridEnvironment::new(5, 5);
+        let mut agent =
+            DQNAgent::new(8).with_prioritized_replay(PrioritizedReplay::new(0.7, 0.5, 0.1));
+        agent.batch_size = 4;
+        agent.n_steps = 1;
+        assert!(<DQNAgent as Agent<GridEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+        for i in 0..12 {
+            <DQNAgent as Agent<GridEnvironment>>::learn(
+                &mut agent,
+                &cell(i % 5, 0),
+                &MoveAction::Down,
+                i as f32,
+                Some(&cell(i % 5, 1)),
+            );
+        }
+        assert_eq!(agent.learn_steps, 9);
+        assert_eq!(agent.memory_buffer.buffer.len(), 8);
+        let replay = agent.memory_buffer.prioritized.as_ref().unwrap();
+        assert!((replay.beta - 1.0).abs() < 1e-12);
+
+        agent.save_to_file(json).unwrap();
+        agent.save_binary(binary).unwrap();
+        for file in [json, binary] {
+            let loaded = DQNAgent::load_from_file(file).unwrap();
+            let replay = loaded.memory_buffer.prioritized.unwrap();
+            assert_eq!((replay.alpha, replay.beta_increment), (0.7, 0.1));
+            assert_eq!(loaded.memory_buffer.capacity, 8);
+        }
+    }
+
+    #[test]
+    fn test_hindsight_relabels_episodes_and_is_saved() {
+        use crate::agents::network::hindsight::{GoalStrategy, HindsightReplay};
+        use crate::environment::reach_goal::{GoalBoard, GoalGridEnvironment};
+
+        let dir = test_dir("dqn_her");
+        let (binary, json) = (dir.join("dqn.bin"), dir.join("dqn.json"));
+        let (binary, json) = (binary.to_str().unwrap(), json.to_str().unwrap());
+
+        let env = GoalGridEnvironment::new(3, 3);
+        let hindsight = HindsightReplay::for_env(&env, GoalStrategy::Final);
+        let mut agent = DQNAgent::new(16).with_hindsight(hindsight);
+        assert_eq!(agent.n_steps, 1);
+        assert!(<DQNAgent as Agent<GoalGridEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+        // Two steps right, towards a goal below, until the episode runs out of steps.
+        let board = |col| GoalBoard {
+            position: (0, col),
+            goal: (2, 2),
+        };
+        for col in 0..2 {
+            let next_state = board(col + 1);
+            let next_state = (col == 0).then_some(&next_state);
+            <DQNAgent as Agent<GoalGridEnvironment>>::learn(
+                &mut agent,
+                &board(col),
+                &MoveAction::Right,
+                0.0,
+                next_state,
+            );
+        }
+        // The two original experiences, and the first step relabeled with the cell it reached.
+        let rewards: Vec<(f32, bool)> = agent
+            .memory_buffer
+            .iter()
+            .map(|e| (e.reward(), e.done()))
+            .collect();
+        assert_eq!(rewards, vec![(0.0, false), (0.0, true), (1.0, true)]);
+        let relabeled = agent.memory_buffer.iter().last().unwrap();
+        assert_eq!(relabeled.state(), &[0.0, 0.0, 0.0, 1.0 / 3.0]);
+
+        agent.save_to_file(json).unwrap();
+        agent.save_binary(binary).unwrap();
+        for file in [json, binary] {
+            let loaded = DQNAgent::load_from_file(file).unwrap();
+            let hindsight = loaded.hindsight.unwrap();
+            assert_eq!(hindsight.strategy, GoalStrategy::Final);
+            assert_eq!((hindsight.achieved, hindsight.goal), (0..2, 2..4));
+        }
+    }
+
+    #[test]
+    fn test_double_targets_evaluate_the_greedy_policy_action_with_the_target_network() {
+        let mut agent = init_grid(
+            DQNAgent::new(4)
+                .with_architecture(Vec::new(), ActivationFunction::Linear)
+                .with_double(true),
+        );
+        // Networks of a single layer without weights output their biases for every state.
+        let set_outputs = |network: &mut NeuralNetwork, outputs: [f64; 4]| {
+            let layer = &mut network.layers[0];
+            layer.weights.map_inplace(|_| 0.0);
+            layer.biases = outputs.to_vec();
+        };
+        set_outputs(&mut agent.policy_net, [0.5, 1.0, 0.0, 0.0]);
+        set_outputs(&mut agent.target_net, [5.0, 2.0, 0.0, 0.0]);
+        let batch = Batch {
+            states: Matrix::from_rows(&[vec![0.0, 0.0], vec![0.0, 0.0]]),
+            actions: vec![0, 3],
+            rewards: vec![1.0, 1.0],
+            next_states: Matrix::from_rows(&[vec![0.2, 0.2], vec![0.2, 0.2]]),
+            dones: vec![false, true],
+            ..Batch::default()
+        };
+
+        // The policy network picks action 1, which the target network values at 2.
+        let (targets, td_errors) = agent.targets(&batch);
+        let double_target = 1.0 + 0.9 * 2.0;
+        assert!((targets[0][0] - double_target).abs() < 1e-6);
+        assert!((td_errors[0] - (double_target - 0.5)).abs() < 1e-6);
+        // The other outputs keep their value, and terminal experiences do not bootstrap.
+        assert_eq!(&targets[0][1..], &[1.0, 0.0, 0.0]);
+        assert_eq!(targets[1].to_vec(), vec![0.5, 1.0, 0.0, 1.0]);
+
+        // Plain DQN takes the largest value of the target network instead.
+        agent.double = false;
+        let (targets, _) = agent.targets(&batch);
+        assert!((targets[0][0] - (1.0 + 0.9 * 5.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_double_dueling_and_soft_updates_train_and_are_saved() {
+        use crate::agents::network::nn::LayerKind;
+
+        let dir = test_dir("dqn_double");
+        let (binary, json) = (dir.join("dqn.bin"), dir.join("dqn.json"));
+        let (binary, json) = (binary.to_str().unwrap(), json.to_str().unwrap());
+
+        let env = GridEnvironment::new(5, 5);
+        let mut agent = DQNAgent::new(8)
+            .with_double(true)
+            .with_dueling(true)
+            .with_soft_update(0.5);
+        agent.batch_size = 4;
+        agent.n_steps = 1;
+        assert!(<DQNAgent as Agent<GridEnvironment>>::try_init(
+            &mut agent, &env
+        ));
+        // A value and four advantages, combined into four Q-values.
+        let layers = &agent.policy_net.layers;
+        assert_eq!(layers[layers.len() - 2].output_size(), 5);
+        assert_eq!(layers[layers.len() - 1].kind, LayerKind::Dueling(4));
+        assert_eq!(agent.policy_net.output_size(), 4);
+
+        let untrained = agent.target_net.clone();
+        for i in 0..6 {
+            <DQNAgent as Agent<GridEnvironment>>::learn(
+                &mut agent,
+                &cell(i % 5, 0),
+                &MoveAction::Down,
+                1.0,
+                Some(&cell(i % 5, 1)),
+            );
+        }
+        assert_eq!(agent.learn_steps, 3);
+        // The target network follows the policy network after every step, without reaching it.
+        let weights = |network: &NeuralNetwork| network.layers[0].weights.clone();
+        assert_ne!(weights(&agent.target_net), weights(&untrained));
+        assert_ne!(weights(&agent.target_net), weights(&agent.policy_net));
+
+        agent.save_to_file(json).unwrap();
+        agent.save_binary(binary).unwrap();
+        let state = cell(2, 3);
+        for file in [json, binary] {
+            let loaded = DQNAgent::load_from_file(file).unwrap();
+            assert!(loaded.double && loaded.dueling);
+            assert_eq!(loaded.soft_update, Some(0.5));
+            let (a, b) = (
+                loaded.predict_network(&state),
+                agent.predict_network(&state),
+            );
+            assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-12));
+        }
     }
 }
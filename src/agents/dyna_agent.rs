@@ -0,0 +1,262 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    agents::{
+        persistence::{load_json, load_json_checked, save_json, Loaded},
+        q_agent::QAgent,
+    },
+    Agent, Environment,
+};
+
+pub const PLANNING_STEPS_DEFAULT: usize = 10;
+pub const KAPPA_DEFAULT: f32 = 1e-3;
+
+/// What the model remembers about a state-action pair from its last real visit.
+#[derive(Debug, Clone, Copy)]
+struct ModelEntry {
+    reward: f32,
+    /// `None` if the transition ended the episode.
+    next_state: Option<usize>,
+    /// Real step count at the last visit, used for the Dyna-Q+ bonus.
+    last_visit: u64,
+}
+
+/// A model-based tabular agent, Dyna-Q (and Dyna-Q+ when `kappa > 0`).
+///
+/// Each real transition passed to `learn` is used for one Q-learning update,
+/// and is stored in a tabular model of the environment. After that the agent
+/// replays `planning_steps` random transitions from the model, each with the
+/// same Q-learning update. The model assumes a deterministic environment and
+/// keeps the last outcome observed for every state-action pair.
+///
+/// Dyna-Q+ adds `κ · √τ` to the reward of simulated transitions, where `τ` is
+/// the number of real steps since the pair was last tried, which makes the agent
+/// revisit pairs whose outcome may have changed. Actions that were never tried
+/// in a visited state are also planned with, modelled as leading back to the
+/// same state with no reward, so that the bonus eventually gets them tried.
+///
+/// The Q-table is flattened into the saved file, so the file can be loaded as
+/// a plain [`QAgent`] and served with `QAgent::predict_all`. The model is not saved.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DynaQAgent {
+    #[serde(flatten)]
+    pub q: QAgent,
+    /// Number of simulated updates per real step.
+    planning_steps: usize,
+    /// Exploration bonus κ of Dyna-Q+, 0 for plain Dyna-Q.
+    kappa: f32,
+    #[serde(skip)]
+    model: HashMap<(usize, usize), ModelEntry>,
+    /// The keys of `model`, so planning can sample them uniformly.
+    #[serde(skip)]
+    observed: Vec<(usize, usize)>,
+    /// Number of real steps taken.
+    #[serde(skip)]
+    time: u64,
+}
+
+impl Default for DynaQAgent {
+    fn default() -> Self {
+        Self::new(PLANNING_STEPS_DEFAULT)
+    }
+}
+
+impl DynaQAgent {
+    /// Creates a Dyna-Q agent that runs `planning_steps` simulated updates per real step.
+    pub fn new(planning_steps: usize) -> Self {
+        DynaQAgent {
+            q: QAgent::new(),
+            planning_steps,
+            kappa: 0.0,
+            model: HashMap::new(),
+            observed: Vec::new(),
+            time: 0,
+        }
+    }
+
+    /// Creates a Dyna-Q+ agent with exploration bonus `kappa`.
+    pub fn new_plus(planning_steps: usize, kappa: f32) -> Self {
+        DynaQAgent {
+            kappa,
+            ..Self::new(planning_steps)
+        }
+    }
+
+    pub fn save_to_file(&self, file_path: impl AsRef<Path>) -> std::io::Result<()> {
+        save_json(file_path, self)
+    }
+
+    pub fn load_from_file(file_path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        load_json(file_path).map(|(agent, _)| agent)
+    }
+
+    /// Loads an agent like `load_from_file` and checks its Q-table against `env` like
+    /// `QAgent::load_for`. Also returns whether the backup was loaded, for the caller to report.
+    pub fn load_for<E: Environment>(
+        file_path: impl AsRef<Path>,
+        env: &E,
+    ) -> std::io::Result<(Self, Loaded)> {
+        load_json_checked(file_path, |agent: &Self| agent.q.check_compatible(env))
+    }
+
+    pub fn predict_all<E: Environment>(&self) -> Vec<(E::State, E::Action)> {
+        self.q.predict_all::<E>()
+    }
+
+    /// Number of distinct state-action pairs in the model.
+    pub fn model_size(&self) -> usize {
+        self.observed.len()
+    }
+
+    /// One Q-learning update of `(s, a)` towards `r + γ · maxₐ' Q(s', a')`.
+    fn q_update(&mut self, state_i: usize, action_i: usize, reward: f32, next_i: Option<usize>) {
+        let target = match next_i {
+            Some(next_i) => reward + self.q.gamma * self.q.max_q(next_i),
+            None => reward,
+        };
+        self.q.update(state_i, action_i, target);
+    }
+
+    fn plan(&mut self) {
+        if self.observed.is_empty() {
+            return;
+        }
+        for _ in 0..self.planning_steps {
+            let key = self.observed[self.q.rng.random_range(0..self.observed.len())];
+            let entry = self.model[&key];
+            let bonus = self.kappa * ((self.time - entry.last_visit) as f32).sqrt();
+            self.q_update(key.0, key.1, entry.reward + bonus, entry.next_state);
+        }
+    }
+}
+
+impl<E: Environment> Agent<E> for DynaQAgent {
+    fn try_init(&mut self, env: &E) -> bool {
+        if !<QAgent as Agent<E>>::try_init(&mut self.q, env) {
+            return false;
+        }
+        self.model.clear();
+        self.observed.clear();
+        self.time = 0;
+        true
+    }
+
+    fn act(&mut self, state: &E::State) -> E::Action {
+        let action_i = self.q.epsilon_greedy_index(self.q.state_index(state));
+        self.q.action_from_index(action_i)
+    }
+
+    /// Applies the Q-learning update for the real transition, records it in the
+    /// model, and then runs the planning updates.
+    fn learn(
+        &mut self,
+        state: &E::State,
+        action: &E::Action,
+        reward: f32,
+        next_state: Option<&E::State>,
+    ) {
+        let key = (self.q.state_index(state), self.q.action_index(action));
+        let next_i = next_state.map(|next_state| self.q.state_index(next_state));
+        self.q_update(key.0, key.1, reward, next_i);
+
+        self.time += 1;
+        if self.kappa > 0.0 {
+            for action_i in 0..self.q.action_space_size {
+                let untried = (key.0, action_i);
+                if !self.model.contains_key(&untried) {
+                    let entry = ModelEntry {
+                        reward: 0.0,
+                        next_state: Some(key.0),
+                        last_visit: self.time,
+                    };
+                    self.model.insert(untried, entry);
+                    self.observed.push(untried);
+                }
+            }
+        }
+        let entry = ModelEntry {
+            reward,
+            next_state: next_i,
+            last_visit: self.time,
+        };
+        if self.model.insert(key, entry).is_none() {
+            self.observed.push(key);
+        }
+        self.plan();
+    }
+
+    fn predict(&self, state: &E::State) -> E::Action {
+        <QAgent as Agent<E>>::predict(&self.q, state)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        <QAgent as Agent<E>>::seed(&mut self.q, seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::test_utils::{cell, init_grid},
+        callback::Silent,
+        environment::move_to_center::{GridEnvironment, MoveAction},
+        train::train_q,
+        Step,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn test_reaches_center_from_every_cell() {
+        let (rows, cols) = (5, 5);
+        let mut env = GridEnvironment::new(rows, cols);
+        let agent = Rc::new(RefCell::new(DynaQAgent::new(20)));
+        assert!(agent.borrow_mut().try_init(&env));
+        let agents = [agent.clone() as Rc<RefCell<dyn Agent<GridEnvironment>>>];
+        train_q(&mut env, &agents, 10_000, None, &mut Silent);
+
+        for row in 0..rows {
+            for col in 0..cols {
+                if (row, col) == (rows / 2, cols / 2) {
+                    continue;
+                }
+                env.board = cell(row, col);
+                // The shortest path never takes more than `rows + cols` steps.
+                let mut reached = false;
+                for _ in 0..rows + cols {
+                    let action = <DynaQAgent as Agent<GridEnvironment>>::predict(
+                        &agent.borrow(),
+                        &env.board,
+                    );
+                    let Step { next_state, .. } = env.step(&action);
+                    if next_state.is_none() {
+                        reached = env.board.position == (rows / 2, cols / 2);
+                        break;
+                    }
+                }
+                assert!(reached, "did not reach the center from ({row}, {col})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_plus_models_each_pair_once() {
+        let mut agent = init_grid(DynaQAgent::new_plus(5, 0.01));
+        let (state, next_state) = (cell(0, 0), cell(1, 0));
+        let action = MoveAction::Down;
+        for _ in 0..3 {
+            <DynaQAgent as Agent<GridEnvironment>>::learn(
+                &mut agent,
+                &state,
+                &action,
+                1.0,
+                Some(&next_state),
+            );
+        }
+        // The untried actions of the state are modelled as well.
+        assert_eq!(agent.model_size(), 4);
+        assert_eq!(agent.time, 3);
+    }
+}
@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{
+    agents::{
+        persistence::{load_json, load_json_checked, save_json, Loaded},
+        q_agent::QAgent,
+    },
+    Agent, Environment,
+};
+
+pub const LAMBDA_DEFAULT: f32 = 0.8;
+
+/// Traces that fall below this value are dropped from the active set.
+const TRACE_CUTOFF: f32 = 1e-4;
+
+/// Selects which action the eligibility-trace update bootstraps from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LambdaAlgorithm {
+    /// Watkins's Q(λ): bootstraps from the greedy action in the next state and
+    /// cuts every trace as soon as an exploratory action is taken.
+    WatkinsQ,
+    /// SARSA(λ): bootstraps from the action that is actually taken next.
+    Sarsa,
+}
+
+/// How the trace of a visited state-action pair is bumped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    /// `e(s, a) ← e(s, a) + 1`
+    Accumulating,
+    /// `e(s, a) ← 1`, and the traces of the other actions in `s` are cleared.
+    Replacing,
+}
+
+/// A tabular agent with eligibility traces, i.e. Q(λ) or SARSA(λ).
+///
+/// Every visited state-action pair keeps a trace that decays by γλ per step,
+/// and each TD error is applied to all pairs in proportion to their trace.
+/// This spreads sparse terminal rewards back along the whole episode instead
+/// of one step per episode.
+///
+/// The Q-table uses the same layout as [`QAgent`], and is flattened into the
+/// saved file so it can be loaded as a plain `QAgent` as well.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LambdaAgent {
+    #[serde(flatten)]
+    pub q: QAgent,
+    /// Trace decay λ where (0 ≤ λ ≤ 1)
+    /// λ = 0 gives one-step updates, λ = 1 gives Monte Carlo like updates.
+    lambda: f32,
+    algorithm: LambdaAlgorithm,
+    trace_kind: TraceKind,
+    /// Eligibility trace for every entry of the Q-table.
+    #[serde(skip)]
+    traces: Vec<f32>,
+    /// Q-table indices with a non-zero trace, so that updates do not have to
+    /// sweep the whole table.
+    #[serde(skip)]
+    active: Vec<usize>,
+    /// The `(state, action)` indices chosen in `learn` for the next state,
+    /// which `act` has to follow for the update to be on-policy.
+    #[serde(skip)]
+    next_action: Option<(usize, usize)>,
+}
+
+impl Default for LambdaAgent {
+    fn default() -> Self {
+        Self::new(
+            LambdaAlgorithm::WatkinsQ,
+            TraceKind::Accumulating,
+            LAMBDA_DEFAULT,
+        )
+    }
+}
+
+impl LambdaAgent {
+    pub fn new(algorithm: LambdaAlgorithm, trace_kind: TraceKind, lambda: f32) -> Self {
+        LambdaAgent {
+            q: QAgent::new(),
+            lambda,
+            algorithm,
+            trace_kind,
+            traces: Vec::new(),
+            active: Vec::new(),
+            next_action: None,
+        }
+    }
+
+    pub fn lambda(&self) -> f32 {
+        self.lambda
+    }
+
+    pub fn save_to_file(&self, file_path: impl AsRef<Path>) -> std::io::Result<()> {
+        save_json(file_path, self)
+    }
+
+    pub fn load_from_file(file_path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        load_json(file_path).map(|(agent, _)| agent)
+    }
+
+    /// Loads an agent like `load_from_file` and checks its Q-table against `env` like
+    /// `QAgent::load_for`. Also returns whether the backup was loaded, for the caller to report.
+    pub fn load_for<E: Environment>(
+        file_path: impl AsRef<Path>,
+        env: &E,
+    ) -> std::io::Result<(Self, Loaded)> {
+        load_json_checked(file_path, |agent: &Self| agent.q.check_compatible(env))
+    }
+
+    /// Clears all traces, e.g. at the end of an episode.
+    fn reset_traces(&mut self) {
+        for &i in &self.active {
+            self.traces[i] = 0.0;
+        }
+        self.active.clear();
+    }
+
+    fn visit(&mut self, state_i: usize, action_i: usize) {
+        if self.traces.len() != self.q.q_table.len() {
+            // Traces are not saved, so they are allocated lazily after loading.
+            self.traces = vec![0.0; self.q.q_table.len()];
+            self.active.clear();
+        }
+        let row = state_i * self.q.action_space_size..(state_i + 1) * self.q.action_space_size;
+        let i = row.start + action_i;
+        if self.trace_kind == TraceKind::Replacing {
+            let traces = &mut self.traces;
+            self.active.retain(|&j| {
+                if j != i && row.contains(&j) {
+                    traces[j] = 0.0;
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        if self.traces[i] == 0.0 {
+            self.active.push(i);
+        }
+        match self.trace_kind {
+            TraceKind::Accumulating => self.traces[i] += 1.0,
+            TraceKind::Replacing => self.traces[i] = 1.0,
+        }
+    }
+}
+
+impl<E: Environment> Agent<E> for LambdaAgent {
+    fn try_init(&mut self, env: &E) -> bool {
+        if !<QAgent as Agent<E>>::try_init(&mut self.q, env) {
+            return false;
+        }
+        self.traces = vec![0.0; self.q.q_table.len()];
+        self.active.clear();
+        self.next_action = None;
+        true
+    }
+
+    fn act(&mut self, state: &E::State) -> E::Action {
+        let state_i = self.q.state_index(state);
+        let action_i = match self.next_action.take() {
+            Some((s, a)) if s == state_i => a,
+            _ => self.q.epsilon_greedy_index(state_i),
+        };
+        self.q.action_from_index(action_i)
+    }
+
+    /// Applies the **λ-return update** to every traced state-action pair.
+    ///
+    /// ```math
+    /// δ ← r + γ · Q(s', a') − Q(s, a)
+    /// Q(x, y) ← Q(x, y) + α · δ · e(x, y)
+    /// e(x, y) ← γ · λ · e(x, y)
+    /// ```
+    ///
+    /// where `a'` is the greedy action for Watkins's Q(λ) and the next action
+    /// actually taken for SARSA(λ). The next action is picked here and handed
+    /// to the following `act` call. All traces are cleared at the end of an
+    /// episode, and for Watkins's Q(λ) also when `a'` is exploratory.
+    fn learn(
+        &mut self,
+        state: &E::State,
+        action: &E::Action,
+        reward: f32,
+        next_state: Option<&E::State>,
+    ) {
+        let state_i = self.q.state_index(state);
+        let action_i = self.q.action_index(action);
+        let mut cut_traces = true;
+        let target = match next_state {
+            Some(next_state) => {
+                let next_i = self.q.state_index(next_state);
+                let row = next_i * self.q.action_space_size;
+                let greedy_q = self.q.q_table[row + self.q.best_action_index(next_i)];
+                let next_action = self.q.epsilon_greedy_index(next_i);
+                let next_q = self.q.q_table[row + next_action];
+                self.next_action = Some((next_i, next_action));
+                match self.algorithm {
+                    LambdaAlgorithm::WatkinsQ => {
+                        // Ties with the greedy value are not exploratory.
+                        cut_traces = next_q < greedy_q;
+                        reward + self.q.gamma * greedy_q
+                    }
+                    LambdaAlgorithm::Sarsa => {
+                        cut_traces = false;
+                        reward + self.q.gamma * next_q
+                    }
+                }
+            }
+            None => {
+                self.next_action = None;
+                reward
+            }
+        };
+
+        let delta = target - self.q.q_table[state_i * self.q.action_space_size + action_i];
+        self.visit(state_i, action_i);
+
+        let step = self.q.alpha * delta;
+        let decay = self.q.gamma * self.lambda;
+        let (q_table, traces) = (&mut self.q.q_table, &mut self.traces);
+        self.active.retain(|&i| {
+            q_table[i] += step * traces[i];
+            traces[i] *= decay;
+            if traces[i] < TRACE_CUTOFF {
+                traces[i] = 0.0;
+                false
+            } else {
+                true
+            }
+        });
+        if cut_traces {
+            self.reset_traces();
+        }
+    }
+
+    fn predict(&self, state: &E::State) -> E::Action {
+        <QAgent as Agent<E>>::predict(&self.q, state)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        <QAgent as Agent<E>>::seed(&mut self.q, seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::test_utils::{cell, init_grid, test_dir},
+        environment::{
+            move_to_center::{GridEnvironment, MoveAction},
+            tic_tac_toe::TicTacEnvironment,
+        },
+    };
+
+    fn init(algorithm: LambdaAlgorithm, trace_kind: TraceKind) -> LambdaAgent {
+        init_grid(LambdaAgent::new(algorithm, trace_kind, 0.9))
+    }
+
+    #[test]
+    fn test_terminal_reward_reaches_episode_start() {
+        let mut agent = init(LambdaAlgorithm::Sarsa, TraceKind::Accumulating);
+        // (2, 0) -> (2, 1) -> center
+        <LambdaAgent as Agent<GridEnvironment>>::learn(
+            &mut agent,
+            &cell(2, 0),
+            &MoveAction::Right,
+            0.0,
+            Some(&cell(2, 1)),
+        );
+        <LambdaAgent as Agent<GridEnvironment>>::learn(
+            &mut agent,
+            &cell(2, 1),
+            &MoveAction::Right,
+            100.0,
+            None,
+        );
+        let first = agent.q.state_index(&cell(2, 0)) * agent.q.action_space_size
+            + agent.q.action_index(&MoveAction::Right);
+        // α · δ · γλ = 0.1 · 100 · 0.81
+        assert!((agent.q.q_table[first] - 8.1).abs() < 1e-4);
+        // Traces do not leak into the next episode.
+        assert!(agent.active.is_empty());
+    }
+
+    #[test]
+    fn test_replacing_traces_do_not_accumulate() {
+        let mut accumulating = init(LambdaAlgorithm::Sarsa, TraceKind::Accumulating);
+        let mut replacing = init(LambdaAlgorithm::Sarsa, TraceKind::Replacing);
+        for agent in [&mut accumulating, &mut replacing] {
+            // Bounce between two cells twice before reaching the center.
+            for _ in 0..2 {
+                <LambdaAgent as Agent<GridEnvironment>>::learn(
+                    agent,
+                    &cell(2, 0),
+                    &MoveAction::Right,
+                    0.0,
+                    Some(&cell(2, 1)),
+                );
+                <LambdaAgent as Agent<GridEnvironment>>::learn(
+                    agent,
+                    &cell(2, 1),
+                    &MoveAction::Left,
+                    0.0,
+                    Some(&cell(2, 0)),
+                );
+            }
+            <LambdaAgent as Agent<GridEnvironment>>::learn(
+                agent,
+                &cell(2, 0),
+                &MoveAction::Right,
+                100.0,
+                None,
+            );
+        }
+        let i = accumulating.q.state_index(&cell(2, 0)) * accumulating.q.action_space_size
+            + accumulating.q.action_index(&MoveAction::Right);
+        assert!(accumulating.q.q_table[i] > replacing.q.q_table[i]);
+    }
+
+    #[test]
+    fn test_load_for_checks_the_q_table() {
+        let agent = init(LambdaAlgorithm::WatkinsQ, TraceKind::Replacing);
+        let dir = test_dir("lambda_agent_for");
+        let path = dir.join("grid.json");
+        agent.save_to_file(&path).unwrap();
+
+        let (loaded, from) = LambdaAgent::load_for(&path, &GridEnvironment::new(5, 5)).unwrap();
+        assert_eq!(from, Loaded::File);
+        assert_eq!(loaded.lambda(), 0.9);
+        let error = LambdaAgent::load_for(&path, &TicTacEnvironment::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(LambdaAgent::load_for(&path, &GridEnvironment::new(5, 7)).is_err());
+    }
+}
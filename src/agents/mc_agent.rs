@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    agents::{
+        persistence::{load_json, load_json_checked, save_json, Loaded},
+        q_agent::QAgent,
+    },
+    Agent, Environment, State,
+};
+
+/// Which occurrences of a state-action pair in an episode produce an update.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitMode {
+    /// Only the return following the first occurrence in the episode is used.
+    FirstVisit,
+    /// The return following every occurrence is used.
+    EveryVisit,
+}
+
+/// How far a Q-value is moved towards an observed return.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepSize {
+    /// `Q(s, a) ← Q(s, a) + (G − Q(s, a)) / N(s, a)`, i.e. the mean of all returns.
+    IncrementalMean,
+    /// `Q(s, a) ← Q(s, a) + α · (G − Q(s, a))`, which tracks non-stationary returns.
+    Constant,
+}
+
+/// An on-policy Monte Carlo control agent with ε-greedy exploration.
+///
+/// The agent buffers the transitions of an episode, and only updates the
+/// Q-table once the terminal `learn(..., None)` call arrives, using the actual
+/// discounted return `G = r₀ + γ · r₁ + γ² · r₂ + …` instead of a bootstrapped
+/// estimate.
+///
+/// The Q-table is flattened into the saved file, so the file can be loaded as
+/// a plain [`QAgent`] and served with `QAgent::predict_all`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MonteCarloAgent {
+    #[serde(flatten)]
+    pub q: QAgent,
+    visit_mode: VisitMode,
+    step_size: StepSize,
+    /// Number of returns averaged into each Q-value, used by the incremental mean.
+    #[serde(default)]
+    visits: Vec<u32>,
+    /// The `(state, action, reward)` indices of the running episode, one per player.
+    #[serde(skip)]
+    episodes: Vec<Vec<(usize, usize, f32)>>,
+}
+
+impl Default for MonteCarloAgent {
+    fn default() -> Self {
+        Self::new(VisitMode::FirstVisit, StepSize::IncrementalMean)
+    }
+}
+
+impl MonteCarloAgent {
+    pub fn new(visit_mode: VisitMode, step_size: StepSize) -> Self {
+        MonteCarloAgent {
+            q: QAgent::new(),
+            visit_mode,
+            step_size,
+            visits: Vec::new(),
+            episodes: Vec::new(),
+        }
+    }
+
+    pub fn save_to_file(&self, file_path: impl AsRef<Path>) -> std::io::Result<()> {
+        save_json(file_path, self)
+    }
+
+    pub fn load_from_file(file_path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        load_json(file_path).map(|(agent, _)| agent)
+    }
+
+    /// Loads an agent like `load_from_file` and checks its Q-table against `env` like
+    /// `QAgent::load_for`. Also returns whether the backup was loaded, for the caller to report.
+    pub fn load_for<E: Environment>(
+        file_path: impl AsRef<Path>,
+        env: &E,
+    ) -> std::io::Result<(Self, Loaded)> {
+        load_json_checked(file_path, |agent: &Self| agent.q.check_compatible(env))
+    }
+
+    pub fn predict_all<E: Environment>(&self) -> Vec<(E::State, E::Action)> {
+        self.q.predict_all::<E>()
+    }
+
+    /// Walks the finished episode backwards, accumulating the return and
+    /// updating the visited state-action pairs.
+    fn update_from_episode(&mut self, episode: &[(usize, usize, f32)]) {
+        if self.visits.len() != self.q.q_table.len() {
+            self.visits = vec![0; self.q.q_table.len()];
+        }
+        let mut first_visit = HashMap::new();
+        if self.visit_mode == VisitMode::FirstVisit {
+            for (t, &(state_i, action_i, _)) in episode.iter().enumerate() {
+                first_visit.entry((state_i, action_i)).or_insert(t);
+            }
+        }
+        let mut ret = 0.0;
+        for (t, &(state_i, action_i, reward)) in episode.iter().enumerate().rev() {
+            ret = reward + self.q.gamma * ret;
+            if self.visit_mode == VisitMode::FirstVisit && first_visit[&(state_i, action_i)] != t {
+                continue;
+            }
+            let i = state_i * self.q.action_space_size + action_i;
+            self.visits[i] = self.visits[i].saturating_add(1);
+            let step = match self.step_size {
+                StepSize::IncrementalMean => 1.0 / self.visits[i] as f32,
+                StepSize::Constant => self.q.alpha,
+            };
+            self.q.q_table[i] += step * (ret - self.q.q_table[i]);
+        }
+    }
+}
+
+impl<E: Environment> Agent<E> for MonteCarloAgent {
+    fn try_init(&mut self, env: &E) -> bool {
+        if !<QAgent as Agent<E>>::try_init(&mut self.q, env) {
+            return false;
+        }
+        self.visits = vec![0; self.q.q_table.len()];
+        self.episodes.clear();
+        true
+    }
+
+    fn act(&mut self, state: &E::State) -> E::Action {
+        let action_i = self.q.epsilon_greedy_index(self.q.state_index(state));
+        self.q.action_from_index(action_i)
+    }
+
+    /// Buffers the transition, and updates the Q-table from the returns of the
+    /// whole episode when `next_state` is `None`.
+    fn learn(
+        &mut self,
+        state: &E::State,
+        action: &E::Action,
+        reward: f32,
+        next_state: Option<&E::State>,
+    ) {
+        let player = state.current_player();
+        if self.episodes.len() <= player {
+            self.episodes.resize_with(player + 1, Vec::new);
+        }
+        let transition = (
+            self.q.state_index(state),
+            self.q.action_index(action),
+            reward,
+        );
+        self.episodes[player].push(transition);
+        if next_state.is_none() {
+            let episode = std::mem::take(&mut self.episodes[player]);
+            self.update_from_episode(&episode);
+        }
+    }
+
+    fn predict(&self, state: &E::State) -> E::Action {
+        <QAgent as Agent<E>>::predict(&self.q, state)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        <QAgent as Agent<E>>::seed(&mut self.q, seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::test_utils::{cell, init_grid},
+        environment::move_to_center::{Board, GridEnvironment, MoveAction},
+    };
+
+    /// Plays (2, 0) -> (2, 1) -> (2, 0) -> (2, 1) -> center, so that the
+    /// first state-action pair is visited twice.
+    fn play_loop(agent: &mut MonteCarloAgent, final_reward: f32) {
+        let moves = [
+            (cell(2, 0), MoveAction::Right, Some(cell(2, 1))),
+            (cell(2, 1), MoveAction::Left, Some(cell(2, 0))),
+            (cell(2, 0), MoveAction::Right, Some(cell(2, 1))),
+            (cell(2, 1), MoveAction::Right, None),
+        ];
+        for (i, (state, action, next_state)) in moves.iter().enumerate() {
+            let reward = if i == moves.len() - 1 {
+                final_reward
+            } else {
+                0.0
+            };
+            <MonteCarloAgent as Agent<GridEnvironment>>::learn(
+                agent,
+                state,
+                action,
+                reward,
+                next_state.as_ref(),
+            );
+        }
+    }
+
+    fn init(visit_mode: VisitMode, step_size: StepSize) -> MonteCarloAgent {
+        init_grid(MonteCarloAgent::new(visit_mode, step_size))
+    }
+
+    fn q_value(agent: &MonteCarloAgent, state: &Board, action: &MoveAction) -> f32 {
+        agent.q.q_table
+            [agent.q.state_index(state) * agent.q.action_space_size + agent.q.action_index(action)]
+    }
+
+    #[test]
+    fn test_first_and_every_visit_returns() {
+        let mut first = init(VisitMode::FirstVisit, StepSize::IncrementalMean);
+        let mut every = init(VisitMode::EveryVisit, StepSize::IncrementalMean);
+        play_loop(&mut first, 100.0);
+        play_loop(&mut every, 100.0);
+        // Nothing is learned before the episode ends, then the first visit of
+        // ((2, 0), Right) is followed by γ³ · 100 and the second by γ · 100.
+        assert!((q_value(&first, &cell(2, 0), &MoveAction::Right) - 72.9).abs() < 1e-3);
+        assert!((q_value(&every, &cell(2, 0), &MoveAction::Right) - 81.45).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_incremental_mean_and_constant_step_size() {
+        let mut mean = init(VisitMode::FirstVisit, StepSize::IncrementalMean);
+        let mut constant = init(VisitMode::FirstVisit, StepSize::Constant);
+        for reward in [100.0, 200.0] {
+            play_loop(&mut mean, reward);
+            play_loop(&mut constant, reward);
+        }
+        assert!((q_value(&mean, &cell(2, 1), &MoveAction::Right) - 150.0).abs() < 1e-3);
+        // α = 0.1: 10, then 10 + 0.1 · (200 − 10)
+        assert!((q_value(&constant, &cell(2, 1), &MoveAction::Right) - 29.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_saved_agent_loads_as_q_agent() {
+        let mut agent = init(VisitMode::EveryVisit, StepSize::Constant);
+        play_loop(&mut agent, 100.0);
+        let json = serde_json::to_string(&agent).unwrap();
+        let q_agent: QAgent = serde_json::from_str(&json).unwrap();
+        assert_eq!(q_agent.q_table, agent.q.q_table);
+        assert_eq!(
+            q_agent.predict_all::<GridEnvironment>().len(),
+            agent.predict_all::<GridEnvironment>().len()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! What a saved agent records about where and how it was trained.
+//!
+//! The metadata is stored with the agent in both the JSON and the binary format, and
+//! `load_for` checks it against the environment the agent is loaded for, so that e.g.
+//! a grid Q-table is not silently served as a tic-tac-toe policy.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Environment, Space};
+
+/// Describes a saved model. Models saved before the metadata existed load with the
+/// default, empty metadata, for which only the spaces can be checked.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ModelMetadata {
+    /// The kind of agent, e.g. `QAgent`.
+    pub agent: String,
+    /// The `Environment::ID` of the environment the agent was initialized for.
+    pub environment: String,
+    /// The `Environment::parameters` of that environment.
+    pub parameters: BTreeMap<String, f64>,
+    /// Episodes trained, summed over all training runs.
+    pub episodes: u64,
+    /// When the last training run finished, in seconds since the Unix epoch.
+    pub trained_at: Option<u64>,
+    /// The seed of the last training run, if it was seeded, see `Agent::seed`.
+    pub seed: Option<u64>,
+    /// Evaluation results by name, e.g. the agreement with the optimal policy.
+    pub evaluation: BTreeMap<String, f64>,
+}
+
+impl ModelMetadata {
+    /// The metadata of an agent of kind `agent` that was just initialized for `env`.
+    pub fn new<E: Environment>(agent: &str, env: &E) -> Self {
+        ModelMetadata {
+            agent: agent.to_string(),
+            environment: E::ID.to_string(),
+            parameters: env
+                .parameters()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Records a finished training run of `episodes` episodes, seeded with `seed` if any.
+    pub fn record_training(&mut self, episodes: u64, seed: Option<u64>) {
+        self.episodes += episodes;
+        self.seed = seed;
+        self.trained_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+    }
+
+    /// Checks that the model was saved by an agent of kind `agent` for an environment
+    /// like `env`. Empty fields, as in models saved without metadata, are not checked.
+    pub fn check<E: Environment>(&self, agent: &str, env: &E) -> Result<(), CompatibilityError> {
+        if !self.agent.is_empty() && self.agent != agent {
+            return Err(CompatibilityError::Agent {
+                expected: agent.to_string(),
+                found: self.agent.clone(),
+            });
+        }
+        if self.environment.is_empty() {
+            return Ok(());
+        }
+        if self.environment != E::ID {
+            return Err(CompatibilityError::Environment {
+                expected: E::ID.to_string(),
+                found: self.environment.clone(),
+            });
+        }
+        let expected = ModelMetadata::new(agent, env).parameters;
+        let names = expected.keys().chain(self.parameters.keys());
+        for name in names {
+            let (expected, found) = (expected.get(name), self.parameters.get(name));
+            if expected != found {
+                return Err(CompatibilityError::Parameter {
+                    name: name.clone(),
+                    expected: expected.copied(),
+                    found: found.copied(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the spaces an agent was initialized with are the spaces of `env`, the
+/// same way `Agent::try_init` reads them.
+pub fn check_spaces<E: Environment>(
+    env: &E,
+    discrete_states: &[usize],
+    continuous_states: &[Range<f32>],
+    actions: &[usize],
+) -> Result<(), CompatibilityError> {
+    let (disc, cont) = env.state_space().as_vecs();
+    if disc != discrete_states || cont != continuous_states {
+        return Err(CompatibilityError::StateSpace {
+            expected: (disc, cont),
+            found: (discrete_states.to_vec(), continuous_states.to_vec()),
+        });
+    }
+    let (disc, cont) = env.action_space().as_vecs();
+    if disc != actions || !cont.is_empty() {
+        return Err(CompatibilityError::ActionSpace {
+            expected: disc,
+            found: actions.to_vec(),
+        });
+    }
+    Ok(())
+}
+
+/// Why a saved model cannot be used with an environment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompatibilityError {
+    Agent {
+        expected: String,
+        found: String,
+    },
+    Environment {
+        expected: String,
+        found: String,
+    },
+    /// A parameter differs, or exists on only one side.
+    Parameter {
+        name: String,
+        expected: Option<f64>,
+        found: Option<f64>,
+    },
+    /// The discrete and continuous dimensions of the state space.
+    StateSpace {
+        expected: (Vec<usize>, Vec<Range<f32>>),
+        found: (Vec<usize>, Vec<Range<f32>>),
+    },
+    ActionSpace {
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+}
+
+impl fmt::Display for CompatibilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompatibilityError::Agent { expected, found } => {
+                write!(f, "the model was saved by a {found}, not a {expected}")
+            }
+            CompatibilityError::Environment { expected, found } => write!(
+                f,
+                "the model was trained on the `{found}` environment, not `{expected}`"
+            ),
+            CompatibilityError::Parameter {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "the environment parameter `{name}` is {found:?} in the model, but {expected:?} in the environment"
+            ),
+            CompatibilityError::StateSpace { expected, found } => write!(
+                f,
+                "the model has the state space {found:?}, but the environment has {expected:?}"
+            ),
+            CompatibilityError::ActionSpace { expected, found } => write!(
+                f,
+                "the model has the action space {found:?}, but the environment has {expected:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompatibilityError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::{q_agent::QAgent, test_utils::test_dir},
+        environment::{move_to_center::GridEnvironment, tic_tac_toe::TicTacEnvironment},
+        Agent,
+    };
+
+    #[test]
+    fn test_check_reports_the_first_mismatch() {
+        let grid = GridEnvironment::new(5, 5);
+        let mut metadata = ModelMetadata::new("QAgent", &grid);
+        assert_eq!(metadata.parameters["rows"], 5.0);
+        assert_eq!(metadata.check("QAgent", &grid), Ok(()));
+        assert!(matches!(
+            metadata.check("DQNAgent", &grid),
+            Err(CompatibilityError::Agent { .. })
+        ));
+        assert!(matches!(
+            metadata.check("QAgent", &TicTacEnvironment::new()),
+            Err(CompatibilityError::Environment { .. })
+        ));
+        let error = metadata
+            .check("QAgent", &GridEnvironment::new(5, 7))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            CompatibilityError::Parameter {
+                name: "cols".to_string(),
+                expected: Some(7.0),
+                found: Some(5.0),
+            }
+        );
+        assert!(error.to_string().contains("`cols`"));
+
+        metadata.record_training(10, None);
+        metadata.record_training(5, Some(3));
+        assert_eq!(metadata.episodes, 15);
+        assert!(metadata.trained_at.is_some());
+        // The seed is saved with the agent in both formats.
+        let mut agent = QAgent::new();
+        <QAgent as Agent<GridEnvironment>>::try_init(&mut agent, &grid);
+        agent.metadata = metadata;
+        let dir = test_dir("metadata");
+        let (json, binary) = (dir.join("grid.json"), dir.join("grid.bin"));
+        agent.save_to_file(&json).unwrap();
+        agent.save_binary(&binary).unwrap();
+        for path in [&json, &binary] {
+            assert_eq!(QAgent::load_from_file(path).unwrap().metadata.seed, Some(3));
+        }
+        // Models saved without metadata can only be checked by their spaces.
+        assert_eq!(
+            ModelMetadata::default().check("QAgent", &TicTacEnvironment::new()),
+            Ok(())
+        );
+        assert!(check_spaces(&grid, &[5, 5], &[], &[4]).is_ok());
+        assert!(matches!(
+            check_spaces(&grid, &[3, 3, 3, 3, 3, 3, 3, 3, 3], &[], &[9]),
+            Err(CompatibilityError::StateSpace { .. })
+        ));
+    }
+}
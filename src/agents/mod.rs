@@ -1,4 +1,14 @@
+pub mod binary_format;
+pub mod dp_solver;
 pub mod dqn_agent;
+pub mod dyna_agent;
+pub mod lambda_agent;
+pub mod mc_agent;
+pub mod metadata;
+pub mod n_step;
 pub mod network;
+pub mod persistence;
 pub mod q_agent;
 pub mod random_agent;
+#[cfg(test)]
+pub(crate) mod test_utils;
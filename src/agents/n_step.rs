@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+
+pub const N_STEPS_DEFAULT: usize = 1;
+
+/// A sliding window over the last `n` transitions of one player, used to turn
+/// one-step rewards into n-step bootstrapped returns.
+///
+/// ```math
+/// G = r₀ + γ · r₁ + … + γⁿ⁻¹ · rₙ₋₁ + γⁿ · maxₐ Q(sₙ, a)
+/// ```
+///
+/// The window only sums the rewards, the caller adds the bootstrapped value
+/// scaled by [`NStepWindow::bootstrap_discount`].
+#[derive(Debug, Clone)]
+pub struct NStepWindow<T> {
+    n: usize,
+    gamma: f32,
+    transitions: VecDeque<(T, f32)>,
+}
+
+impl<T> NStepWindow<T> {
+    pub fn new(n: usize, gamma: f32) -> Self {
+        assert!(n > 0, "n-step returns need at least one step");
+        NStepWindow {
+            n,
+            gamma,
+            transitions: VecDeque::with_capacity(n),
+        }
+    }
+
+    /// The factor γⁿ applied to the value of the state reached after the window.
+    pub fn bootstrap_discount(&self) -> f32 {
+        self.gamma.powi(self.n as i32)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+
+    /// Adds a non-terminal transition.
+    /// Once `n` transitions are buffered the oldest one is returned together
+    /// with its discounted n-step reward, and should be bootstrapped from the
+    /// state reached after `item`.
+    pub fn push(&mut self, item: T, reward: f32) -> Option<(T, f32)> {
+        self.transitions.push_back((item, reward));
+        if self.transitions.len() < self.n {
+            return None;
+        }
+        let ret = self.discounted_reward();
+        self.transitions.pop_front().map(|(item, _)| (item, ret))
+    }
+
+    /// Adds the last transition of an episode and empties the window.
+    /// Every buffered transition is returned, oldest first, with the discounted
+    /// reward up to the end of the episode. None of them should be bootstrapped.
+    pub fn finish(&mut self, item: T, reward: f32) -> Vec<(T, f32)> {
+        self.transitions.push_back((item, reward));
+        let mut finished = Vec::with_capacity(self.transitions.len());
+        while !self.transitions.is_empty() {
+            let ret = self.discounted_reward();
+            let (item, _) = self.transitions.pop_front().unwrap();
+            finished.push((item, ret));
+        }
+        finished
+    }
+
+    /// Drops all buffered transitions without returning them.
+    pub fn clear(&mut self) {
+        self.transitions.clear();
+    }
+
+    fn discounted_reward(&self) -> f32 {
+        self.transitions
+            .iter()
+            .rev()
+            .fold(0.0, |ret, (_, reward)| reward + self.gamma * ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_returns_oldest_after_n() {
+        let mut window = NStepWindow::new(3, 0.5);
+        assert_eq!(window.push('a', 1.0), None);
+        assert_eq!(window.push('b', 2.0), None);
+        // 1 + 0.5 · 2 + 0.25 · 4
+        assert_eq!(window.push('c', 4.0), Some(('a', 3.0)));
+        assert_eq!(window.len(), 2);
+        assert_eq!(window.bootstrap_discount(), 0.125);
+    }
+
+    #[test]
+    fn test_finish_flushes_truncated_returns() {
+        let mut window = NStepWindow::new(3, 0.5);
+        window.push('a', 1.0);
+        window.push('b', 2.0);
+        let finished = window.finish('c', 4.0);
+        assert_eq!(finished, vec![('a', 3.0), ('b', 4.0), ('c', 4.0)]);
+        assert!(window.is_empty());
+    }
+}
@@ -0,0 +1,641 @@
+//! Reverse-mode automatic differentiation on matrices.
+//!
+//! Operations are recorded on a [`Tape`] as they are computed. [`Tape::backward`]
+//! then walks the tape from the end and applies the chain rule to every operation,
+//! so that the gradient of a scalar with respect to every recorded matrix is found
+//! without deriving it by hand.
+//!
+//! ```
+//! use rust_rl::agents::network::{autodiff::Tape, matrix::Matrix};
+//!
+//! let mut tape = Tape::new();
+//! let x = tape.variable(&Matrix::row_vector(vec![1.0, 2.0, 3.0]));
+//! let squares = tape.square(x);
+//! let y = tape.sum(squares);
+//! tape.backward(y);
+//! assert_eq!(tape.value(y)[0][0], 14.0);
+//! assert_eq!(tape.grad(x).as_slice(), [2.0, 4.0, 6.0]);
+//! ```
+
+use super::conv::Conv2d;
+use super::matrix::{axpy, matmul, matmul_transpose_a, matmul_transpose_b, Matrix};
+use super::nn::ActivationFunction;
+
+/// A matrix recorded on a [`Tape`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Var(usize);
+
+#[derive(Clone, Debug)]
+enum Op {
+    Leaf,
+    Add(Var, Var),
+    Sub(Var, Var),
+    Mul(Var, Var),
+    /// `scale · a + shift`, only the scale is needed backwards.
+    Affine(Var, f64),
+    AddRow(Var, Var),
+    MatMul(Var, Var),
+    MatMulTransposeB(Var, Var),
+    Activation(Var, ActivationFunction),
+    LogSoftmax(Var),
+    Ln(Var),
+    Exp(Var),
+    Square(Var),
+    Clamp(Var, f64, f64),
+    Huber(Var, f64),
+    Sum(Var),
+    Mean(Var),
+    Conv2d {
+        input: Var,
+        weights: Var,
+        biases: Var,
+        conv: Conv2d,
+    },
+}
+
+/// Records operations on matrices, and computes gradients by walking them backwards.
+///
+/// The values and gradients of every operation are kept in buffers that `clear`
+/// keeps, so that recording the same computation again does not allocate.
+#[derive(Clone, Debug, Default)]
+pub struct Tape {
+    ops: Vec<Op>,
+    /// Whether the gradient of every operation is needed, i.e. whether it depends on a variable.
+    requires_grad: Vec<bool>,
+    values: Vec<Matrix>,
+    grads: Vec<Matrix>,
+    scratch: Matrix,
+    scratch_row: Vec<f64>,
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Tape::default()
+    }
+
+    /// Forgets every operation, and keeps the buffers.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.requires_grad.clear();
+    }
+
+    /// Number of recorded operations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn value(&self, var: Var) -> &Matrix {
+        &self.values[var.0]
+    }
+
+    /// The gradient of the output of the last `backward` with respect to `var`.
+    /// All zeros for constants and for operations that only depend on constants.
+    pub fn grad(&self, var: Var) -> &Matrix {
+        &self.grads[var.0]
+    }
+
+    /// Records `op` with its value computed by `compute` from the values before it.
+    fn push(
+        &mut self,
+        op: Op,
+        requires_grad: bool,
+        compute: impl FnOnce(&[Matrix], &mut Matrix),
+    ) -> Var {
+        let index = self.ops.len();
+        if self.values.len() == index {
+            self.values.push(Matrix::default());
+        }
+        let (before, rest) = self.values.split_at_mut(index);
+        compute(before, &mut rest[0]);
+        self.ops.push(op);
+        self.requires_grad.push(requires_grad);
+        Var(index)
+    }
+
+    fn requires(&self, vars: &[Var]) -> bool {
+        vars.iter().any(|v| self.requires_grad[v.0])
+    }
+
+    fn unary(&mut self, op: Op, a: Var, f: impl Fn(f64) -> f64) -> Var {
+        let requires_grad = self.requires(&[a]);
+        self.push(op, requires_grad, |values, out| {
+            out.copy_from(&values[a.0]);
+            out.map_inplace(f);
+        })
+    }
+
+    fn binary(&mut self, op: Op, a: Var, b: Var, f: impl Fn(f64, f64) -> f64) -> Var {
+        let requires_grad = self.requires(&[a, b]);
+        self.push(op, requires_grad, |values, out| {
+            let (a, b) = (&values[a.0], &values[b.0]);
+            assert_eq!(
+                a.shape(),
+                b.shape(),
+                "elementwise operation on different shapes"
+            );
+            out.copy_from(a);
+            for (o, b) in out.as_mut_slice().iter_mut().zip(b.as_slice()) {
+                *o = f(*o, *b);
+            }
+        })
+    }
+
+    /// A matrix that gradients are computed for, e.g. weights.
+    pub fn variable(&mut self, value: &Matrix) -> Var {
+        self.push(Op::Leaf, true, |_, out| out.copy_from(value))
+    }
+
+    /// A single-row variable, e.g. biases.
+    pub fn variable_row(&mut self, value: &[f64]) -> Var {
+        self.push(Op::Leaf, true, |_, out| out.copy_from_row(value))
+    }
+
+    /// A matrix that no gradient is needed for, e.g. inputs or targets.
+    pub fn constant(&mut self, value: &Matrix) -> Var {
+        self.push(Op::Leaf, false, |_, out| out.copy_from(value))
+    }
+
+    /// A constant with the element in row `i` and column `j` set to `f(i, j)`.
+    pub fn constant_from_fn(
+        &mut self,
+        rows: usize,
+        cols: usize,
+        mut f: impl FnMut(usize, usize) -> f64,
+    ) -> Var {
+        self.push(Op::Leaf, false, |_, out| {
+            out.reset(rows, cols);
+            for (i, row) in out.iter_rows_mut().enumerate() {
+                for (j, x) in row.iter_mut().enumerate() {
+                    *x = f(i, j);
+                }
+            }
+        })
+    }
+
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        self.binary(Op::Add(a, b), a, b, |a, b| a + b)
+    }
+
+    pub fn sub(&mut self, a: Var, b: Var) -> Var {
+        self.binary(Op::Sub(a, b), a, b, |a, b| a - b)
+    }
+
+    /// Elementwise product.
+    pub fn mul(&mut self, a: Var, b: Var) -> Var {
+        self.binary(Op::Mul(a, b), a, b, |a, b| a * b)
+    }
+
+    /// `scale · a + shift`, elementwise.
+    pub fn affine(&mut self, a: Var, scale: f64, shift: f64) -> Var {
+        self.unary(Op::Affine(a, scale), a, |x| scale * x + shift)
+    }
+
+    pub fn scale(&mut self, a: Var, scale: f64) -> Var {
+        self.affine(a, scale, 0.0)
+    }
+
+    /// Adds the single row `row` to every row of `a`, e.g. biases to a batch.
+    pub fn add_row(&mut self, a: Var, row: Var) -> Var {
+        let requires_grad = self.requires(&[a, row]);
+        self.push(Op::AddRow(a, row), requires_grad, |values, out| {
+            assert_eq!(values[row.0].rows(), 1, "add_row takes a single row");
+            out.copy_from(&values[a.0]);
+            out.add_row(values[row.0].as_slice());
+        })
+    }
+
+    /// `a · b`
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var {
+        let requires_grad = self.requires(&[a, b]);
+        self.push(Op::MatMul(a, b), requires_grad, |values, out| {
+            matmul(&values[a.0], &values[b.0], out)
+        })
+    }
+
+    /// `a · bᵀ`, e.g. a batch times the weights of a layer.
+    pub fn matmul_transpose_b(&mut self, a: Var, b: Var) -> Var {
+        let requires_grad = self.requires(&[a, b]);
+        self.push(Op::MatMulTransposeB(a, b), requires_grad, |values, out| {
+            matmul_transpose_b(&values[a.0], &values[b.0], out)
+        })
+    }
+
+    /// Applies `activation` to every row of `a`.
+    pub fn activation(&mut self, a: Var, activation: &ActivationFunction) -> Var {
+        let requires_grad = self.requires(&[a]);
+        self.push(
+            Op::Activation(a, activation.clone()),
+            requires_grad,
+            |values, out| {
+                out.copy_from(&values[a.0]);
+                for row in out.iter_rows_mut() {
+                    activation.apply_slice(row);
+                }
+            },
+        )
+    }
+
+    /// The logarithm of the softmax of every row, `xᵢ − ln Σⱼ exp(xⱼ)`, without
+    /// computing small probabilities first.
+    pub fn log_softmax(&mut self, a: Var) -> Var {
+        let requires_grad = self.requires(&[a]);
+        self.push(Op::LogSoftmax(a), requires_grad, |values, out| {
+            out.copy_from(&values[a.0]);
+            for row in out.iter_rows_mut() {
+                let max = row.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                let log_sum = row.iter().map(|x| (x - max).exp()).sum::<f64>().ln();
+                for x in row.iter_mut() {
+                    *x -= max + log_sum;
+                }
+            }
+        })
+    }
+
+    pub fn ln(&mut self, a: Var) -> Var {
+        self.unary(Op::Ln(a), a, f64::ln)
+    }
+
+    pub fn exp(&mut self, a: Var) -> Var {
+        self.unary(Op::Exp(a), a, f64::exp)
+    }
+
+    pub fn square(&mut self, a: Var) -> Var {
+        self.unary(Op::Square(a), a, |x| x * x)
+    }
+
+    /// Limits every element to `[low, high]`. The gradient is zero where the limits apply.
+    pub fn clamp(&mut self, a: Var, low: f64, high: f64) -> Var {
+        self.unary(Op::Clamp(a, low, high), a, |x| x.clamp(low, high))
+    }
+
+    /// The Huber function with threshold δ of every element, `x²/2` up to δ and `δ · (|x| − δ/2)` beyond.
+    pub fn huber(&mut self, a: Var, delta: f64) -> Var {
+        self.unary(Op::Huber(a, delta), a, |x| {
+            if x.abs() <= delta {
+                0.5 * x * x
+            } else {
+                delta * (x.abs() - 0.5 * delta)
+            }
+        })
+    }
+
+    /// The sum of all elements, as a 1 x 1 matrix.
+    pub fn sum(&mut self, a: Var) -> Var {
+        let requires_grad = self.requires(&[a]);
+        self.push(Op::Sum(a), requires_grad, |values, out| {
+            out.reset(1, 1);
+            out[0][0] = values[a.0].as_slice().iter().sum();
+        })
+    }
+
+    /// The mean of all elements, as a 1 x 1 matrix.
+    pub fn mean(&mut self, a: Var) -> Var {
+        let requires_grad = self.requires(&[a]);
+        self.push(Op::Mean(a), requires_grad, |values, out| {
+            let a = values[a.0].as_slice();
+            out.reset(1, 1);
+            out[0][0] = a.iter().sum::<f64>() / a.len().max(1) as f64;
+        })
+    }
+
+    /// The weighted sums of a convolution of every sample of `input`, see `Conv2d`.
+    pub fn conv2d(&mut self, input: Var, weights: Var, biases: Var, conv: Conv2d) -> Var {
+        let requires_grad = self.requires(&[input, weights, biases]);
+        let op = Op::Conv2d {
+            input,
+            weights,
+            biases,
+            conv,
+        };
+        self.push(op, requires_grad, |values, out| {
+            conv.forward(
+                &values[weights.0],
+                values[biases.0].as_slice(),
+                &values[input.0],
+                out,
+            )
+        })
+    }
+
+    /// Computes the gradient of `output`, which has to be a 1 x 1 matrix, with respect
+    /// to every operation on the tape, see `grad`.
+    pub fn backward(&mut self, output: Var) {
+        assert_eq!(
+            self.values[output.0].shape(),
+            (1, 1),
+            "backward needs a scalar output"
+        );
+        let Tape {
+            ops,
+            requires_grad,
+            values,
+            grads,
+            scratch,
+            scratch_row,
+        } = self;
+        if grads.len() < ops.len() {
+            grads.resize_with(ops.len(), Matrix::default);
+        }
+        for (grad, value) in grads.iter_mut().zip(values.iter()).take(ops.len()) {
+            grad.reset(value.rows(), value.cols());
+        }
+        grads[output.0][0][0] = 1.0;
+
+        for i in (0..=output.0).rev() {
+            if !requires_grad[i] {
+                continue;
+            }
+            let (before, rest) = grads.split_at_mut(i);
+            let grad = &rest[0];
+            let value = &values[i];
+            // Adds the gradient `g` of an input, if it is needed.
+            let needs = |v: &Var| requires_grad[v.0];
+            match &ops[i] {
+                Op::Leaf => {}
+                Op::Add(a, b) => {
+                    for v in [a, b] {
+                        if needs(v) {
+                            before[v.0].add_scaled(1.0, grad);
+                        }
+                    }
+                }
+                Op::Sub(a, b) => {
+                    if needs(a) {
+                        before[a.0].add_scaled(1.0, grad);
+                    }
+                    if needs(b) {
+                        before[b.0].add_scaled(-1.0, grad);
+                    }
+                }
+                Op::Mul(a, b) => {
+                    for (v, other) in [(a, b), (b, a)] {
+                        if needs(v) {
+                            let g = before[v.0].as_mut_slice();
+                            let pairs = grad.as_slice().iter().zip(values[other.0].as_slice());
+                            for (g, (d, o)) in g.iter_mut().zip(pairs) {
+                                *g += d * o;
+                            }
+                        }
+                    }
+                }
+                Op::Affine(a, scale) => before[a.0].add_scaled(*scale, grad),
+                Op::AddRow(a, row) => {
+                    if needs(a) {
+                        before[a.0].add_scaled(1.0, grad);
+                    }
+                    if needs(row) {
+                        grad.column_sums(scratch_row);
+                        axpy(before[row.0].as_mut_slice(), 1.0, scratch_row);
+                    }
+                }
+                Op::MatMul(a, b) => {
+                    if needs(a) {
+                        matmul_transpose_b(grad, &values[b.0], scratch);
+                        before[a.0].add_scaled(1.0, scratch);
+                    }
+                    if needs(b) {
+                        matmul_transpose_a(&values[a.0], grad, scratch);
+                        before[b.0].add_scaled(1.0, scratch);
+                    }
+                }
+                Op::MatMulTransposeB(a, b) => {
+                    if needs(a) {
+                        matmul(grad, &values[b.0], scratch);
+                        before[a.0].add_scaled(1.0, scratch);
+                    }
+                    if needs(b) {
+                        matmul_transpose_a(grad, &values[a.0], scratch);
+                        before[b.0].add_scaled(1.0, scratch);
+                    }
+                }
+                Op::Activation(a, activation) => {
+                    scratch.copy_from(grad);
+                    let rows = scratch
+                        .iter_rows_mut()
+                        .zip(values[a.0].iter_rows())
+                        .zip(value.iter_rows());
+                    for ((g, x), activated) in rows {
+                        activation.backward_slice(x, activated, g);
+                    }
+                    before[a.0].add_scaled(1.0, scratch);
+                }
+                Op::LogSoftmax(a) => {
+                    // d/dx (x − ln Σ exp x) · g = g − softmax(x) · Σ g
+                    let rows = before[a.0]
+                        .iter_rows_mut()
+                        .zip(grad.iter_rows())
+                        .zip(value.iter_rows());
+                    for ((out, g), log_p) in rows {
+                        let total: f64 = g.iter().sum();
+                        for ((o, g), log_p) in out.iter_mut().zip(g).zip(log_p) {
+                            *o += g - log_p.exp() * total;
+                        }
+                    }
+                }
+                Op::Ln(a) => {
+                    elementwise(&mut before[a.0], grad, &values[a.0], |g, x, _| g / x, value)
+                }
+                Op::Exp(a) => {
+                    elementwise(&mut before[a.0], grad, &values[a.0], |g, _, y| g * y, value)
+                }
+                Op::Square(a) => elementwise(
+                    &mut before[a.0],
+                    grad,
+                    &values[a.0],
+                    |g, x, _| 2.0 * g * x,
+                    value,
+                ),
+                Op::Clamp(a, low, high) => elementwise(
+                    &mut before[a.0],
+                    grad,
+                    &values[a.0],
+                    |g, x, _| if (*low..=*high).contains(&x) { g } else { 0.0 },
+                    value,
+                ),
+                Op::Huber(a, delta) => elementwise(
+                    &mut before[a.0],
+                    grad,
+                    &values[a.0],
+                    |g, x, _| g * x.clamp(-delta, *delta),
+                    value,
+                ),
+                Op::Sum(a) => {
+                    let g = grad[0][0];
+                    before[a.0].map_inplace(|x| x + g);
+                }
+                Op::Mean(a) => {
+                    let g = grad[0][0] / values[a.0].as_slice().len().max(1) as f64;
+                    before[a.0].map_inplace(|x| x + g);
+                }
+                Op::Conv2d {
+                    input,
+                    weights,
+                    biases,
+                    conv,
+                } => {
+                    if needs(input) {
+                        conv.backward_input(&values[weights.0], grad, scratch);
+                        before[input.0].add_scaled(1.0, scratch);
+                    }
+                    if needs(weights) || needs(biases) {
+                        conv.backward_params(grad, &values[input.0], scratch, scratch_row);
+                        before[weights.0].add_scaled(1.0, scratch);
+                        axpy(before[biases.0].as_mut_slice(), 1.0, scratch_row);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Adds `f(g, x, y)` to every element of `input_grad`, for the gradient `g`,
+/// the input `x` and the output `y` of an elementwise operation.
+fn elementwise(
+    input_grad: &mut Matrix,
+    grad: &Matrix,
+    input: &Matrix,
+    f: impl Fn(f64, f64, f64) -> f64,
+    output: &Matrix,
+) {
+    let elements = grad
+        .as_slice()
+        .iter()
+        .zip(input.as_slice())
+        .zip(output.as_slice());
+    for (out, ((g, x), y)) in input_grad.as_mut_slice().iter_mut().zip(elements) {
+        *out += f(*g, *x, *y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::network::conv::PlaneShape;
+
+    /// Compares the gradient of `build` with respect to every element of `inputs`
+    /// with central finite differences.
+    fn check(inputs: &[Matrix], build: impl Fn(&mut Tape, &[Var]) -> Var) {
+        let evaluate = |inputs: &[Matrix]| {
+            let mut tape = Tape::new();
+            let vars: Vec<Var> = inputs.iter().map(|m| tape.variable(m)).collect();
+            let output = build(&mut tape, &vars);
+            tape.value(output)[0][0]
+        };
+        let mut tape = Tape::new();
+        let vars: Vec<Var> = inputs.iter().map(|m| tape.variable(m)).collect();
+        let output = build(&mut tape, &vars);
+        tape.backward(output);
+
+        let h = 1e-6;
+        let mut inputs = inputs.to_vec();
+        for (n, var) in vars.iter().enumerate() {
+            for k in 0..inputs[n].as_slice().len() {
+                let original = inputs[n].as_slice()[k];
+                inputs[n].as_mut_slice()[k] = original + h;
+                let plus = evaluate(&inputs);
+                inputs[n].as_mut_slice()[k] = original - h;
+                let minus = evaluate(&inputs);
+                inputs[n].as_mut_slice()[k] = original;
+                let numeric = (plus - minus) / (2.0 * h);
+                let analytic = tape.grad(*var).as_slice()[k];
+                assert!(
+                    (numeric - analytic).abs() < 1e-6 * (1.0 + numeric.abs()),
+                    "input {n} element {k}: {numeric} != {analytic}"
+                );
+            }
+        }
+    }
+
+    fn matrix(rows: usize, cols: usize, seed: usize) -> Matrix {
+        Matrix::from_fn(rows, cols, |i, j| {
+            (((i * 7 + j * 13 + seed * 5) % 11) as f64 - 5.3) * 0.15
+        })
+    }
+
+    #[test]
+    fn test_gradients_match_finite_differences() {
+        let (a, b, row) = (matrix(3, 4, 0), matrix(3, 4, 1), matrix(1, 4, 2));
+        let square = matrix(4, 2, 3);
+        check(&[a.clone(), b.clone()], |t, v| {
+            let sum = t.add(v[0], v[1]);
+            let difference = t.sub(sum, v[1]);
+            let product = t.mul(difference, v[1]);
+            let scaled = t.affine(product, -1.5, 0.3);
+            t.mean(scaled)
+        });
+        check(&[a.clone(), row], |t, v| {
+            let shifted = t.add_row(v[0], v[1]);
+            let squared = t.square(shifted);
+            t.sum(squared)
+        });
+        check(&[a.clone(), square.clone(), b.clone()], |t, v| {
+            let product = t.matmul(v[0], v[1]);
+            let projected = t.matmul_transpose_b(v[2], v[0]);
+            let (p, q) = (t.sum(product), t.sum(projected));
+            let both = t.mul(p, q);
+            t.exp(both)
+        });
+        // Reusing a value sums the gradients of every use.
+        check(std::slice::from_ref(&a), |t, v| {
+            let squared = t.mul(v[0], v[0]);
+            let positive = t.exp(squared);
+            let logs = t.ln(positive);
+            t.sum(logs)
+        });
+        check(&[a.clone(), b.clone()], |t, v| {
+            let difference = t.sub(v[0], v[1]);
+            let huber = t.huber(difference, 0.4);
+            let clamped = t.clamp(v[0], -0.5, 0.5);
+            let (h, c) = (t.sum(huber), t.sum(clamped));
+            t.add(h, c)
+        });
+        check(&[a.clone(), b.clone()], |t, v| {
+            let log_p = t.log_softmax(v[0]);
+            let weighted = t.mul(log_p, v[1]);
+            t.sum(weighted)
+        });
+        for activation in [
+            ActivationFunction::Sigmoid,
+            ActivationFunction::Tanh,
+            ActivationFunction::ELU(0.7),
+            ActivationFunction::GELU,
+            ActivationFunction::Softmax,
+        ] {
+            check(&[a.clone(), b.clone()], |t, v| {
+                let activated = t.activation(v[0], &activation);
+                let weighted = t.mul(activated, v[1]);
+                t.sum(weighted)
+            });
+        }
+        let conv = Conv2d::new(PlaneShape::new(2, 3, 3), 2, 2).padding(1);
+        let input = matrix(2, 18, 4);
+        let weights = matrix(2, conv.patch_size(), 5);
+        let biases = matrix(1, 2, 6);
+        let upstream = matrix(2, conv.output_shape().size(), 7);
+        check(&[input, weights, biases, upstream], |t, v| {
+            let sums = t.conv2d(v[0], v[1], v[2], conv);
+            let weighted = t.mul(sums, v[3]);
+            t.sum(weighted)
+        });
+    }
+
+    #[test]
+    fn test_constants_get_no_gradient_and_buffers_are_reused() {
+        let mut tape = Tape::new();
+        for _ in 0..2 {
+            tape.clear();
+            let x = tape.constant(&matrix(2, 3, 0));
+            let w = tape.variable(&matrix(4, 3, 1));
+            let z = tape.matmul_transpose_b(x, w);
+            let loss = tape.mean(z);
+            tape.backward(loss);
+            assert!(tape.grad(x).as_slice().iter().all(|g| *g == 0.0));
+            assert!(tape.grad(w).as_slice().iter().any(|g| *g != 0.0));
+            assert_eq!(tape.len(), 4);
+        }
+    }
+}
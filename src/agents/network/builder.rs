@@ -0,0 +1,512 @@
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use super::conv::{Conv2d, PlaneShape};
+use super::initializer::Initializer;
+use super::nn::{
+    ActivationFunction, GradientClipping, Layer, LayerKind, LossFunction, NeuralNetwork,
+};
+use super::schedule::{LearningRateSchedule, LearningRateScheduler, ScheduleInterval};
+
+pub const LEARNING_RATE_DEFAULT: f64 = 0.01;
+
+/// Why a network could not be built, or does not fit its input.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetworkError {
+    NoLayers,
+    /// A layer without neurons or without inputs.
+    EmptyLayer {
+        layer: usize,
+    },
+    /// The inputs of a layer do not match the outputs of the layer before it.
+    ShapeMismatch {
+        layer: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// A layer does not have one bias per neuron.
+    BiasMismatch {
+        layer: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// An input does not match the inputs of the first layer.
+    InputSize {
+        expected: usize,
+        found: usize,
+    },
+    InvalidLearningRate(f64),
+    /// A dropout rate outside of `[0, 1)`, or dropout on the output layer.
+    InvalidDropout {
+        layer: usize,
+        rate: f64,
+    },
+    /// A clipping limit that is not positive.
+    InvalidClipping(f64),
+    /// A target does not match the outputs of the last layer.
+    TargetSize {
+        expected: usize,
+        found: usize,
+    },
+    /// There is not one target per input.
+    SampleCount {
+        inputs: usize,
+        targets: usize,
+    },
+    /// There are no samples to train on.
+    NoSamples,
+    InvalidBatchSize,
+    /// A validation split outside of `[0, 1)`, or one that leaves no samples for training.
+    InvalidValidationSplit(f64),
+    /// A convolution whose kernel does not fit its input, or whose weights do not fit the kernel.
+    InvalidConvolution {
+        layer: usize,
+    },
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::NoLayers => write!(f, "the network has no layers"),
+            NetworkError::EmptyLayer { layer } => {
+                write!(f, "layer {layer} has no neurons or inputs")
+            }
+            NetworkError::ShapeMismatch {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "layer {layer} takes {found} inputs, but the layer before it has {expected} outputs"
+            ),
+            NetworkError::BiasMismatch {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "layer {layer} has {expected} neurons, but {found} biases"
+            ),
+            NetworkError::InputSize { expected, found } => {
+                write!(f, "the network takes {expected} inputs, got {found}")
+            }
+            NetworkError::InvalidDropout { layer, rate } => write!(
+                f,
+                "layer {layer} cannot use a dropout rate of {rate}, it has to be in [0, 1) and the layer cannot be the output layer"
+            ),
+            NetworkError::TargetSize { expected, found } => {
+                write!(f, "the network has {expected} outputs, got a target with {found}")
+            }
+            NetworkError::SampleCount { inputs, targets } => {
+                write!(f, "got {inputs} inputs, but {targets} targets")
+            }
+            NetworkError::InvalidConvolution { layer } => {
+                write!(f, "layer {layer} is not a valid convolution")
+            }
+            NetworkError::NoSamples => write!(f, "there are no samples to train on"),
+            NetworkError::InvalidBatchSize => write!(f, "the batch size has to be at least 1"),
+            NetworkError::InvalidValidationSplit(split) => write!(
+                f,
+                "cannot hold out a fraction of {split} for validation, it has to be in [0, 1) and leave samples for training"
+            ),
+            NetworkError::InvalidClipping(limit) => {
+                write!(f, "gradient clipping limits have to be positive, got {limit}")
+            }
+            NetworkError::InvalidLearningRate(rate) => {
+                write!(
+                    f,
+                    "the learning rate has to be positive and finite, got {rate}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+/// The declaration of one layer for a [`NetworkBuilder`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LayerSpec {
+    /// Number of neurons, or of outputs of a convolution.
+    pub size: usize,
+    pub activation: ActivationFunction,
+    pub initializer: Initializer,
+    /// Coefficient of the L2 penalty on the weights, 0 to use the weight decay of the network.
+    #[serde(default)]
+    pub l2: f64,
+    /// Fraction of the outputs dropped during training, 0 to disable.
+    #[serde(default)]
+    pub dropout: f64,
+    #[serde(default)]
+    pub kind: LayerKind,
+}
+
+impl LayerSpec {
+    /// A layer with He initialization for ReLU and its variants, and Xavier
+    /// initialization for every other activation.
+    pub fn dense(size: usize, activation: ActivationFunction) -> Self {
+        let initializer = match activation {
+            ActivationFunction::ReLU
+            | ActivationFunction::LeakyReLU(_)
+            | ActivationFunction::ELU(_)
+            | ActivationFunction::GELU => Initializer::HeUniform,
+            _ => Initializer::XavierUniform,
+        };
+        LayerSpec {
+            size,
+            activation,
+            initializer,
+            l2: 0.0,
+            dropout: 0.0,
+            kind: LayerKind::Dense,
+        }
+    }
+
+    /// A convolutional layer, initialized like `dense`. Its input has to be the
+    /// planes of `conv.input`, laid out like `PlaneShape` describes.
+    pub fn conv2d(conv: Conv2d, activation: ActivationFunction) -> Self {
+        let size = if conv.is_valid() {
+            conv.output_shape().size()
+        } else {
+            0
+        };
+        LayerSpec {
+            kind: LayerKind::Conv2d(conv),
+            ..Self::dense(size, activation)
+        }
+    }
+
+    /// Passes the planes of `shape` on as one flat vector, e.g. after the last convolution.
+    pub fn flatten(shape: PlaneShape) -> Self {
+        LayerSpec {
+            kind: LayerKind::Flatten(shape),
+            ..Self::dense(shape.size(), ActivationFunction::Linear)
+        }
+    }
+
+    /// The head of a dueling network with `actions` outputs, see `LayerKind::Dueling`.
+    /// The layer before it has to have `actions + 1` outputs, the value and the advantages,
+    /// usually without an activation.
+    pub fn dueling(actions: usize) -> Self {
+        LayerSpec {
+            kind: LayerKind::Dueling(actions),
+            ..Self::dense(actions, ActivationFunction::Linear)
+        }
+    }
+
+    pub fn initializer(mut self, initializer: Initializer) -> Self {
+        self.initializer = initializer;
+        self
+    }
+
+    pub fn l2(mut self, l2: f64) -> Self {
+        self.l2 = l2;
+        self
+    }
+
+    /// Drops each output with probability `rate` during training. Not allowed on the output layer.
+    pub fn dropout(mut self, rate: f64) -> Self {
+        self.dropout = rate;
+        self
+    }
+}
+
+/// Builds a [`NeuralNetwork`] layer by layer, each with its own size,
+/// activation, initializer and regularization.
+///
+/// ```
+/// use rust_rl::agents::network::{builder::LayerSpec, nn::*};
+///
+/// let network = NeuralNetwork::builder(9)
+///     .dense(64, ActivationFunction::ReLU)
+///     .layer(LayerSpec::dense(9, ActivationFunction::Linear).l2(1e-4))
+///     .loss(LossFunction::MeanSquaredError)
+///     .seed(42)
+///     .build()
+///     .unwrap();
+/// assert_eq!(network.output_size(), 9);
+/// ```
+#[derive(Clone, Debug)]
+pub struct NetworkBuilder {
+    input_size: usize,
+    layers: Vec<LayerSpec>,
+    learning_rate: f64,
+    loss_function: LossFunction,
+    weight_decay: f64,
+    clipping: GradientClipping,
+    scheduler: LearningRateScheduler,
+    seed: Option<u64>,
+    threads: usize,
+}
+
+impl NeuralNetwork {
+    /// Starts a network that takes `input_size` inputs.
+    pub fn builder(input_size: usize) -> NetworkBuilder {
+        NetworkBuilder::new(input_size)
+    }
+}
+
+impl NetworkBuilder {
+    pub fn new(input_size: usize) -> Self {
+        NetworkBuilder {
+            input_size,
+            layers: Vec::new(),
+            learning_rate: LEARNING_RATE_DEFAULT,
+            loss_function: LossFunction::MeanSquaredError,
+            weight_decay: 0.0,
+            clipping: GradientClipping::default(),
+            scheduler: LearningRateScheduler::default(),
+            seed: None,
+            threads: 1,
+        }
+    }
+
+    /// Appends a layer after the previous one. The last layer is the output layer.
+    pub fn layer(mut self, spec: LayerSpec) -> Self {
+        self.layers.push(spec);
+        self
+    }
+
+    /// Appends several layers.
+    pub fn layers(mut self, specs: impl IntoIterator<Item = LayerSpec>) -> Self {
+        self.layers.extend(specs);
+        self
+    }
+
+    /// Appends a layer with the default initializer and no regularization.
+    pub fn dense(self, size: usize, activation: ActivationFunction) -> Self {
+        self.layer(LayerSpec::dense(size, activation))
+    }
+
+    pub fn learning_rate(mut self, learning_rate: f64) -> Self {
+        self.learning_rate = learning_rate;
+        self
+    }
+
+    /// Changes the learning rate during training, stepped after every batch or every epoch.
+    pub fn schedule(mut self, schedule: LearningRateSchedule, interval: ScheduleInterval) -> Self {
+        self.scheduler = LearningRateScheduler::new(schedule, interval);
+        self
+    }
+
+    pub fn loss(mut self, loss_function: LossFunction) -> Self {
+        self.loss_function = loss_function;
+        self
+    }
+
+    /// L2 penalty for every layer that does not set its own.
+    pub fn weight_decay(mut self, weight_decay: f64) -> Self {
+        self.weight_decay = weight_decay;
+        self
+    }
+
+    pub fn clipping(mut self, clipping: GradientClipping) -> Self {
+        self.clipping = clipping;
+        self
+    }
+
+    /// Clamps every partial derivative to `[−max_value, max_value]`.
+    pub fn clip_value(mut self, max_value: f64) -> Self {
+        self.clipping.max_value = Some(max_value);
+        self
+    }
+
+    /// Scales the gradients down whenever their global L2 norm exceeds `max_norm`.
+    pub fn clip_norm(mut self, max_norm: f64) -> Self {
+        self.clipping.max_norm = Some(max_norm);
+        self
+    }
+
+    /// Draws the initial weights and the dropout masks from RNGs seeded with `seed`,
+    /// so that they are reproducible.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Splits batches across up to `threads` threads, see `NeuralNetwork::set_threads`.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn build(self) -> Result<NeuralNetwork, NetworkError> {
+        if !(self.learning_rate.is_finite() && self.learning_rate > 0.0) {
+            return Err(NetworkError::InvalidLearningRate(self.learning_rate));
+        }
+        if self.layers.is_empty() {
+            return Err(NetworkError::NoLayers);
+        }
+        if self.input_size == 0 {
+            return Err(NetworkError::EmptyLayer { layer: 0 });
+        }
+        for limit in [self.clipping.max_value, self.clipping.max_norm]
+            .into_iter()
+            .flatten()
+        {
+            if limit.is_nan() || limit <= 0.0 {
+                return Err(NetworkError::InvalidClipping(limit));
+            }
+        }
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+        // The network-wide activations are never used, every layer has its own.
+        let mut network = NeuralNetwork::new(
+            self.learning_rate,
+            ActivationFunction::Linear,
+            ActivationFunction::Linear,
+            self.loss_function,
+        );
+        network.set_clipping(self.clipping);
+        network.set_threads(self.threads);
+        network.set_schedule(self.scheduler.schedule, self.scheduler.interval);
+        if let Some(seed) = self.seed {
+            network.seed_dropout(seed.wrapping_add(1));
+        }
+        let mut input_size = self.input_size;
+        let last = self.layers.len() - 1;
+        for (i, spec) in self.layers.into_iter().enumerate() {
+            if let LayerKind::Conv2d(conv) = spec.kind {
+                if !conv.is_valid() {
+                    return Err(NetworkError::InvalidConvolution { layer: i });
+                }
+            }
+            if spec.size == 0 {
+                return Err(NetworkError::EmptyLayer { layer: i });
+            }
+            if !(0.0..1.0).contains(&spec.dropout) || (i == last && spec.dropout > 0.0) {
+                return Err(NetworkError::InvalidDropout {
+                    layer: i,
+                    rate: spec.dropout,
+                });
+            }
+            let mut layer = match spec.kind {
+                LayerKind::Dense => {
+                    Layer::with_initializer(input_size, spec.size, spec.initializer, &mut rng)
+                }
+                LayerKind::Conv2d(conv) => Layer::conv2d(conv, spec.initializer, &mut rng),
+                LayerKind::Flatten(shape) => Layer::flatten(shape),
+                LayerKind::Dueling(actions) => Layer::dueling(actions),
+            };
+            if layer.input_size() != input_size {
+                return Err(NetworkError::ShapeMismatch {
+                    layer: i,
+                    expected: input_size,
+                    found: layer.input_size(),
+                });
+            }
+            layer.activation = Some(spec.activation);
+            layer.l2 = if spec.l2 > 0.0 {
+                spec.l2
+            } else {
+                self.weight_decay
+            };
+            layer.dropout = spec.dropout;
+            input_size = layer.output_size();
+            network.push_layer(layer)?;
+        }
+        Ok(network)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers_keep_their_activation() {
+        let mut network = NeuralNetwork::builder(2)
+            .layer(
+                LayerSpec::dense(2, ActivationFunction::Linear)
+                    .initializer(Initializer::Constant(1.0)),
+            )
+            .layer(
+                LayerSpec::dense(1, ActivationFunction::ReLU)
+                    .initializer(Initializer::Constant(-1.0)),
+            )
+            .build()
+            .unwrap();
+        assert_eq!((network.input_size(), network.output_size()), (2, 1));
+        // Linear then ReLU: relu(−(x₀ + x₁) · 2)
+        assert_eq!(network.predict(vec![-1.0, -2.0]), vec![6.0]);
+        assert_eq!(network.predict(vec![1.0, 2.0]), vec![0.0]);
+        assert_eq!(
+            network.try_predict(vec![1.0]),
+            Err(NetworkError::InputSize {
+                expected: 2,
+                found: 1
+            })
+        );
+
+        // A layer that does not take the outputs of the last layer is rejected.
+        let layer = Layer::new(3, 1);
+        assert_eq!(
+            network.push_layer(layer),
+            Err(NetworkError::ShapeMismatch {
+                layer: 2,
+                expected: 1,
+                found: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_networks_are_errors() {
+        assert_eq!(
+            NeuralNetwork::builder(3).build().unwrap_err(),
+            NetworkError::NoLayers
+        );
+        assert_eq!(
+            NeuralNetwork::builder(3)
+                .dense(4, ActivationFunction::ReLU)
+                .dense(0, ActivationFunction::Linear)
+                .build()
+                .unwrap_err(),
+            NetworkError::EmptyLayer { layer: 1 }
+        );
+        assert!(NeuralNetwork::builder(3)
+            .dense(1, ActivationFunction::Linear)
+            .learning_rate(-0.1)
+            .build()
+            .is_err());
+
+        // A saved network whose layers do not fit together fails validation.
+        let json = r#"{
+            "layers": [{"weights": [[0.5, 0.5]], "biases": [0.1]},
+                       {"weights": [[0.3, 0.2]], "biases": [0.1]}],
+            "learning_rate": 0.5,
+            "activation_function": "ReLU",
+            "final_activation": "Linear",
+            "loss_function": "MeanSquaredError",
+            "history": []
+        }"#;
+        let network: NeuralNetwork = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            network.validate(),
+            Err(NetworkError::ShapeMismatch {
+                layer: 1,
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_seed_makes_weights_reproducible() {
+        let build = || {
+            NeuralNetwork::builder(4)
+                .dense(8, ActivationFunction::Tanh)
+                .dense(2, ActivationFunction::Linear)
+                .seed(9)
+                .build()
+                .unwrap()
+        };
+        let (a, b) = (build(), build());
+        for (x, y) in a.layers.iter().zip(&b.layers) {
+            assert_eq!(x.weights, y.weights);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use serde::{Deserialize, Serialize};
+
+use super::matrix::{matmul, matmul_transpose_a, matmul_transpose_b, Matrix};
+
+/// The shape of a sample made of planes, e.g. one plane per piece type of a board.
+///
+/// Samples are stored flat, plane after plane and row after row within a plane,
+/// so `channels × height × width` values per row of a batch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlaneShape {
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl PlaneShape {
+    pub const fn new(channels: usize, height: usize, width: usize) -> Self {
+        PlaneShape {
+            channels,
+            height,
+            width,
+        }
+    }
+
+    /// Number of values of one sample.
+    pub const fn size(&self) -> usize {
+        self.channels * self.plane_size()
+    }
+
+    /// Number of values of one channel.
+    pub const fn plane_size(&self) -> usize {
+        self.height * self.width
+    }
+}
+
+fn stride_default() -> usize {
+    1
+}
+
+/// The geometry of a 2D convolution with square kernels.
+///
+/// Every output channel slides a `kernel x kernel` window over all input channels,
+/// moving `stride` cells at a time over the input padded with `padding` zeros on
+/// every side. The weights of the layer hold one row of `channels · kernel²`
+/// values per output channel, and there is one bias per output channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conv2d {
+    pub input: PlaneShape,
+    pub out_channels: usize,
+    pub kernel: usize,
+    #[serde(default = "stride_default")]
+    pub stride: usize,
+    #[serde(default)]
+    pub padding: usize,
+}
+
+impl Conv2d {
+    /// A convolution with stride 1 and no padding.
+    pub fn new(input: PlaneShape, out_channels: usize, kernel: usize) -> Self {
+        Conv2d {
+            input,
+            out_channels,
+            kernel,
+            stride: 1,
+            padding: 0,
+        }
+    }
+
+    pub fn stride(mut self, stride: usize) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    pub fn padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Whether every size is positive and the kernel fits into the padded input.
+    pub fn is_valid(&self) -> bool {
+        self.input.size() > 0
+            && self.out_channels > 0
+            && self.kernel > 0
+            && self.stride > 0
+            && self.kernel <= self.input.height + 2 * self.padding
+            && self.kernel <= self.input.width + 2 * self.padding
+    }
+
+    /// Panics if the convolution is not valid.
+    pub fn output_shape(&self) -> PlaneShape {
+        assert!(self.is_valid(), "invalid convolution {self:?}");
+        let out = |size: usize| (size + 2 * self.padding - self.kernel) / self.stride + 1;
+        PlaneShape::new(
+            self.out_channels,
+            out(self.input.height),
+            out(self.input.width),
+        )
+    }
+
+    /// Number of weights of every output channel.
+    pub fn patch_size(&self) -> usize {
+        self.input.channels * self.kernel * self.kernel
+    }
+
+    /// Calls `f(position, patch index, input index)` for every weight of every output
+    /// position that falls inside the input, i.e. not on the padding.
+    fn for_each_tap(&self, mut f: impl FnMut(usize, usize, usize)) {
+        let PlaneShape {
+            channels,
+            height,
+            width,
+        } = self.input;
+        let out = self.output_shape();
+        for oy in 0..out.height {
+            for ox in 0..out.width {
+                let position = oy * out.width + ox;
+                for c in 0..channels {
+                    for ky in 0..self.kernel {
+                        let Some(iy) = (oy * self.stride + ky).checked_sub(self.padding) else {
+                            continue;
+                        };
+                        if iy >= height {
+                            continue;
+                        }
+                        for kx in 0..self.kernel {
+                            let Some(ix) = (ox * self.stride + kx).checked_sub(self.padding) else {
+                                continue;
+                            };
+                            if ix < width {
+                                let patch = (c * self.kernel + ky) * self.kernel + kx;
+                                f(position, patch, (c * height + iy) * width + ix);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Copies the input window of every output position into one row of `patches`.
+    fn im2col(&self, sample: &[f64], patches: &mut Matrix) {
+        let positions = self.output_shape().height * self.output_shape().width;
+        patches.reset(positions, self.patch_size());
+        let patch_size = self.patch_size();
+        let data = patches.as_mut_slice();
+        self.for_each_tap(|position, patch, input| {
+            data[position * patch_size + patch] = sample[input];
+        });
+    }
+
+    /// The reverse of `im2col`, adds every window back onto the input it was copied from.
+    fn col2im(&self, patches: &Matrix, sample: &mut [f64]) {
+        let patch_size = self.patch_size();
+        let data = patches.as_slice();
+        self.for_each_tap(|position, patch, input| {
+            sample[input] += data[position * patch_size + patch];
+        });
+    }
+
+    /// Writes the weighted sums of every sample of `input` into `sums`.
+    pub(crate) fn forward(
+        &self,
+        weights: &Matrix,
+        biases: &[f64],
+        input: &Matrix,
+        sums: &mut Matrix,
+    ) {
+        let out = self.output_shape();
+        let positions = out.height * out.width;
+        sums.reset(input.rows(), out.size());
+        let (mut patches, mut product) = (Matrix::default(), Matrix::default());
+        for (sample, sums) in input.iter_rows().zip(sums.iter_rows_mut()) {
+            self.im2col(sample, &mut patches);
+            // One row per position and one column per output channel.
+            matmul_transpose_b(&patches, weights, &mut product);
+            for (channel, (plane, bias)) in sums.chunks_exact_mut(positions).zip(biases).enumerate()
+            {
+                for (position, s) in plane.iter_mut().enumerate() {
+                    *s = product[position][channel] + bias;
+                }
+            }
+        }
+    }
+
+    /// Turns the gradient with respect to the weighted sums into the gradient with
+    /// respect to the inputs.
+    pub(crate) fn backward_input(
+        &self,
+        weights: &Matrix,
+        delta: &Matrix,
+        input_gradient: &mut Matrix,
+    ) {
+        let positions = self.output_shape().height * self.output_shape().width;
+        input_gradient.reset(delta.rows(), self.input.size());
+        let (mut sample_delta, mut patches) = (Matrix::default(), Matrix::default());
+        for (d, g) in delta.iter_rows().zip(input_gradient.iter_rows_mut()) {
+            sample_delta.copy_from_row(d);
+            let sample_delta = reshape(&mut sample_delta, self.out_channels, positions);
+            matmul_transpose_a(sample_delta, weights, &mut patches);
+            self.col2im(&patches, g);
+        }
+    }
+
+    /// Writes the gradients of the weights and biases, summed over the batch.
+    pub(crate) fn backward_params(
+        &self,
+        delta: &Matrix,
+        input: &Matrix,
+        weights: &mut Matrix,
+        biases: &mut Vec<f64>,
+    ) {
+        let positions = self.output_shape().height * self.output_shape().width;
+        weights.reset(self.out_channels, self.patch_size());
+        biases.clear();
+        biases.resize(self.out_channels, 0.0);
+        let (mut sample_delta, mut patches, mut product) =
+            (Matrix::default(), Matrix::default(), Matrix::default());
+        for (d, sample) in delta.iter_rows().zip(input.iter_rows()) {
+            sample_delta.copy_from_row(d);
+            let sample_delta = reshape(&mut sample_delta, self.out_channels, positions);
+            self.im2col(sample, &mut patches);
+            matmul(sample_delta, &patches, &mut product);
+            weights.add_scaled(1.0, &product);
+            for (b, plane) in biases.iter_mut().zip(sample_delta.iter_rows()) {
+                *b += plane.iter().sum::<f64>();
+            }
+        }
+    }
+}
+
+/// Views a single row as a `rows x cols` matrix, without copying.
+fn reshape(matrix: &mut Matrix, rows: usize, cols: usize) -> &Matrix {
+    let data = std::mem::take(matrix).into_vec();
+    *matrix = Matrix::from_vec(rows, cols, data);
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The convolution of one sample as a direct sum over the kernel, to check the kernels against.
+    fn naive_conv(conv: &Conv2d, weights: &Matrix, biases: &[f64], sample: &[f64]) -> Vec<f64> {
+        let out = conv.output_shape();
+        let mut sums = vec![0.0; out.size()];
+        for o in 0..conv.out_channels {
+            for oy in 0..out.height {
+                for ox in 0..out.width {
+                    let mut sum = biases[o];
+                    for c in 0..conv.input.channels {
+                        for ky in 0..conv.kernel {
+                            for kx in 0..conv.kernel {
+                                let iy = (oy * conv.stride + ky) as isize - conv.padding as isize;
+                                let ix = (ox * conv.stride + kx) as isize - conv.padding as isize;
+                                let inside = (0..conv.input.height as isize).contains(&iy)
+                                    && (0..conv.input.width as isize).contains(&ix);
+                                if inside {
+                                    let input = (c * conv.input.height + iy as usize)
+                                        * conv.input.width
+                                        + ix as usize;
+                                    sum += weights[o][(c * conv.kernel + ky) * conv.kernel + kx]
+                                        * sample[input];
+                                }
+                            }
+                        }
+                    }
+                    sums[(o * out.height + oy) * out.width + ox] = sum;
+                }
+            }
+        }
+        sums
+    }
+
+    #[test]
+    fn test_forward_matches_direct_convolution() {
+        let input = PlaneShape::new(2, 4, 5);
+        for conv in [
+            Conv2d::new(input, 3, 3),
+            Conv2d::new(input, 2, 2).stride(2),
+            Conv2d::new(input, 1, 3).padding(1),
+        ] {
+            let weights = Matrix::from_fn(conv.out_channels, conv.patch_size(), |i, j| {
+                ((i * 7 + j * 3) % 5) as f64 - 2.0
+            });
+            let biases: Vec<f64> = (0..conv.out_channels).map(|o| o as f64 * 0.5).collect();
+            let batch = Matrix::from_fn(2, input.size(), |i, j| ((i + j * 11) % 7) as f64 * 0.25);
+            let mut sums = Matrix::default();
+            conv.forward(&weights, &biases, &batch, &mut sums);
+            assert_eq!(sums.shape(), (2, conv.output_shape().size()));
+            for (sample, sums) in batch.iter_rows().zip(sums.iter_rows()) {
+                assert_eq!(sums, naive_conv(&conv, &weights, &biases, sample));
+            }
+        }
+        let conv = Conv2d::new(PlaneShape::new(4, 3, 3), 8, 2).padding(1);
+        assert_eq!(conv.output_shape(), PlaneShape::new(8, 4, 4));
+        assert!(!Conv2d::new(PlaneShape::new(1, 3, 3), 1, 4).is_valid());
+    }
+}
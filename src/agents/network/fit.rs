@@ -0,0 +1,323 @@
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use super::builder::NetworkError;
+use super::matrix::Matrix;
+use super::nn::{Layer, NeuralNetwork};
+use super::schedule::LearningRateScheduler;
+use crate::callback::TrainingCallback;
+
+/// Settings of `NeuralNetwork::fit`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FitConfig {
+    pub epochs: usize,
+    /// Samples per gradient descent step. The last batch of an epoch can be smaller.
+    pub batch_size: usize,
+    /// Fraction of the samples, taken from the end, that is held out to compute the
+    /// validation loss instead of being trained on.
+    pub validation_split: f64,
+    /// Shuffles the training samples before every epoch.
+    pub shuffle: bool,
+    /// Stops when the monitored loss has not improved for this many epochs. The validation
+    /// loss is monitored if there is a validation split, the training loss otherwise.
+    pub patience: Option<usize>,
+    /// Puts back the weights of the epoch with the lowest monitored loss at the end, together
+    /// with the learning-rate schedule as it was after that epoch.
+    pub restore_best_weights: bool,
+    /// Seeds the shuffling, so that training is reproducible.
+    pub seed: Option<u64>,
+}
+
+impl Default for FitConfig {
+    fn default() -> Self {
+        FitConfig {
+            epochs: 10,
+            batch_size: 32,
+            validation_split: 0.0,
+            shuffle: true,
+            patience: None,
+            restore_best_weights: true,
+            seed: None,
+        }
+    }
+}
+
+/// The losses of one epoch, passed to `TrainingCallback::on_epoch_end`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EpochStats {
+    pub epoch: usize,
+    /// Mean loss of the training batches, before each of their steps.
+    pub train_loss: f64,
+    pub validation_loss: Option<f64>,
+    /// The learning rate of the last step of the epoch.
+    pub learning_rate: f64,
+}
+
+/// The losses of every epoch of `NeuralNetwork::fit`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FitHistory {
+    pub train_loss: Vec<f64>,
+    /// Empty without a validation split.
+    pub validation_loss: Vec<f64>,
+    /// The epoch with the lowest monitored loss.
+    pub best_epoch: usize,
+    pub stopped_early: bool,
+}
+
+impl NeuralNetwork {
+    /// Trains on `inputs` and `targets` for several epochs of mini-batches and reports
+    /// the loss of every batch and every epoch to `callback`.
+    ///
+    /// Every epoch also steps learning-rate schedules with `ScheduleInterval::Epoch`.
+    pub fn fit(
+        &mut self,
+        inputs: &[Vec<f64>],
+        targets: &[Vec<f64>],
+        config: &FitConfig,
+        callback: &mut dyn TrainingCallback,
+    ) -> Result<FitHistory, NetworkError> {
+        self.check_samples(inputs, targets)?;
+        if inputs.is_empty() {
+            return Err(NetworkError::NoSamples);
+        }
+        if config.batch_size == 0 {
+            return Err(NetworkError::InvalidBatchSize);
+        }
+        let validation_size = (inputs.len() as f64 * config.validation_split).round() as usize;
+        if !(0.0..1.0).contains(&config.validation_split) || validation_size >= inputs.len() {
+            return Err(NetworkError::InvalidValidationSplit(
+                config.validation_split,
+            ));
+        }
+        let train_size = inputs.len() - validation_size;
+        let validation = (validation_size > 0).then(|| {
+            (
+                Matrix::from_rows(&inputs[train_size..]),
+                Matrix::from_rows(&targets[train_size..]),
+            )
+        });
+
+        let mut rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+        let mut order: Vec<usize> = (0..train_size).collect();
+        let (mut batch_inputs, mut batch_targets) = (Matrix::default(), Matrix::default());
+        let mut history = FitHistory::default();
+        let mut best: Option<(f64, Vec<Layer>, LearningRateScheduler)> = None;
+        let mut batches = 0;
+        for epoch in 0..config.epochs {
+            if config.shuffle {
+                order.shuffle(&mut rng);
+            }
+            let mut train_loss = 0.0;
+            let mut learning_rate = self.current_learning_rate();
+            for batch in order.chunks(config.batch_size) {
+                gather(inputs, batch, &mut batch_inputs);
+                gather(targets, batch, &mut batch_targets);
+                // Read before the step, which may advance the schedule.
+                learning_rate = self.current_learning_rate();
+                let loss = self.train_matrix(&batch_inputs, &batch_targets);
+                train_loss += loss * batch.len() as f64;
+                batches += 1;
+                callback.on_batch_end(batches, loss);
+            }
+            let stats = EpochStats {
+                epoch,
+                train_loss: train_loss / train_size as f64,
+                validation_loss: validation
+                    .as_ref()
+                    .map(|(inputs, targets)| self.evaluate(inputs, targets)),
+                learning_rate,
+            };
+            self.end_epoch();
+            history.train_loss.push(stats.train_loss);
+            history.validation_loss.extend(stats.validation_loss);
+            callback.on_epoch_end(&stats);
+
+            let monitored = stats.validation_loss.unwrap_or(stats.train_loss);
+            if best.as_ref().is_none_or(|(loss, ..)| monitored < *loss) {
+                history.best_epoch = epoch;
+                best = Some((monitored, self.layers.clone(), self.scheduler.clone()));
+            } else if config
+                .patience
+                .is_some_and(|patience| epoch - history.best_epoch >= patience)
+            {
+                history.stopped_early = true;
+                break;
+            }
+        }
+        if let (true, Some((_, layers, scheduler))) = (config.restore_best_weights, best) {
+            self.layers = layers;
+            self.scheduler = scheduler;
+        }
+        callback.on_train_end();
+        Ok(history)
+    }
+
+    fn check_samples(&self, inputs: &[Vec<f64>], targets: &[Vec<f64>]) -> Result<(), NetworkError> {
+        if inputs.len() != targets.len() {
+            return Err(NetworkError::SampleCount {
+                inputs: inputs.len(),
+                targets: targets.len(),
+            });
+        }
+        if let Some(input) = inputs.iter().find(|x| x.len() != self.input_size()) {
+            return Err(NetworkError::InputSize {
+                expected: self.input_size(),
+                found: input.len(),
+            });
+        }
+        if let Some(target) = targets.iter().find(|y| y.len() != self.output_size()) {
+            return Err(NetworkError::TargetSize {
+                expected: self.output_size(),
+                found: target.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Copies the rows `indices` of `samples` into `out`.
+fn gather(samples: &[Vec<f64>], indices: &[usize], out: &mut Matrix) {
+    out.reset(indices.len(), samples[indices[0]].len());
+    for (row, &i) in out.iter_rows_mut().zip(indices) {
+        row.copy_from_slice(&samples[i]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::network::nn::{ActivationFunction, LossFunction};
+    use crate::agents::network::schedule::{LearningRateSchedule, ScheduleInterval};
+    use crate::callback::{Metrics, Silent};
+
+    fn samples(n: usize) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let inputs: Vec<Vec<f64>> = (0..n)
+            .map(|i| vec![i as f64 / n as f64, 1.0 - 2.0 * i as f64 / n as f64])
+            .collect();
+        let targets = inputs.iter().map(|x| vec![0.5 * x[0] - x[1]]).collect();
+        (inputs, targets)
+    }
+
+    fn network(learning_rate: f64) -> NeuralNetwork {
+        NeuralNetwork::builder(2)
+            .dense(4, ActivationFunction::Tanh)
+            .dense(1, ActivationFunction::Linear)
+            .learning_rate(learning_rate)
+            .loss(LossFunction::MeanSquaredError)
+            .seed(3)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fit_reduces_validation_loss() {
+        let (inputs, targets) = samples(100);
+        let mut nn = network(0.1);
+        let config = FitConfig {
+            epochs: 50,
+            batch_size: 8,
+            validation_split: 0.2,
+            seed: Some(1),
+            ..FitConfig::default()
+        };
+        let mut metrics = Metrics::default();
+        let history = nn.fit(&inputs, &targets, &config, &mut metrics).unwrap();
+        let epochs: Vec<usize> = metrics.epochs.iter().map(|stats| stats.epoch).collect();
+        assert_eq!(epochs, (0..50).collect::<Vec<_>>());
+        // 80 training samples in batches of 8.
+        assert_eq!(metrics.batch_losses.len(), 50 * 10);
+        assert!(metrics.finished);
+        assert_eq!(history.train_loss.len(), 50);
+        assert_eq!(history.validation_loss.len(), 50);
+        assert!(history.validation_loss[49] < 0.1 * history.validation_loss[0]);
+
+        // The same seeds train the same network.
+        let mut again = network(0.1);
+        let repeated = again.fit(&inputs, &targets, &config, &mut Silent).unwrap();
+        assert_eq!(repeated, history);
+    }
+
+    #[test]
+    fn test_early_stopping_restores_best_weights() {
+        let (inputs, targets) = samples(40);
+        // A learning rate this large diverges, so the first epochs are the best.
+        let mut nn = network(5.0);
+        let config = FitConfig {
+            epochs: 100,
+            batch_size: 4,
+            validation_split: 0.25,
+            patience: Some(3),
+            seed: Some(2),
+            ..FitConfig::default()
+        };
+        let history = nn.fit(&inputs, &targets, &config, &mut Silent).unwrap();
+        assert!(history.stopped_early);
+        assert_eq!(history.validation_loss.len(), history.best_epoch + 4);
+        let best = history.validation_loss[history.best_epoch];
+        assert!(history.validation_loss.iter().all(|&loss| loss >= best));
+        let validation = (
+            Matrix::from_rows(&inputs[30..]),
+            Matrix::from_rows(&targets[30..]),
+        );
+        assert_eq!(nn.evaluate(&validation.0, &validation.1), best);
+        // The schedule is back at the end of the best epoch, 30 samples in batches of 4.
+        assert_eq!(nn.scheduler().steps, (history.best_epoch + 1) * 8);
+    }
+
+    #[test]
+    fn test_epochs_report_the_rate_of_their_last_step() {
+        let (inputs, targets) = samples(8);
+        let mut nn = network(0.1);
+        nn.set_schedule(
+            LearningRateSchedule::Exponential { decay: 0.5 },
+            ScheduleInterval::Batch,
+        );
+        let config = FitConfig {
+            epochs: 2,
+            batch_size: 4,
+            ..FitConfig::default()
+        };
+        let mut metrics = Metrics::default();
+        nn.fit(&inputs, &targets, &config, &mut metrics).unwrap();
+        // Two steps per epoch, so the last steps are steps 1 and 3.
+        let rates: Vec<f64> = metrics.epochs.iter().map(|s| s.learning_rate).collect();
+        assert_eq!(rates, [0.1 * 0.5, 0.1 * 0.5f64.powi(3)]);
+    }
+
+    #[test]
+    fn test_fit_rejects_bad_data() {
+        let (inputs, targets) = samples(10);
+        let mut nn = network(0.1);
+        let fit = |nn: &mut NeuralNetwork, inputs: &[Vec<f64>], targets: &[Vec<f64>], config| {
+            nn.fit(inputs, targets, &config, &mut Silent).unwrap_err()
+        };
+        assert_eq!(
+            fit(&mut nn, &inputs, &targets[1..], FitConfig::default()),
+            NetworkError::SampleCount {
+                inputs: 10,
+                targets: 9
+            }
+        );
+        assert_eq!(
+            fit(&mut nn, &targets, &targets, FitConfig::default()),
+            NetworkError::InputSize {
+                expected: 2,
+                found: 1
+            }
+        );
+        let config = FitConfig {
+            validation_split: 1.0,
+            ..FitConfig::default()
+        };
+        assert_eq!(
+            fit(&mut nn, &inputs, &targets, config),
+            NetworkError::InvalidValidationSplit(1.0)
+        );
+        assert_eq!(
+            fit(&mut nn, &[], &[], FitConfig::default()),
+            NetworkError::NoSamples
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Hindsight experience replay (Andrychowicz et al., 2017).
+//!
+//! With sparse rewards an agent rarely reaches its goal, so almost every experience says
+//! that nothing was achieved. At the end of every episode its transitions are therefore also
+//! stored with goals that the episode did reach, as if those had been the goals all along,
+//! which turns failed episodes into successful ones for other goals.
+
+use rand::{seq::IndexedRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+use crate::{
+    agents::network::memory_buffer::{Experience, MemoryBuffer},
+    GoalEnvironment,
+};
+
+/// Which reached goals the transitions of an episode are relabeled with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GoalStrategy {
+    /// The goal reached at the end of the episode.
+    Final,
+    /// Goals reached after the transition, the most common choice.
+    Future,
+    /// Goals reached anywhere in the episode.
+    Episode,
+}
+
+/// Relabels the episodes of a goal-conditioned agent, see the module documentation.
+///
+/// It works on the inputs of a network, in which `achieved` and `goal` are the positions of
+/// what was reached and of the goal, and sits next to the `MemoryBuffer` the original
+/// experiences go to. The relabeled experiences are one-step ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HindsightReplay {
+    pub strategy: GoalStrategy,
+    /// Goals sampled per transition by `Future` and `Episode`. `Final` has only one.
+    pub k: usize,
+    pub achieved: Range<usize>,
+    pub goal: Range<usize>,
+    /// The reward for reaching the goal, which ends the episode.
+    pub success_reward: f32,
+    /// The reward for any other step.
+    pub step_reward: f32,
+    /// The inputs, the action and the next inputs, `None` at the end, of every transition
+    /// of the current episode.
+    #[serde(skip)]
+    episode: Vec<(Vec<f64>, usize, Option<Vec<f64>>)>,
+    /// Whether the current episode ended at its goal.
+    #[serde(skip)]
+    succeeded: bool,
+}
+
+pub const K_DEFAULT: usize = 4;
+
+impl HindsightReplay {
+    pub fn new(
+        strategy: GoalStrategy,
+        k: usize,
+        achieved: Range<usize>,
+        goal: Range<usize>,
+        success_reward: f32,
+        step_reward: f32,
+    ) -> Self {
+        assert_eq!(
+            achieved.len(),
+            goal.len(),
+            "what was reached and the goal must have the same size"
+        );
+        HindsightReplay {
+            strategy,
+            k,
+            achieved,
+            goal,
+            success_reward,
+            step_reward,
+            episode: Vec::new(),
+            succeeded: false,
+        }
+    }
+
+    /// Relabels the states of `env` with `K_DEFAULT` goals per transition.
+    /// The goal dimensions of `env` are the inputs of the `Normalized` encoding of a `DQNAgent`,
+    /// which has one input per state dimension.
+    pub fn for_env<E: GoalEnvironment>(env: &E, strategy: GoalStrategy) -> Self {
+        let (achieved, goal) = env.goal_dims();
+        let (success_reward, step_reward) = env.goal_rewards();
+        Self::new(
+            strategy,
+            K_DEFAULT,
+            achieved,
+            goal,
+            success_reward,
+            step_reward,
+        )
+    }
+
+    /// Records a transition, with `next_state` set to `None` at the end of an episode.
+    /// At the end of an episode, its relabeled experiences are added to `buffer`.
+    pub fn add(
+        &mut self,
+        buffer: &mut MemoryBuffer,
+        state: Vec<f64>,
+        action: usize,
+        reward: f32,
+        next_state: Option<Vec<f64>>,
+    ) {
+        let done = next_state.is_none();
+        self.episode.push((state, action, next_state));
+        if done {
+            self.succeeded = reward == self.success_reward;
+            self.finish(buffer, &mut rand::rng());
+        }
+    }
+
+    /// Adds the relabeled experiences of the recorded episode to `buffer` and forgets it.
+    fn finish(&mut self, buffer: &mut MemoryBuffer, rng: &mut impl Rng) {
+        let episode = std::mem::take(&mut self.episode);
+        // What was reached in every state that was seen, starting with the first one.
+        // The state that ends an episode is only seen if it reached the goal.
+        let mut reached: Vec<&[f64]> = Vec::with_capacity(episode.len() + 1);
+        if let Some((state, _, _)) = episode.first() {
+            reached.push(&state[self.achieved.clone()]);
+        }
+        for (state, _, next_state) in &episode {
+            match next_state {
+                Some(next_state) => reached.push(&next_state[self.achieved.clone()]),
+                None if self.succeeded => reached.push(&state[self.goal.clone()]),
+                None => {}
+            }
+        }
+        let mut goals: Vec<&[f64]> = Vec::with_capacity(self.k);
+        for (t, (state, action, next_state)) in episode.iter().enumerate() {
+            // Only transitions whose next state was seen can be relabeled.
+            let Some(next_state) = next_state else {
+                continue;
+            };
+            goals.clear();
+            match self.strategy {
+                GoalStrategy::Final => goals.extend(reached.last()),
+                GoalStrategy::Future => {
+                    goals.extend((0..self.k).filter_map(|_| reached[t + 1..].choose(rng)))
+                }
+                GoalStrategy::Episode => {
+                    goals.extend((0..self.k).filter_map(|_| reached.choose(rng)))
+                }
+            }
+            for &goal in &goals {
+                buffer.add_experience(self.relabel(state, *action, next_state, goal));
+            }
+        }
+        self.succeeded = false;
+    }
+
+    /// The transition from `state` to `next_state` as if `goal` had been its goal.
+    fn relabel(
+        &self,
+        state: &[f64],
+        action: usize,
+        next_state: &[f64],
+        goal: &[f64],
+    ) -> Experience {
+        let with_goal = |inputs: &[f64]| {
+            let mut inputs = inputs.to_vec();
+            inputs[self.goal.clone()].copy_from_slice(goal);
+            inputs
+        };
+        if &next_state[self.achieved.clone()] == goal {
+            let terminal = vec![0.0; next_state.len()];
+            let reward = self.success_reward;
+            Experience::new(with_goal(state), action, reward, terminal, true)
+        } else {
+            let reward = self.step_reward;
+            Experience::new(
+                with_goal(state),
+                action,
+                reward,
+                with_goal(next_state),
+                false,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::reach_goal::{GoalGridEnvironment, GOAL_REWARD};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Inputs of a one-dimensional grid: the position and the goal.
+    fn inputs(position: f64, goal: f64) -> Vec<f64> {
+        vec![position, goal]
+    }
+
+    /// An episode that walks from 0 to 3 towards the goal 5 and runs out of steps.
+    fn record_failed_episode(replay: &mut HindsightReplay) {
+        let goal = 5.0;
+        for position in 0..3 {
+            let position = position as f64;
+            let next_state = inputs(position + 1.0, goal);
+            replay
+                .episode
+                .push((inputs(position, goal), 1, Some(next_state)));
+        }
+        replay.episode.push((inputs(3.0, goal), 1, None));
+    }
+
+    fn experiences(buffer: &MemoryBuffer) -> Vec<(Vec<f64>, f32, bool)> {
+        buffer
+            .iter()
+            .map(|e| (e.state().to_vec(), e.reward(), e.done()))
+            .collect()
+    }
+
+    #[test]
+    fn test_episodes_are_relabeled_with_reached_goals() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut replay = HindsightReplay::new(GoalStrategy::Final, 4, 0..1, 1..2, 1.0, 0.0);
+        let mut buffer = MemoryBuffer::new(100);
+        record_failed_episode(&mut replay);
+        replay.finish(&mut buffer, &mut rng);
+        // The last position that was seen, 3, becomes the goal, and is reached by the last
+        // transition that was seen. The transition that ran out of steps is not relabeled.
+        assert_eq!(
+            experiences(&buffer),
+            vec![
+                (inputs(0.0, 3.0), 0.0, false),
+                (inputs(1.0, 3.0), 0.0, false),
+                (inputs(2.0, 3.0), 1.0, true),
+            ]
+        );
+        assert!(replay.episode.is_empty());
+
+        for strategy in [GoalStrategy::Future, GoalStrategy::Episode] {
+            let mut replay = HindsightReplay::new(strategy, 4, 0..1, 1..2, 1.0, 0.0);
+            let mut buffer = MemoryBuffer::new(100);
+            for _ in 0..20 {
+                record_failed_episode(&mut replay);
+                replay.finish(&mut buffer, &mut rng);
+            }
+            assert_eq!(buffer.len(), 100);
+            for experience in buffer.iter() {
+                let (position, goal) = (experience.state()[0], experience.state()[1]);
+                assert!((0.0..=3.0).contains(&goal), "{goal}");
+                if strategy == GoalStrategy::Future {
+                    assert!(goal > position, "{position} {goal}");
+                }
+                // Only the step onto the goal succeeds, and ends the episode.
+                let reached = position + 1.0 == goal;
+                assert_eq!(experience.done(), reached);
+                assert_eq!(experience.reward(), if reached { 1.0 } else { 0.0 });
+                if !reached {
+                    assert_eq!(experience.next_state(), &inputs(position + 1.0, goal)[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_successful_episodes_count_their_goal_as_reached() {
+        let env = GoalGridEnvironment::new(3, 3);
+        let mut replay = HindsightReplay::for_env(&env, GoalStrategy::Final);
+        assert_eq!((replay.achieved.clone(), replay.goal.clone()), (0..2, 2..4));
+        let mut buffer = MemoryBuffer::new(10);
+        replay.add(
+            &mut buffer,
+            vec![0.0, 0.0, 0.0, 2.0],
+            3,
+            0.0,
+            Some(vec![0.0, 1.0, 0.0, 2.0]),
+        );
+        assert!(buffer.is_empty());
+        replay.add(&mut buffer, vec![0.0, 1.0, 0.0, 2.0], 3, GOAL_REWARD, None);
+        // The first step is relabeled with the goal of the episode, which the last one reached.
+        assert_eq!(
+            experiences(&buffer),
+            vec![(vec![0.0, 0.0, 0.0, 2.0], 0.0, false)]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::matrix::{axpy, dot, Matrix};
+
+/// How the weights of a layer are drawn when it is created.
+///
+/// `fan_in` is the number of inputs of the layer and `fan_out` the number of
+/// neurons. The variance-scaling schemes keep the variance of the activations
+/// roughly constant from layer to layer, so that deep networks neither saturate
+/// nor die at the start of training.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Initializer {
+    /// Uniform in `[low, high)`. `Layer::new` uses uniform(-1, 1).
+    Uniform { low: f64, high: f64 },
+    /// Glorot uniform, `U(−l, l)` with `l = √(6 / (fan_in + fan_out))`. Suited to Sigmoid and Tanh.
+    XavierUniform,
+    /// Glorot normal, `N(0, σ²)` with `σ = √(2 / (fan_in + fan_out))`.
+    XavierNormal,
+    /// He/Kaiming uniform, `U(−l, l)` with `l = √(6 / fan_in)`. Suited to ReLU.
+    HeUniform,
+    /// He/Kaiming normal, `N(0, σ²)` with `σ = √(2 / fan_in)`.
+    HeNormal,
+    /// All weights zero. Only useful for output layers, hidden neurons would stay identical.
+    Zeros,
+    /// All weights set to the value.
+    Constant(f64),
+    /// A random matrix with orthonormal rows (or columns, if it has more rows
+    /// than columns), scaled by `gain`.
+    Orthogonal { gain: f64 },
+}
+
+impl Default for Initializer {
+    fn default() -> Self {
+        Initializer::Uniform {
+            low: -1.0,
+            high: 1.0,
+        }
+    }
+}
+
+impl Initializer {
+    /// Draws the `fan_out x fan_in` weight matrix of a layer.
+    pub fn weights(&self, fan_in: usize, fan_out: usize, rng: &mut impl Rng) -> Matrix {
+        let fan_avg = (fan_in + fan_out).max(1) as f64;
+        let fan_in_f = fan_in.max(1) as f64;
+        match *self {
+            Initializer::Uniform { low, high } => {
+                Matrix::from_fn(fan_out, fan_in, |_, _| uniform(rng, low, high))
+            }
+            Initializer::XavierUniform => {
+                symmetric_uniform(fan_in, fan_out, (6.0 / fan_avg).sqrt(), rng)
+            }
+            Initializer::XavierNormal => normal(fan_in, fan_out, (2.0 / fan_avg).sqrt(), rng),
+            Initializer::HeUniform => {
+                symmetric_uniform(fan_in, fan_out, (6.0 / fan_in_f).sqrt(), rng)
+            }
+            Initializer::HeNormal => normal(fan_in, fan_out, (2.0 / fan_in_f).sqrt(), rng),
+            Initializer::Zeros => Matrix::zeros(fan_out, fan_in),
+            Initializer::Constant(value) => Matrix::from_fn(fan_out, fan_in, |_, _| value),
+            Initializer::Orthogonal { gain } => orthogonal(fan_out, fan_in, gain, rng),
+        }
+    }
+}
+
+fn uniform(rng: &mut impl Rng, low: f64, high: f64) -> f64 {
+    if low < high {
+        rng.random_range(low..high)
+    } else {
+        low
+    }
+}
+
+fn symmetric_uniform(fan_in: usize, fan_out: usize, limit: f64, rng: &mut impl Rng) -> Matrix {
+    Matrix::from_fn(fan_out, fan_in, |_, _| uniform(rng, -limit, limit))
+}
+
+fn normal(fan_in: usize, fan_out: usize, std_dev: f64, rng: &mut impl Rng) -> Matrix {
+    Matrix::from_fn(fan_out, fan_in, |_, _| std_dev * standard_normal(rng))
+}
+
+/// A sample of N(0, 1) with the Box-Muller transform.
+fn standard_normal(rng: &mut impl Rng) -> f64 {
+    // 1 − U lies in (0, 1], so the logarithm is finite.
+    let u1 = 1.0 - rng.random::<f64>();
+    let u2 = rng.random::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+}
+
+/// Orthonormalizes Gaussian vectors with modified Gram-Schmidt. The shorter side
+/// of the matrix decides how many vectors there are, the longer one their length.
+fn orthogonal(rows: usize, cols: usize, gain: f64, rng: &mut impl Rng) -> Matrix {
+    let (count, len) = (rows.min(cols), rows.max(cols));
+    let mut basis = Matrix::zeros(count, len);
+    for i in 0..count {
+        loop {
+            let mut v: Vec<f64> = (0..len).map(|_| standard_normal(rng)).collect();
+            for j in 0..i {
+                let projection = dot(&v, &basis[j]);
+                axpy(&mut v, -projection, &basis[j]);
+            }
+            let norm = dot(&v, &v).sqrt();
+            // A draw that is (almost) in the span of the previous vectors is redrawn.
+            if norm > 1e-6 {
+                for (b, x) in basis[i].iter_mut().zip(&v) {
+                    *b = x / norm;
+                }
+                break;
+            }
+        }
+    }
+    basis.map_inplace(|x| gain * x);
+    if rows <= cols {
+        basis
+    } else {
+        Matrix::from_fn(rows, cols, |i, j| basis[j][i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_variance_scaling() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let (fan_in, fan_out) = (200, 100);
+
+        let xavier = Initializer::XavierUniform.weights(fan_in, fan_out, &mut rng);
+        assert_eq!(xavier.shape(), (fan_out, fan_in));
+        let limit = (6.0 / 300.0f64).sqrt();
+        assert!(xavier.as_slice().iter().all(|w| w.abs() <= limit));
+
+        let he = Initializer::HeNormal.weights(fan_in, fan_out, &mut rng);
+        let n = he.as_slice().len() as f64;
+        let mean = he.as_slice().iter().sum::<f64>() / n;
+        let variance = he
+            .as_slice()
+            .iter()
+            .map(|w| (w - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        assert!(mean.abs() < 0.01);
+        assert!((variance - 2.0 / fan_in as f64).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_orthogonal_and_seeded() {
+        for (fan_in, fan_out) in [(8, 3), (3, 8), (5, 5)] {
+            let w = Initializer::Orthogonal { gain: 2.0 }.weights(
+                fan_in,
+                fan_out,
+                &mut StdRng::seed_from_u64(1),
+            );
+            // The shorter side is an orthogonal set of vectors with norm `gain`.
+            let vectors = if fan_out <= fan_in {
+                w.clone()
+            } else {
+                Matrix::from_fn(fan_in, fan_out, |i, j| w[j][i])
+            };
+            for i in 0..vectors.rows() {
+                for j in 0..vectors.rows() {
+                    let expected = if i == j { 4.0 } else { 0.0 };
+                    assert!((dot(&vectors[i], &vectors[j]) - expected).abs() < 1e-9);
+                }
+            }
+        }
+
+        // The same seed gives the same weights.
+        let draw = |seed| Initializer::XavierNormal.weights(4, 3, &mut StdRng::seed_from_u64(seed));
+        assert_eq!(draw(3), draw(3));
+        assert_ne!(draw(3), draw(4));
+    }
+}
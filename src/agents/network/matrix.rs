@@ -0,0 +1,384 @@
+use serde::{Deserialize, Serialize};
+use std::ops::{Index, IndexMut, Range};
+
+/// Edge length of the tiles the matrix products work on. A tile of 64 rows of
+/// a few hundred `f64` stays in L2 while it is reused.
+const BLOCK: usize = 64;
+
+/// A dense row-major matrix of `f64`, stored in one contiguous buffer.
+///
+/// `matrix[i]` is the `i`-th row as a slice, so `matrix[i][j]` reads like the
+/// nested vectors it replaces. A batch of samples is stored one sample per row.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "MatrixRepr")]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+/// The serialized forms of a [`Matrix`]. Models saved before the matrix type
+/// existed store their weights as one vector per row.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MatrixRepr {
+    RowMajor {
+        rows: usize,
+        cols: usize,
+        data: Vec<f64>,
+    },
+    Nested(Vec<Vec<f64>>),
+}
+
+impl TryFrom<MatrixRepr> for Matrix {
+    type Error = String;
+
+    fn try_from(repr: MatrixRepr) -> Result<Self, Self::Error> {
+        match repr {
+            MatrixRepr::RowMajor { rows, cols, data } => {
+                if data.len() != rows * cols {
+                    return Err(format!(
+                        "a {rows}x{cols} matrix needs {} values, found {}",
+                        rows * cols,
+                        data.len()
+                    ));
+                }
+                Ok(Matrix { rows, cols, data })
+            }
+            MatrixRepr::Nested(rows) => {
+                let cols = rows.first().map_or(0, Vec::len);
+                if rows.iter().any(|row| row.len() != cols) {
+                    return Err("matrix rows differ in length".to_string());
+                }
+                Ok(Matrix::from_rows(&rows))
+            }
+        }
+    }
+}
+
+impl Matrix {
+    pub fn zeros(rows: usize, cols: usize) -> Self {
+        Matrix {
+            rows,
+            cols,
+            data: vec![0.0; rows * cols],
+        }
+    }
+
+    /// Wraps a row-major buffer. Panics if its length is not `rows * cols`.
+    pub fn from_vec(rows: usize, cols: usize, data: Vec<f64>) -> Self {
+        assert_eq!(data.len(), rows * cols, "matrix data has the wrong length");
+        Matrix { rows, cols, data }
+    }
+
+    /// Builds a matrix with element `(i, j)` set to `f(i, j)`.
+    pub fn from_fn(rows: usize, cols: usize, mut f: impl FnMut(usize, usize) -> f64) -> Self {
+        let mut data = Vec::with_capacity(rows * cols);
+        for i in 0..rows {
+            for j in 0..cols {
+                data.push(f(i, j));
+            }
+        }
+        Matrix { rows, cols, data }
+    }
+
+    /// Stacks equally long rows into a matrix.
+    pub fn from_rows(rows: &[Vec<f64>]) -> Self {
+        let cols = rows.first().map_or(0, Vec::len);
+        let mut data = Vec::with_capacity(rows.len() * cols);
+        for row in rows {
+            assert_eq!(row.len(), cols, "matrix rows differ in length");
+            data.extend_from_slice(row);
+        }
+        Matrix {
+            rows: rows.len(),
+            cols,
+            data,
+        }
+    }
+
+    /// A matrix with a single row.
+    pub fn row_vector(data: Vec<f64>) -> Self {
+        Matrix {
+            rows: 1,
+            cols: data.len(),
+            data,
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn as_slice(&self) -> &[f64] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [f64] {
+        &mut self.data
+    }
+
+    pub fn into_vec(self) -> Vec<f64> {
+        self.data
+    }
+
+    pub fn iter_rows(&self) -> impl Iterator<Item = &[f64]> {
+        // `max(1)` only matters for matrices without columns, which have no data.
+        self.data.chunks_exact(self.cols.max(1))
+    }
+
+    pub fn iter_rows_mut(&mut self) -> impl Iterator<Item = &mut [f64]> {
+        self.data.chunks_exact_mut(self.cols.max(1))
+    }
+
+    pub fn to_rows(&self) -> Vec<Vec<f64>> {
+        self.iter_rows().map(<[f64]>::to_vec).collect()
+    }
+
+    /// Changes the shape to `rows x cols` and sets every element to zero.
+    /// The buffer is reused, so this does not allocate once it is large enough.
+    pub fn reset(&mut self, rows: usize, cols: usize) {
+        self.rows = rows;
+        self.cols = cols;
+        self.data.clear();
+        self.data.resize(rows * cols, 0.0);
+    }
+
+    /// Copies `other` into `self`, reusing the buffer of `self`.
+    pub fn copy_from(&mut self, other: &Matrix) {
+        self.rows = other.rows;
+        self.cols = other.cols;
+        self.data.clear();
+        self.data.extend_from_slice(&other.data);
+    }
+
+    /// Makes `self` a single row holding `row`, reusing the buffer of `self`.
+    pub fn copy_from_row(&mut self, row: &[f64]) {
+        self.rows = 1;
+        self.cols = row.len();
+        self.data.clear();
+        self.data.extend_from_slice(row);
+    }
+
+    /// Copies the rows `rows` of `other` into `self`, reusing the buffer of `self`.
+    pub fn copy_rows_from(&mut self, other: &Matrix, rows: Range<usize>) {
+        self.rows = rows.len();
+        self.cols = other.cols;
+        self.data.clear();
+        self.data
+            .extend_from_slice(&other.data[rows.start * other.cols..rows.end * other.cols]);
+    }
+
+    pub fn map_inplace(&mut self, f: impl Fn(f64) -> f64) {
+        for x in &mut self.data {
+            *x = f(*x);
+        }
+    }
+
+    /// `self ← self + alpha · other`
+    pub fn add_scaled(&mut self, alpha: f64, other: &Matrix) {
+        assert_eq!(self.shape(), other.shape(), "matrix shapes differ");
+        axpy(&mut self.data, alpha, &other.data);
+    }
+
+    /// Adds `row` to every row, e.g. the biases of a layer to a batch.
+    pub fn add_row(&mut self, row: &[f64]) {
+        assert_eq!(row.len(), self.cols, "row has the wrong length");
+        for self_row in self.iter_rows_mut() {
+            axpy(self_row, 1.0, row);
+        }
+    }
+
+    /// Writes the sum of every column into `sums`.
+    pub fn column_sums(&self, sums: &mut Vec<f64>) {
+        sums.clear();
+        sums.resize(self.cols, 0.0);
+        for row in self.iter_rows() {
+            axpy(sums, 1.0, row);
+        }
+    }
+}
+
+impl Index<usize> for Matrix {
+    type Output = [f64];
+
+    fn index(&self, row: usize) -> &[f64] {
+        assert!(row < self.rows, "row {row} out of bounds");
+        &self.data[row * self.cols..(row + 1) * self.cols]
+    }
+}
+
+impl IndexMut<usize> for Matrix {
+    fn index_mut(&mut self, row: usize) -> &mut [f64] {
+        assert!(row < self.rows, "row {row} out of bounds");
+        &mut self.data[row * self.cols..(row + 1) * self.cols]
+    }
+}
+
+/// `y ← y + a · x`
+#[inline]
+pub(crate) fn axpy(y: &mut [f64], a: f64, x: &[f64]) {
+    for (y, x) in y.iter_mut().zip(x) {
+        *y += a * x;
+    }
+}
+
+/// Dot product with four independent accumulators, so that the additions do
+/// not form one long dependency chain and the loop can be vectorized.
+#[inline]
+pub(crate) fn dot(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    let mut acc = [0.0; 4];
+    let mut a_chunks = a.chunks_exact(4);
+    let mut b_chunks = b.chunks_exact(4);
+    for (x, y) in a_chunks.by_ref().zip(b_chunks.by_ref()) {
+        for ((acc, x), y) in acc.iter_mut().zip(x).zip(y) {
+            *acc += x * y;
+        }
+    }
+    let tail: f64 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    (acc[0] + acc[1]) + (acc[2] + acc[3]) + tail
+}
+
+/// `out ← a · b` for `a: m x k` and `b: k x n`.
+///
+/// Loops in i-k-j order, so the innermost loop scales a row of `b` into a row
+/// of `out`, both contiguous. Tiling `k` and `j` keeps the rows of `b` in cache
+/// while every row of `a` passes over them.
+pub fn matmul(a: &Matrix, b: &Matrix, out: &mut Matrix) {
+    assert_eq!(a.cols, b.rows, "matmul: inner dimensions differ");
+    let (m, k, n) = (a.rows, a.cols, b.cols);
+    out.reset(m, n);
+    for kk in (0..k).step_by(BLOCK) {
+        let k_end = (kk + BLOCK).min(k);
+        for jj in (0..n).step_by(BLOCK) {
+            let j_end = (jj + BLOCK).min(n);
+            for i in 0..m {
+                let out_row = &mut out.data[i * n + jj..i * n + j_end];
+                for p in kk..k_end {
+                    axpy(
+                        out_row,
+                        a.data[i * k + p],
+                        &b.data[p * n + jj..p * n + j_end],
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// `out ← a · bᵀ` for `a: m x k` and `b: n x k`.
+///
+/// Every element is a dot product of two contiguous rows. This is the forward
+/// pass of a layer, with a batch in `a` and the weights in `b`.
+pub fn matmul_transpose_b(a: &Matrix, b: &Matrix, out: &mut Matrix) {
+    assert_eq!(
+        a.cols, b.cols,
+        "matmul_transpose_b: inner dimensions differ"
+    );
+    let (m, n) = (a.rows, b.rows);
+    out.reset(m, n);
+    for jj in (0..n).step_by(BLOCK) {
+        let j_end = (jj + BLOCK).min(n);
+        for i in 0..m {
+            let a_row = &a[i];
+            for j in jj..j_end {
+                out.data[i * n + j] = dot(a_row, &b[j]);
+            }
+        }
+    }
+}
+
+/// `out ← aᵀ · b` for `a: k x m` and `b: k x n`.
+///
+/// Sums the outer products of the rows of `a` and `b`. This is the weight
+/// gradient of a layer, with the deltas in `a` and the layer inputs in `b`.
+/// Tiling the rows of `out` keeps them in cache while `k` is summed over.
+pub fn matmul_transpose_a(a: &Matrix, b: &Matrix, out: &mut Matrix) {
+    assert_eq!(
+        a.rows, b.rows,
+        "matmul_transpose_a: inner dimensions differ"
+    );
+    let (k, m, n) = (a.rows, a.cols, b.cols);
+    out.reset(m, n);
+    if n == 0 {
+        return;
+    }
+    for ii in (0..m).step_by(BLOCK) {
+        let i_end = (ii + BLOCK).min(m);
+        for p in 0..k {
+            let b_row = &b[p];
+            let out_rows = out.data[ii * n..i_end * n].chunks_exact_mut(n);
+            for (out_row, &a_pi) in out_rows.zip(&a[p][ii..i_end]) {
+                axpy(out_row, a_pi, b_row);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The textbook triple loop, to check the blocked kernels against.
+    fn naive(a: &Matrix, b: &Matrix) -> Matrix {
+        Matrix::from_fn(a.rows(), b.cols(), |i, j| {
+            (0..a.cols()).map(|p| a[i][p] * b[p][j]).sum()
+        })
+    }
+
+    fn transpose(a: &Matrix) -> Matrix {
+        Matrix::from_fn(a.cols(), a.rows(), |i, j| a[j][i])
+    }
+
+    fn assert_close(a: &Matrix, b: &Matrix) {
+        assert_eq!(a.shape(), b.shape());
+        for (x, y) in a.as_slice().iter().zip(b.as_slice()) {
+            assert!((x - y).abs() < 1e-9, "{x} != {y}");
+        }
+    }
+
+    #[test]
+    fn test_kernels_match_naive_product() {
+        // Sizes that are not multiples of the block size or of the dot product lanes.
+        let a = Matrix::from_fn(70, 131, |i, j| ((i * 7 + j * 3) % 11) as f64 - 5.0);
+        let b = Matrix::from_fn(131, 67, |i, j| ((i * 5 + j) % 13) as f64 * 0.25);
+        let expected = naive(&a, &b);
+
+        let mut out = Matrix::default();
+        matmul(&a, &b, &mut out);
+        assert_close(&out, &expected);
+        matmul_transpose_b(&a, &transpose(&b), &mut out);
+        assert_close(&out, &expected);
+        matmul_transpose_a(&transpose(&a), &b, &mut out);
+        assert_close(&out, &expected);
+    }
+
+    #[test]
+    fn test_loads_nested_and_row_major_json() {
+        let nested: Matrix = serde_json::from_str("[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]").unwrap();
+        assert_eq!(nested.shape(), (2, 3));
+        assert_eq!(nested[1][0], 4.0);
+
+        let json = serde_json::to_string(&nested).unwrap();
+        let row_major: Matrix = serde_json::from_str(&json).unwrap();
+        assert_eq!(row_major, nested);
+
+        assert!(serde_json::from_str::<Matrix>("[[1.0, 2.0], [3.0]]").is_err());
+        assert!(serde_json::from_str::<Matrix>(r#"{"rows":2,"cols":2,"data":[1.0]}"#).is_err());
+    }
+}
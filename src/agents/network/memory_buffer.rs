@@ -1,14 +1,26 @@
-use rand::seq::IndexedRandom;
+use rand::{seq::IndexedRandom, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+};
+
+use crate::agents::{
+    binary_format::{invalid_data, Reader, Writer, REPLAY_MAGIC},
+    n_step::NStepWindow,
+    network::{matrix::Matrix, prioritized_replay::PrioritizedReplay},
+};
+
+/// The version of the replay buffer files written by `MemoryBuffer::write`.
+const REPLAY_VERSION: u16 = 1;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Experience {
-    state: Vec<f64>,
-    action: usize,
-    reward: f32,
-    next_state: Vec<f64>,
-    done: bool,
+    pub(crate) state: Vec<f64>,
+    pub(crate) action: usize,
+    pub(crate) reward: f32,
+    pub(crate) next_state: Vec<f64>,
+    pub(crate) done: bool,
 }
 
 impl Experience {
@@ -27,12 +39,68 @@ impl Experience {
             done,
         }
     }
+
+    pub fn state(&self) -> &[f64] {
+        &self.state
+    }
+
+    pub fn action(&self) -> usize {
+        self.action
+    }
+
+    pub fn reward(&self) -> f32 {
+        self.reward
+    }
+
+    /// The state the n-step return bootstraps from, zeros if `done`.
+    pub fn next_state(&self) -> &[f64] {
+        &self.next_state
+    }
+
+    /// Whether the episode ended within the steps of this experience.
+    pub fn done(&self) -> bool {
+        self.done
+    }
+}
+
+/// A batch sampled by `MemoryBuffer::sample_batch`, with one row of `states` and
+/// `next_states` and one entry of every other field per experience.
+///
+/// A batch is meant to be reused: sampling into it does not allocate once its buffers
+/// have grown to the batch size.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub states: Matrix,
+    pub actions: Vec<usize>,
+    pub rewards: Vec<f32>,
+    pub next_states: Matrix,
+    pub dones: Vec<bool>,
+    /// Positions of the experiences in the buffer, for `MemoryBuffer::update_priorities`.
+    pub indices: Vec<usize>,
+    /// The importance-sampling weights with prioritized replay. Empty with uniform
+    /// sampling, where every experience weighs the same.
+    pub weights: Vec<f64>,
+}
+
+impl Batch {
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryBuffer {
+    /// The experiences are not part of the JSON, see `write` for the compact format.
+    #[serde(skip)]
     pub buffer: VecDeque<Experience>,
     pub capacity: usize,
+    /// Samples experiences by their TD errors instead of uniformly, see `sample_batch`.
+    #[serde(default)]
+    pub prioritized: Option<PrioritizedReplay>,
 }
 
 impl MemoryBuffer {
@@ -40,14 +108,119 @@ impl MemoryBuffer {
         MemoryBuffer {
             buffer: VecDeque::with_capacity(capacity),
             capacity,
+            prioritized: None,
+        }
+    }
+
+    /// A buffer that samples with prioritized replay.
+    pub fn prioritized(capacity: usize, replay: PrioritizedReplay) -> Self {
+        MemoryBuffer {
+            prioritized: Some(replay),
+            ..Self::new(capacity)
         }
     }
 
     pub fn add_experience(&mut self, experience: Experience) {
-        if self.buffer.len() == self.capacity {
+        let evicted = self.buffer.len() == self.capacity;
+        if evicted {
             self.buffer.pop_front();
         }
         self.buffer.push_back(experience);
+        if let (Some(replay), true) = (&mut self.prioritized, self.capacity > 0) {
+            replay.push(self.capacity, self.buffer.len(), evicted);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Removes all experiences, keeping the capacity and the settings of prioritized replay.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        if let Some(replay) = &mut self.prioritized {
+            replay.clear();
+        }
+    }
+
+    /// The stored experiences, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Experience> {
+        self.buffer.iter()
+    }
+
+    /// Samples `batch_size` experiences into `batch`, or all of them if there are fewer:
+    /// by priority with prioritized replay, together with their importance-sampling weights,
+    /// and uniformly without replacement otherwise.
+    pub fn sample_batch(&mut self, batch_size: usize, batch: &mut Batch) {
+        self.sample_batch_with(batch_size, batch, &mut rand::rng());
+    }
+
+    /// Like `sample_batch`, drawing from `rng`.
+    pub fn sample_batch_with(&mut self, batch_size: usize, batch: &mut Batch, rng: &mut impl Rng) {
+        let len = self.buffer.len();
+        batch.indices.clear();
+        batch.weights.clear();
+        match &mut self.prioritized {
+            Some(replay) if self.capacity > 0 && len > 0 => replay.sample(
+                self.capacity,
+                len,
+                batch_size,
+                rng,
+                &mut batch.indices,
+                &mut batch.weights,
+            ),
+            _ => {
+                // Floyd's algorithm, which needs no other memory than the indices.
+                let batch_size = batch_size.min(len);
+                for j in len - batch_size..len {
+                    let i = rng.random_range(0..=j);
+                    let i = if batch.indices.contains(&i) { j } else { i };
+                    batch.indices.push(i);
+                }
+            }
+        }
+        self.fill(batch);
+    }
+
+    /// Copies the experiences at `batch.indices` into the other fields of `batch`.
+    fn fill(&self, batch: &mut Batch) {
+        let Batch {
+            states,
+            actions,
+            rewards,
+            next_states,
+            dones,
+            indices,
+            ..
+        } = batch;
+        let cols = indices.first().map_or(0, |&i| self.buffer[i].state.len());
+        states.reset(indices.len(), cols);
+        next_states.reset(indices.len(), cols);
+        actions.clear();
+        rewards.clear();
+        dones.clear();
+        let rows = states.iter_rows_mut().zip(next_states.iter_rows_mut());
+        for (&i, (state, next_state)) in indices.iter().zip(rows) {
+            let experience = &self.buffer[i];
+            state.copy_from_slice(&experience.state);
+            next_state.copy_from_slice(&experience.next_state);
+            actions.push(experience.action);
+            rewards.push(experience.reward);
+            dones.push(experience.done);
+        }
+    }
+
+    /// Sets the priorities of the experiences at `indices`, as sampled by `sample_batch`,
+    /// from their new TD errors. Must be called before experiences are added, which moves
+    /// them. Does nothing without prioritized replay.
+    pub fn update_priorities(&mut self, indices: &[usize], td_errors: &[f64]) {
+        if let Some(replay) = &mut self.prioritized {
+            replay.update(self.capacity, self.buffer.len(), indices, td_errors);
+        }
     }
 
     pub fn sample(&self, batch_size: usize) -> Vec<&Experience> {
@@ -59,29 +232,163 @@ impl MemoryBuffer {
         sampled_indices.iter().map(|&i| &self.buffer[*i]).collect()
     }
 
-    // pub fn sample_and_unpack(
-    //     &self,
-    //     batch_size: usize,
-    // ) -> (
-    //     Vec<Vec<f64>>,
-    //     Vec<usize>,
-    //     Vec<f32>,
-    //     Vec<Vec<f64>>,
-    //     Vec<bool>,
-    // ) {
-    //     let sample = self.sample(batch_size);
-    //     let (states, actions, rewards, next_states, dones) = sample
-    //         .into_iter()
-    //         .map(|exp| {
-    //             (
-    //                 exp.state.clone(),
-    //                 exp.action,
-    //                 exp.reward,
-    //                 exp.next_state.clone(),
-    //                 exp.done,
-    //             )
-    //         })
-    //         .collect();
-    //     (states, actions, rewards, next_states, dones)
-    // }
+    /// Writes the capacity and the experiences, oldest first, in the little-endian
+    /// format of [`binary_format`](crate::agents::binary_format), after the magic bytes
+    /// `RLRB` and a `u16` version.
+    pub fn write(&self, writer: &mut Writer<impl Write>) -> io::Result<()> {
+        writer.bytes(&REPLAY_MAGIC)?;
+        writer.u16(REPLAY_VERSION)?;
+        writer.usize(self.capacity)?;
+        writer.usize(self.buffer.len())?;
+        for experience in &self.buffer {
+            writer.f64s(&experience.state)?;
+            writer.usize(experience.action)?;
+            writer.f32(experience.reward)?;
+            writer.f64s(&experience.next_state)?;
+            writer.bytes(&[experience.done as u8])?;
+        }
+        Ok(())
+    }
+
+    /// Reads a buffer written by `write`.
+    pub fn read(reader: &mut Reader<impl Read>) -> io::Result<Self> {
+        if reader.bytes()? != REPLAY_MAGIC {
+            return Err(invalid_data("not a replay buffer file"));
+        }
+        let version = reader.u16()?;
+        if version != REPLAY_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported replay buffer version {version}, expected {REPLAY_VERSION}"
+            )));
+        }
+        let capacity = reader.usize()?;
+        let len = reader.usize()?;
+        if len > capacity {
+            return Err(invalid_data(format!(
+                "{len} experiences do not fit into a buffer of capacity {capacity}"
+            )));
+        }
+        // Grows as experiences are read, instead of trusting `capacity` with an allocation.
+        let mut buffer = VecDeque::new();
+        for _ in 0..len {
+            let state = reader.f64s()?;
+            let action = reader.usize()?;
+            let reward = reader.f32()?;
+            let next_state = reader.f64s()?;
+            let done = match reader.bytes()? {
+                [0] => false,
+                [1] => true,
+                [byte] => return Err(invalid_data(format!("{byte} is not a boolean"))),
+            };
+            buffer.push_back(Experience::new(state, action, reward, next_state, done));
+        }
+        Ok(MemoryBuffer {
+            buffer,
+            capacity,
+            prioritized: None,
+        })
+    }
+}
+
+/// Sits in front of [`MemoryBuffer::add_experience`] and turns one-step
+/// transitions into n-step experiences, whose reward is the discounted sum of
+/// the next `n` rewards and whose next state is the state reached after them.
+#[derive(Debug, Clone)]
+pub struct NStepAggregator {
+    window: NStepWindow<(Vec<f64>, usize)>,
+}
+
+impl NStepAggregator {
+    pub fn new(n: usize, gamma: f32) -> Self {
+        NStepAggregator {
+            window: NStepWindow::new(n, gamma),
+        }
+    }
+
+    /// The factor γⁿ the next state value of the produced experiences is scaled by.
+    pub fn bootstrap_discount(&self) -> f32 {
+        self.window.bootstrap_discount()
+    }
+
+    /// Adds a transition, with `next_state` set to `None` at the end of an episode.
+    /// Every experience that is complete is added to `buffer`. At the end of an
+    /// episode that is all of them, marked as done.
+    pub fn add(
+        &mut self,
+        buffer: &mut MemoryBuffer,
+        state: Vec<f64>,
+        action: usize,
+        reward: f32,
+        next_state: Option<Vec<f64>>,
+    ) {
+        match next_state {
+            Some(next_state) => {
+                if let Some(((state, action), ret)) = self.window.push((state, action), reward) {
+                    buffer.add_experience(Experience::new(state, action, ret, next_state, false));
+                }
+            }
+            None => {
+                let terminal = vec![0.0; state.len()];
+                for ((state, action), ret) in self.window.finish((state, action), reward) {
+                    buffer.add_experience(Experience::new(
+                        state,
+                        action,
+                        ret,
+                        terminal.clone(),
+                        true,
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_batches_hold_distinct_experiences_in_contiguous_rows() {
+        let mut buffer = MemoryBuffer::new(8);
+        for i in 0..10 {
+            let state = vec![i as f64, -(i as f64)];
+            let next_state = vec![i as f64 + 1.0, 0.0];
+            buffer.add_experience(Experience::new(state, i, i as f32, next_state, i % 2 == 0));
+        }
+        assert_eq!(buffer.len(), 8);
+        let actions: Vec<usize> = buffer.iter().map(Experience::action).collect();
+        assert_eq!(actions, (2..10).collect::<Vec<_>>());
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut batch = Batch::default();
+        for _ in 0..100 {
+            buffer.sample_batch_with(5, &mut batch, &mut rng);
+            assert_eq!(batch.len(), 5);
+            assert!(batch.weights.is_empty());
+            let mut indices = batch.indices.clone();
+            indices.sort_unstable();
+            indices.dedup();
+            assert_eq!(indices.len(), 5, "{:?}", batch.indices);
+            for (k, &i) in batch.indices.iter().enumerate() {
+                let experience = &buffer.buffer[i];
+                assert_eq!(&batch.states[k], experience.state());
+                assert_eq!(&batch.next_states[k], experience.next_state());
+                assert_eq!(batch.actions[k], experience.action());
+                assert_eq!(batch.rewards[k], experience.reward());
+                assert_eq!(batch.dones[k], experience.done());
+            }
+        }
+
+        // Asking for more experiences than there are samples each of them once.
+        buffer.sample_batch_with(20, &mut batch, &mut rng);
+        assert_eq!(batch.len(), 8);
+        assert_eq!(batch.states.rows(), 8);
+
+        buffer.clear();
+        assert!(buffer.is_empty());
+        buffer.sample_batch_with(5, &mut batch, &mut rng);
+        assert!(batch.is_empty());
+        assert_eq!(buffer.capacity, 8);
+    }
 }
@@ -1,2 +1,11 @@
+pub mod autodiff;
+pub mod builder;
+pub mod conv;
+pub mod fit;
+pub mod hindsight;
+pub mod initializer;
+pub mod matrix;
 pub mod memory_buffer;
 pub mod nn;
+pub mod prioritized_replay;
+pub mod schedule;
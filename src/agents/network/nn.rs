@@ -1,9 +1,17 @@
-use indicatif::{ProgressBar, ProgressStyle};
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+use super::autodiff::{Tape, Var};
+use super::builder::NetworkError;
+use super::conv::{Conv2d, PlaneShape};
+use super::initializer::Initializer;
+use super::matrix::{axpy, dot, matmul_transpose_b, Matrix};
+use super::schedule::{LearningRateSchedule, LearningRateScheduler, ScheduleInterval};
+use crate::callback::TrainingCallback;
 
 /// Enum representing different activation functions used in the neural network.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ActivationFunction {
     /// Rectified Linear Unit (ReLU) activation function.
     /// ReLU is defined as f(x) = max(0, x).
@@ -17,10 +25,30 @@ pub enum ActivationFunction {
     /// Linear activation function.
     /// Linear is defined as f(x) = x.
     Linear,
+    /// Leaky ReLU with slope α for negative inputs.
+    /// Leaky ReLU is defined as f(x) = x if x > 0, else α * x.
+    LeakyReLU(f64),
+    /// Exponential Linear Unit with saturation α.
+    /// ELU is defined as f(x) = x if x > 0, else α * (exp(x) - 1).
+    ELU(f64),
+    /// Gaussian Error Linear Unit, with the tanh approximation
+    /// f(x) = 0.5 * x * (1 + tanh(√(2/π) * (x + 0.044715 * x³))).
+    GELU,
+    /// Softmax over all outputs of a layer, f(x)ᵢ = exp(xᵢ) / Σⱼ exp(xⱼ).
+    /// Turns the outputs into a probability distribution, for classification
+    /// with `LossFunction::CategoricalCrossEntropy`.
+    Softmax,
 }
 
+/// √(2/π), used by the GELU approximation.
+const GELU_SCALE: f64 = 0.797_884_560_802_865_4;
+const GELU_CUBIC: f64 = 0.044715;
+
 impl ActivationFunction {
     /// Applies the activation function to the input value.
+    ///
+    /// Softmax depends on all outputs of a layer, see `apply_slice`. For a
+    /// single value it is always 1.
     pub fn apply(&self, x: f64) -> f64 {
         match self {
             ActivationFunction::ReLU => {
@@ -33,13 +61,56 @@ impl ActivationFunction {
             ActivationFunction::Sigmoid => 1.0 / (1.0 + (-x).exp()),
             ActivationFunction::Tanh => x.tanh(),
             ActivationFunction::Linear => x,
+            ActivationFunction::LeakyReLU(alpha) => {
+                if x > 0.0 {
+                    x
+                } else {
+                    alpha * x
+                }
+            }
+            ActivationFunction::ELU(alpha) => {
+                if x > 0.0 {
+                    x
+                } else {
+                    alpha * x.exp_m1()
+                }
+            }
+            ActivationFunction::GELU => {
+                0.5 * x * (1.0 + (GELU_SCALE * (x + GELU_CUBIC * x.powi(3))).tanh())
+            }
+            ActivationFunction::Softmax => 1.0,
         }
     }
 
-    /// Returns the derivative of the activation function using the activated value.
+    /// Applies the activation function in place to the outputs of one layer for one sample.
+    pub fn apply_slice(&self, values: &mut [f64]) {
+        match self {
+            ActivationFunction::Softmax => {
+                // Subtracting the maximum keeps exp from overflowing, and does not change the result.
+                let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                let mut sum = 0.0;
+                for v in values.iter_mut() {
+                    *v = (*v - max).exp();
+                    sum += *v;
+                }
+                for v in values.iter_mut() {
+                    *v /= sum;
+                }
+            }
+            _ => {
+                for v in values.iter_mut() {
+                    *v = self.apply(*v);
+                }
+            }
+        }
+    }
+
+    /// Returns the derivative of the activation function at `x`, given the
+    /// activated value `activated = f(x)`.
     ///
-    /// For example, for Sigmoid, f'(x) = f(x)*(1 - f(x)).
-    pub fn derivative(&self, activated: f64) -> f64 {
+    /// For example, for Sigmoid, f'(x) = f(x)*(1 - f(x)). For Softmax this is
+    /// the diagonal of its Jacobian, the full Jacobian is applied by `backward_slice`.
+    pub fn derivative(&self, x: f64, activated: f64) -> f64 {
         match self {
             ActivationFunction::ReLU => {
                 if activated > 0.0 {
@@ -51,15 +122,64 @@ impl ActivationFunction {
             ActivationFunction::Sigmoid => activated * (1.0 - activated),
             ActivationFunction::Tanh => 1.0 - activated.powi(2),
             ActivationFunction::Linear => 1.0,
+            ActivationFunction::LeakyReLU(alpha) => {
+                if x > 0.0 {
+                    1.0
+                } else {
+                    *alpha
+                }
+            }
+            // α * exp(x) = f(x) + α for x ≤ 0
+            ActivationFunction::ELU(alpha) => {
+                if x > 0.0 {
+                    1.0
+                } else {
+                    activated + alpha
+                }
+            }
+            ActivationFunction::GELU => {
+                let t = (GELU_SCALE * (x + GELU_CUBIC * x.powi(3))).tanh();
+                0.5 * (1.0 + t)
+                    + 0.5 * x * (1.0 - t * t) * GELU_SCALE * (1.0 + 3.0 * GELU_CUBIC * x * x)
+            }
+            ActivationFunction::Softmax => activated * (1.0 - activated),
+        }
+    }
+
+    /// Turns the gradient of the loss with respect to the outputs of one layer
+    /// for one sample into the gradient with respect to the weighted sums `xs`, in place.
+    ///
+    /// Softmax mixes all outputs, its gradient is `f(x) ⊙ (g − g · f(x))`.
+    pub fn backward_slice(&self, xs: &[f64], activated: &[f64], gradient: &mut [f64]) {
+        match self {
+            ActivationFunction::Softmax => {
+                let g_dot_p = dot(gradient, activated);
+                for (g, p) in gradient.iter_mut().zip(activated) {
+                    *g = p * (*g - g_dot_p);
+                }
+            }
+            _ => {
+                for ((g, &x), &a) in gradient.iter_mut().zip(xs).zip(activated) {
+                    *g *= self.derivative(x, a);
+                }
+            }
         }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum LossFunction {
     /// Mean Squared Error (MSE) loss function.
     MeanSquaredError,
+    /// Mean of −(t * ln(p) + (1 − t) * ln(1 − p)) over the outputs, for
+    /// independent probabilities, e.g. Sigmoid outputs.
     BinaryCrossEntropy,
+    /// −Σ t * ln(p), for a target distribution over classes and Softmax outputs.
+    CategoricalCrossEntropy,
+    /// Huber loss with threshold δ, averaged over the outputs. Quadratic for errors
+    /// up to δ and linear beyond, so large TD errors do not produce huge gradients.
+    /// Huber(1.0) is the smooth L1 loss.
+    Huber(f64),
 }
 
 impl LossFunction {
@@ -81,10 +201,26 @@ impl LossFunction {
                     .zip(target)
                     .map(|(p, t)| {
                         let p = p.clamp(epsilon, 1.0 - epsilon); // Clamp predicted values
-                        if *t == 1.0 {
-                            -p.ln()
+                        -(t * p.ln() + (1.0 - t) * (1.0 - p).ln())
+                    })
+                    .sum::<f64>()
+                    / predicted.len() as f64
+            }
+            LossFunction::CategoricalCrossEntropy => predicted
+                .iter()
+                .zip(target)
+                .map(|(p, t)| -t * p.max(epsilon).ln())
+                .sum(),
+            LossFunction::Huber(delta) => {
+                predicted
+                    .iter()
+                    .zip(target)
+                    .map(|(p, t)| {
+                        let error = (p - t).abs();
+                        if error <= *delta {
+                            0.5 * error * error
                         } else {
-                            -(1.0 - p).ln()
+                            delta * (error - 0.5 * delta)
                         }
                     })
                     .sum::<f64>()
@@ -93,27 +229,67 @@ impl LossFunction {
         }
     }
 
-    /// Calculates the gradient of the loss function with respect to the predicted output.
-    fn gradient(&self, predicted: &[f64], target: &[f64]) -> Vec<f64> {
-        let epsilon = 1e-10; // Small value to prevent division by zero
+    /// Records the mean loss over a batch on `tape`, so that its gradients are found
+    /// by `Tape::backward`. `sums` are the weighted sums of the output layer and
+    /// `output` is their activation.
+    ///
+    /// Softmax with categorical cross-entropy is recorded as −Σ t * log_softmax(z),
+    /// which avoids dividing by small probabilities in the backward pass.
+    ///
+    /// `weights`, of the shape of `output`, scale the loss of every element, e.g. by
+    /// the importance-sampling weight of its sample.
+    pub(crate) fn record(
+        &self,
+        tape: &mut Tape,
+        sums: Var,
+        output: Var,
+        activation: &ActivationFunction,
+        target: Var,
+        weights: Option<Var>,
+    ) -> Var {
+        let epsilon = 1e-10; // Small value to prevent log(0)
+        let weigh = |tape: &mut Tape, loss: Var| match weights {
+            Some(weights) => tape.mul(loss, weights),
+            None => loss,
+        };
         match self {
-            LossFunction::MeanSquaredError => predicted
-                .iter()
-                .zip(target)
-                .map(|(p, t)| 2.0 * (p - t) / predicted.len() as f64)
-                .collect(),
-            LossFunction::BinaryCrossEntropy => predicted
-                .iter()
-                .zip(target)
-                .map(|(p, t)| {
-                    let p = p.clamp(epsilon, 1.0 - epsilon); // Clamp predicted values
-                    if *t == 1.0 {
-                        1.0 / p
-                    } else {
-                        -1.0 / (1.0 - p)
-                    }
-                })
-                .collect(),
+            LossFunction::MeanSquaredError => {
+                let error = tape.sub(output, target);
+                let squared = tape.square(error);
+                let squared = weigh(tape, squared);
+                tape.mean(squared)
+            }
+            LossFunction::BinaryCrossEntropy => {
+                let p = tape.clamp(output, epsilon, 1.0 - epsilon);
+                let not_p = tape.affine(p, -1.0, 1.0);
+                let not_t = tape.affine(target, -1.0, 1.0);
+                let (ln_p, ln_not_p) = (tape.ln(p), tape.ln(not_p));
+                let positive = tape.mul(target, ln_p);
+                let negative = tape.mul(not_t, ln_not_p);
+                let likelihood = tape.add(positive, negative);
+                let likelihood = weigh(tape, likelihood);
+                let mean = tape.mean(likelihood);
+                tape.scale(mean, -1.0)
+            }
+            LossFunction::CategoricalCrossEntropy => {
+                let batch_size = tape.value(output).rows() as f64;
+                let log_p = if *activation == ActivationFunction::Softmax {
+                    tape.log_softmax(sums)
+                } else {
+                    let p = tape.clamp(output, epsilon, f64::INFINITY);
+                    tape.ln(p)
+                };
+                let weighted = tape.mul(target, log_p);
+                let weighted = weigh(tape, weighted);
+                let sum = tape.sum(weighted);
+                tape.scale(sum, -1.0 / batch_size)
+            }
+            LossFunction::Huber(delta) => {
+                let error = tape.sub(output, target);
+                let huber = tape.huber(error, *delta);
+                let huber = weigh(tape, huber);
+                tape.mean(huber)
+            }
         }
     }
 }
@@ -126,6 +302,129 @@ pub struct NeuralNetwork {
     final_activation: ActivationFunction,
     loss_function: LossFunction,
     history: Vec<f64>,
+    #[serde(default)]
+    clipping: GradientClipping,
+    #[serde(default)]
+    pub(super) scheduler: LearningRateScheduler,
+    /// Threads that batches are split across, see `set_threads`.
+    #[serde(skip)]
+    threads: usize,
+    #[serde(skip)]
+    workspace: Workspace,
+}
+
+/// Limits on the gradients, applied before every gradient descent step.
+///
+/// Per-value clipping is applied first, then the global norm is limited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GradientClipping {
+    /// Every partial derivative is clamped to `[−max_value, max_value]`.
+    pub max_value: Option<f64>,
+    /// All gradients are scaled by the same factor, so that the L2 norm over
+    /// the weights and biases of every layer is at most `max_norm`.
+    pub max_norm: Option<f64>,
+}
+
+/// The nodes of a training pass on the tape of the workspace.
+#[derive(Clone, Debug, Default)]
+struct Graph {
+    /// The input batch followed by the activated output of every layer, after dropout.
+    activations: Vec<Var>,
+    /// The weighted sums `z = x · Wᵀ + b` of every layer, before the activation.
+    sums: Vec<Var>,
+    /// The dropout mask of every layer, `0` for dropped units and `1 / (1 − rate)`
+    /// for kept ones. `None` for layers without dropout and outside of training.
+    masks: Vec<Option<Var>>,
+    /// The weights and biases of every layer, `None` for layers without parameters.
+    params: Vec<Option<(Var, Var)>>,
+}
+
+impl Graph {
+    fn clear(&mut self) {
+        self.activations.clear();
+        self.sums.clear();
+        self.masks.clear();
+        self.params.clear();
+    }
+
+    fn output(&self) -> Var {
+        self.activations[self.activations.len() - 1]
+    }
+}
+
+/// Buffers that are reused by every training step, so that training does not allocate.
+#[derive(Clone, Debug, Default)]
+struct Workspace {
+    tape: Tape,
+    graph: Graph,
+    gradients: Gradients,
+    /// Draws the dropout masks, seeded from the thread RNG on first use unless `seed_dropout` was called.
+    rng: Option<StdRng>,
+    /// The buffers of every thread of a batch that is split across threads.
+    workers: Vec<Worker>,
+}
+
+/// The buffers of one thread, for its rows of a batch.
+#[derive(Clone, Debug, Default)]
+struct Worker {
+    tape: Tape,
+    graph: Graph,
+    gradients: Gradients,
+    inputs: Matrix,
+    targets: Matrix,
+}
+
+/// Rows below which a batch is not split across threads, as spawning costs more than it saves.
+const MIN_ROWS_PER_THREAD: usize = 8;
+
+/// Splits `rows` into at most `threads` contiguous ranges of nearly equal size, in order.
+/// The ranges only depend on `rows` and `threads`, so that sums over them are reproducible.
+fn split_rows(rows: usize, threads: usize) -> Vec<Range<usize>> {
+    let parts = threads.min(rows / MIN_ROWS_PER_THREAD).max(1);
+    let size = rows.div_ceil(parts).max(1);
+    (0..rows)
+        .step_by(size)
+        .map(|start| start..(start + size).min(rows))
+        .collect()
+}
+
+/// Waits for a thread of a split batch, and passes its panic on.
+fn join<T>(handle: std::thread::ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+impl Workspace {
+    fn take_rng(&mut self) -> StdRng {
+        self.rng
+            .take()
+            .unwrap_or_else(|| StdRng::from_rng(&mut rand::rng()))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Gradients {
+    /// Gradient of the loss with respect to the weights of every layer.
+    weights: Vec<Matrix>,
+    /// Gradient of the loss with respect to the biases of every layer.
+    biases: Vec<Vec<f64>>,
+}
+
+impl Gradients {
+    /// Applies `f` to the gradient of every weight and bias.
+    fn for_each_mut(&mut self, mut f: impl FnMut(&mut f64)) {
+        let weights = self.weights.iter_mut().map(Matrix::as_mut_slice);
+        let biases = self.biases.iter_mut().map(Vec::as_mut_slice);
+        weights.chain(biases).flatten().for_each(&mut f);
+    }
+
+    /// The L2 norm over the gradients of all weights and biases.
+    fn norm(&self) -> f64 {
+        let weights = self.weights.iter().map(Matrix::as_slice);
+        let biases = self.biases.iter().map(Vec::as_slice);
+        weights.chain(biases).map(|g| dot(g, g)).sum::<f64>().sqrt()
+    }
 }
 
 impl NeuralNetwork {
@@ -147,164 +446,891 @@ impl NeuralNetwork {
             final_activation,
             loss_function,
             history: Vec::new(),
+            clipping: GradientClipping::default(),
+            scheduler: LearningRateScheduler::default(),
+            threads: 1,
+            workspace: Workspace::default(),
+        }
+    }
+
+    /// The base learning rate, before the schedule is applied.
+    pub fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    pub fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    /// The learning rate of the next gradient descent step.
+    pub fn current_learning_rate(&self) -> f64 {
+        self.scheduler.rate(self.learning_rate)
+    }
+
+    /// Changes the learning rate over the course of training, starting from the first step.
+    pub fn set_schedule(&mut self, schedule: LearningRateSchedule, interval: ScheduleInterval) {
+        self.scheduler = LearningRateScheduler::new(schedule, interval);
+    }
+
+    pub fn scheduler(&self) -> &LearningRateScheduler {
+        &self.scheduler
+    }
+
+    /// Marks the end of a training epoch, which steps schedules with `ScheduleInterval::Epoch`.
+    pub fn end_epoch(&mut self) {
+        self.scheduler.step(ScheduleInterval::Epoch);
+    }
+
+    pub fn set_clipping(&mut self, clipping: GradientClipping) {
+        self.clipping = clipping;
+    }
+
+    pub fn clipping(&self) -> GradientClipping {
+        self.clipping
+    }
+
+    /// Sets the L2 weight decay λ of every layer, which adds `λ/2 · ‖W‖²` to the loss.
+    pub fn set_weight_decay(&mut self, weight_decay: f64) {
+        for layer in &mut self.layers {
+            layer.l2 = weight_decay;
         }
     }
 
+    /// Splits batches across up to `threads` threads in `predict_matrix`, `predict_batch`
+    /// and batch training. 0 and 1 both run on the calling thread.
+    ///
+    /// Predictions do not depend on the number of threads. Training sums the gradients of
+    /// the threads in a fixed order, so it is reproducible for the same number of threads,
+    /// but can differ from other numbers of threads in rounding and in the dropout masks.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads.max(1)
+    }
+
+    /// Seeds the RNG that draws the dropout masks during training.
+    pub fn seed_dropout(&mut self, seed: u64) {
+        self.workspace.rng = Some(StdRng::seed_from_u64(seed));
+    }
+
     pub fn add_layers(&mut self, layer_sizes: &[usize]) {
+        self.add_layers_with(layer_sizes, Initializer::default(), &mut rand::rng());
+    }
+
+    /// Like `add_layers`, with the weights of every layer drawn by `initializer` from `rng`.
+    /// A seeded `rng` makes the initial weights reproducible.
+    pub fn add_layers_with(
+        &mut self,
+        layer_sizes: &[usize],
+        initializer: Initializer,
+        rng: &mut impl Rng,
+    ) {
         for i in 0..layer_sizes.len() - 1 {
-            self.layers
-                .push(Layer::new(layer_sizes[i], layer_sizes[i + 1]));
+            self.layers.push(Layer::with_initializer(
+                layer_sizes[i],
+                layer_sizes[i + 1],
+                initializer,
+                rng,
+            ));
+        }
+    }
+
+    /// Appends a layer, which has to take the outputs of the current last layer as its inputs.
+    pub fn push_layer(&mut self, layer: Layer) -> Result<(), NetworkError> {
+        let index = self.layers.len();
+        if let Some(previous) = self.layers.last() {
+            if layer.input_size() != previous.output_size() {
+                return Err(NetworkError::ShapeMismatch {
+                    layer: index,
+                    expected: previous.output_size(),
+                    found: layer.input_size(),
+                });
+            }
         }
+        layer.validate(index)?;
+        self.layers.push(layer);
+        Ok(())
     }
 
-    /// Performs a forward pass through the network.
-    /// Each layer computes its output which is fed as input to the next layer.
-    /// Returns the activated outputs of the final layer.
-    fn forward_inner(&self, mut input: Vec<f64>, with_cache: bool) -> (Vec<Vec<f64>>, Vec<f64>) {
-        let mut cache: Vec<Vec<f64>> = Vec::new();
-        if with_cache {
-            cache.push(input.clone());
+    /// Checks that every layer is consistent and takes the outputs of the previous layer as its inputs.
+    /// A network loaded from a file should be validated before it is used.
+    pub fn validate(&self) -> Result<(), NetworkError> {
+        if self.layers.is_empty() {
+            return Err(NetworkError::NoLayers);
         }
         for (i, layer) in self.layers.iter().enumerate() {
-            input = if i < self.layers.len() - 1 {
-                // Hidden layers use the standard activation function.
-                layer.forward(&input, &self.activation_function)
-            } else {
-                // Final layer uses the final activation function.
-                layer.forward(&input, &self.final_activation)
-            };
-            if with_cache {
-                cache.push(input.clone());
+            layer.validate(i)?;
+            if i > 0 && layer.input_size() != self.layers[i - 1].output_size() {
+                return Err(NetworkError::ShapeMismatch {
+                    layer: i,
+                    expected: self.layers[i - 1].output_size(),
+                    found: layer.input_size(),
+                });
             }
         }
-        (cache, input)
+        Ok(())
     }
 
-    fn forward(&self, input: Vec<f64>) -> Vec<Vec<f64>> {
-        // Perform the forward pass and return the cache of activations.
-        self.forward_inner(input, true).0
+    /// Number of inputs of the first layer, 0 for a network without layers.
+    pub fn input_size(&self) -> usize {
+        self.layers.first().map_or(0, Layer::input_size)
     }
 
+    /// Number of outputs of the last layer, 0 for a network without layers.
+    pub fn output_size(&self) -> usize {
+        self.layers.last().map_or(0, Layer::output_size)
+    }
+
+    /// The activation of layer `i`. Layers without their own activation fall back
+    /// to the standard activation function if hidden, and to the final activation if last.
+    fn activation(&self, layer: usize) -> &ActivationFunction {
+        match &self.layers[layer].activation {
+            Some(activation) => activation,
+            None if layer + 1 < self.layers.len() => &self.activation_function,
+            None => &self.final_activation,
+        }
+    }
+
+    /// Records a forward pass for a batch with one sample per row on `tape`, and
+    /// the nodes of every layer in `graph`.
+    ///
+    /// With an RNG this is a training pass, and the outputs of hidden layers with dropout
+    /// are masked. Without one, dropout is off, like in `predict`.
+    fn record(
+        &self,
+        tape: &mut Tape,
+        graph: &mut Graph,
+        inputs: &Matrix,
+        mut dropout: Option<&mut StdRng>,
+    ) {
+        tape.clear();
+        graph.clear();
+        let mut current = tape.constant(inputs);
+        graph.activations.push(current);
+        let last = self.layers.len() - 1;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let (sums, params) = layer.record(tape, current);
+            current = tape.activation(sums, self.activation(i));
+            let mask = match dropout.as_deref_mut() {
+                Some(rng) if layer.dropout > 0.0 && i < last => {
+                    // Inverted dropout, kept units are scaled up so that no scaling is needed in `predict`.
+                    let scale = 1.0 / (1.0 - layer.dropout);
+                    let (rows, cols) = tape.value(current).shape();
+                    let mask = tape.constant_from_fn(rows, cols, |_, _| {
+                        if rng.random::<f64>() < layer.dropout {
+                            0.0
+                        } else {
+                            scale
+                        }
+                    });
+                    current = tape.mul(current, mask);
+                    Some(mask)
+                }
+                _ => None,
+            };
+            graph.sums.push(sums);
+            graph.masks.push(mask);
+            graph.params.push(params);
+            graph.activations.push(current);
+        }
+    }
+
+    /// Performs a forward pass through the network for a batch with one sample per row.
+    /// Returns the input followed by the activated output of every layer.
+    #[cfg(test)]
+    fn forward(&self, input: &Matrix) -> Vec<Matrix> {
+        let (mut tape, mut graph) = (Tape::new(), Graph::default());
+        self.record(&mut tape, &mut graph, input, None);
+        graph
+            .activations
+            .iter()
+            .map(|&a| tape.value(a).clone())
+            .collect()
+    }
+
+    /// Panics if the input does not have `input_size()` values, see `try_predict`.
     pub fn predict(&self, input: Vec<f64>) -> Vec<f64> {
-        self.forward_inner(input, false).1
+        self.try_predict(input).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_predict(&self, input: Vec<f64>) -> Result<Vec<f64>, NetworkError> {
+        if input.len() != self.input_size() {
+            return Err(NetworkError::InputSize {
+                expected: self.input_size(),
+                found: input.len(),
+            });
+        }
+        let mut input = input;
+        let mut output = Vec::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            layer.forward_into(&input, self.activation(i), &mut output);
+            std::mem::swap(&mut input, &mut output);
+        }
+        Ok(input)
     }
 
     pub fn predict_batch(&self, inputs: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
-        inputs
-            .into_iter()
-            .map(|input| self.predict(input))
-            .collect()
+        self.predict_matrix(&Matrix::from_rows(&inputs)).to_rows()
     }
 
-    /// TODO: Implement docs...
-    fn backpropagation(&mut self, cache: Vec<Vec<f64>>, target: &[f64], log: bool) {
+    /// Predicts a whole batch at once, one sample per row of `inputs`, split across
+    /// the threads of `set_threads`.
+    pub fn predict_matrix(&self, inputs: &Matrix) -> Matrix {
+        let ranges = split_rows(inputs.rows(), self.threads);
+        if ranges.len() <= 1 {
+            return self.forward_matrix(inputs);
+        }
+        let parts: Vec<Matrix> = std::thread::scope(|scope| {
+            let handles: Vec<_> = ranges
+                .into_iter()
+                .map(|rows| {
+                    scope.spawn(move || {
+                        let mut part = Matrix::default();
+                        part.copy_rows_from(inputs, rows);
+                        self.forward_matrix(&part)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(join).collect()
+        });
+        let data = parts.into_iter().flat_map(Matrix::into_vec).collect();
+        Matrix::from_vec(inputs.rows(), self.output_size(), data)
+    }
 
-        // 1. Compute initial delta at the output layer.
-        // Get the output of the final layer.
-        let output = cache.last().unwrap();
-        if log {
-            self.history.push(self.loss_function.loss(output, target));
+    /// Predicts a batch on the calling thread.
+    fn forward_matrix(&self, inputs: &Matrix) -> Matrix {
+        let mut current = inputs.clone();
+        let mut next = Matrix::default();
+        for (i, layer) in self.layers.iter().enumerate() {
+            layer.forward_batch(&current, self.activation(i), &mut next);
+            std::mem::swap(&mut current, &mut next);
         }
-        let loss_grad = self.loss_function.gradient(output, target);
-        let mut delta: Vec<f64> = loss_grad
-            .iter()
-            .zip(output)
-            .map(|(lg, &o)| lg * self.final_activation.derivative(o))
-            .collect();
+        current
+    }
 
-        // 2. Iterate backwards over layers.
-        for i in (0..cache.len() - 1).rev() {
-            if i == cache.len() - 1 {
-                // Compute gradients for weights and biases.
-                let mut weight_gradients = vec![vec![0.0; cache[i].len()]; delta.len()];
-                for j in 0..delta.len() {
-                    for k in 0..cache[i].len() {
-                        weight_gradients[j][k] = delta[j] * cache[i][k];
-                    }
-                }
-                // Update weights and biases.
-                for j in 0..self.layers[i].weights.len() {
-                    for k in 0..self.layers[i].weights[j].len() {
-                        self.layers[i].weights[j][k] -=
-                            self.learning_rate * weight_gradients[j][k];
-                    }
-                    self.layers[i].biases[j] -= self.learning_rate * delta[j];
+    /// Records the forward pass of a batch, then takes a gradient descent step on its mean loss,
+    /// with the loss of every sample scaled by its entry in `weights`.
+    /// Returns the mean loss of the batch before the step.
+    fn step(
+        &mut self,
+        workspace: &mut Workspace,
+        inputs: &Matrix,
+        targets: &Matrix,
+        weights: Option<&[f64]>,
+    ) -> f64 {
+        let mut rng = workspace.take_rng();
+        let ranges = split_rows(inputs.rows(), self.threads);
+        let loss = if ranges.len() <= 1 {
+            let Workspace {
+                tape,
+                graph,
+                gradients,
+                ..
+            } = workspace;
+            self.record(tape, graph, inputs, Some(&mut rng));
+            let loss = self.mean_loss(tape.value(graph.output()), targets, weights);
+            self.compute_gradients(tape, graph, targets, weights, gradients);
+            loss
+        } else {
+            self.compute_gradients_parallel(workspace, inputs, targets, weights, &ranges, &mut rng)
+        };
+        workspace.rng = Some(rng);
+        self.apply_gradients(&mut workspace.gradients);
+        loss
+    }
+
+    /// Computes the gradients of every range of rows of the batch on its own thread, with
+    /// its own tape and a dropout RNG seeded from `rng`. The gradients of the ranges are
+    /// summed in order, weighted by their share of the batch, so that the result does not
+    /// depend on which thread finishes first. Returns the mean loss of the batch.
+    fn compute_gradients_parallel(
+        &self,
+        workspace: &mut Workspace,
+        inputs: &Matrix,
+        targets: &Matrix,
+        weights: Option<&[f64]>,
+        ranges: &[Range<usize>],
+        rng: &mut StdRng,
+    ) -> f64 {
+        let Workspace {
+            gradients, workers, ..
+        } = workspace;
+        workers.resize_with(ranges.len(), Worker::default);
+        let losses: Vec<f64> = std::thread::scope(|scope| {
+            let handles: Vec<_> = workers
+                .iter_mut()
+                .zip(ranges)
+                .map(|(worker, rows)| {
+                    let seed = rng.random();
+                    scope.spawn(move || {
+                        let Worker {
+                            tape,
+                            graph,
+                            gradients,
+                            inputs: part_inputs,
+                            targets: part_targets,
+                        } = worker;
+                        part_inputs.copy_rows_from(inputs, rows.clone());
+                        part_targets.copy_rows_from(targets, rows.clone());
+                        let part_weights = weights.map(|weights| &weights[rows.clone()]);
+                        self.record(
+                            tape,
+                            graph,
+                            part_inputs,
+                            Some(&mut StdRng::seed_from_u64(seed)),
+                        );
+                        let loss =
+                            self.mean_loss(tape.value(graph.output()), part_targets, part_weights);
+                        self.compute_gradients(tape, graph, part_targets, part_weights, gradients);
+                        loss
+                    })
+                })
+                .collect();
+            handles.into_iter().map(join).collect()
+        });
+
+        gradients
+            .weights
+            .resize_with(self.layers.len(), Matrix::default);
+        gradients.biases.resize_with(self.layers.len(), Vec::new);
+        for (i, (weights, biases)) in gradients
+            .weights
+            .iter_mut()
+            .zip(&mut gradients.biases)
+            .enumerate()
+        {
+            let (rows, cols) = workers[0].gradients.weights[i].shape();
+            weights.reset(rows, cols);
+            biases.clear();
+            biases.resize(workers[0].gradients.biases[i].len(), 0.0);
+        }
+        let batch_size = inputs.rows() as f64;
+        let mut loss = 0.0;
+        for ((worker, rows), part_loss) in workers.iter().zip(ranges).zip(losses) {
+            let share = rows.len() as f64 / batch_size;
+            loss += share * part_loss;
+            let parts = worker
+                .gradients
+                .weights
+                .iter()
+                .zip(&worker.gradients.biases);
+            for ((weights, biases), (part_weights, part_biases)) in gradients
+                .weights
+                .iter_mut()
+                .zip(&mut gradients.biases)
+                .zip(parts)
+            {
+                weights.add_scaled(share, part_weights);
+                axpy(biases, share, part_biases);
+            }
+        }
+        loss
+    }
+
+    /// Mean loss over a batch of outputs, without regularization, with the loss of every
+    /// sample scaled by its entry in `weights`.
+    fn mean_loss(&self, output: &Matrix, target: &Matrix, weights: Option<&[f64]>) -> f64 {
+        let loss = output
+            .iter_rows()
+            .zip(target.iter_rows())
+            .enumerate()
+            .map(|(i, (o, t))| weights.map_or(1.0, |w| w[i]) * self.loss_function.loss(o, t))
+            .sum::<f64>();
+        loss / output.rows() as f64
+    }
+
+    /// Computes the gradients of the mean loss of the batch recorded in `graph`, plus
+    /// the L2 penalties, with respect to the weights and biases of every layer.
+    ///
+    /// The loss is recorded on the tape after the forward pass, and `Tape::backward`
+    /// propagates its gradient back through every layer with the chain rule.
+    fn compute_gradients(
+        &self,
+        tape: &mut Tape,
+        graph: &Graph,
+        targets: &Matrix,
+        weights: Option<&[f64]>,
+        gradients: &mut Gradients,
+    ) {
+        let output = graph.output();
+        assert_eq!(
+            tape.value(output).shape(),
+            targets.shape(),
+            "target has the wrong shape"
+        );
+        let last = self.layers.len() - 1;
+        let target = tape.constant(targets);
+        let weights = weights.map(|weights| {
+            assert_eq!(
+                weights.len(),
+                targets.rows(),
+                "one weight per sample is needed"
+            );
+            let (rows, cols) = targets.shape();
+            tape.constant_from_fn(rows, cols, |i, _| weights[i])
+        });
+        let mut loss = self.loss_function.record(
+            tape,
+            graph.sums[last],
+            output,
+            self.activation(last),
+            target,
+            weights,
+        );
+        for (layer, params) in self.layers.iter().zip(&graph.params) {
+            // The L2 penalty `λ/2 · ‖W‖²`.
+            if let (true, Some((weights, _))) = (layer.l2 > 0.0, params) {
+                let squares = tape.square(*weights);
+                let sum = tape.sum(squares);
+                let penalty = tape.scale(sum, 0.5 * layer.l2);
+                loss = tape.add(loss, penalty);
+            }
+        }
+        tape.backward(loss);
+
+        gradients
+            .weights
+            .resize_with(self.layers.len(), Matrix::default);
+        gradients.biases.resize_with(self.layers.len(), Vec::new);
+        for ((weights, biases), params) in gradients
+            .weights
+            .iter_mut()
+            .zip(&mut gradients.biases)
+            .zip(&graph.params)
+        {
+            biases.clear();
+            match params {
+                Some((w, b)) => {
+                    weights.copy_from(tape.grad(*w));
+                    biases.extend_from_slice(tape.grad(*b).as_slice());
                 }
+                None => weights.reset(0, 0),
             }
-            else {
-                todo!()
+        }
+    }
 
+    /// Clips the gradients and takes a gradient descent step, `W ← W − η · ∇W` and `b ← b − η · ∇b`,
+    /// with the current learning rate of the schedule.
+    fn apply_gradients(&mut self, gradients: &mut Gradients) {
+        if let Some(max_value) = self.clipping.max_value {
+            gradients.for_each_mut(|g| *g = g.clamp(-max_value, max_value));
+        }
+        if let Some(max_norm) = self.clipping.max_norm {
+            let norm = gradients.norm();
+            if norm > max_norm {
+                gradients.for_each_mut(|g| *g *= max_norm / norm);
             }
+        }
+        let learning_rate = self.current_learning_rate();
+        for ((layer, weights), biases) in self
+            .layers
+            .iter_mut()
+            .zip(&gradients.weights)
+            .zip(&gradients.biases)
+        {
+            layer.weights.add_scaled(-learning_rate, weights);
+            axpy(&mut layer.biases, -learning_rate, biases);
+        }
+        self.scheduler.step(ScheduleInterval::Batch);
+    }
 
+    /// Compares the gradients of the tape with central finite differences
+    /// `(L(θ + ε) − L(θ − ε)) / 2ε` of the mean loss over the batch, plus the L2
+    /// penalties, for every weight and bias. Returns the largest relative error
+    /// `|analytic − numeric| / max(|analytic| + |numeric|, 1e-8)` of every layer.
+    ///
+    /// The network is not changed. Inputs that put a ReLU-like unit or a Huber
+    /// error right at its kink can show large errors that are not bugs.
+    pub fn gradient_check(&self, inputs: &Matrix, targets: &Matrix, epsilon: f64) -> Vec<f64> {
+        let (mut tape, mut graph) = (Tape::new(), Graph::default());
+        self.record(&mut tape, &mut graph, inputs, None);
+        let mut gradients = Gradients::default();
+        self.compute_gradients(&mut tape, &graph, targets, None, &mut gradients);
 
-            // 3. Propagate the error to previous layer if not at the first layer.
-            if layer_index > 0 {
-                let mut new_delta = vec![0.0; self.layers[layer_index - 1].weights.len()];
-                for k in 0..new_delta.len() {
-                    for j in 0..delta.len() {
-                        new_delta[k] += delta[j] * self.layers[layer_index].weights[j][k];
-                    }
-                    new_delta[k] *= self.activation_function.derivative(cache[i][k]);
-                }
-                delta = new_delta;
+        let mut network = self.clone();
+        let objective = |network: &NeuralNetwork| {
+            let penalty = network
+                .layers
+                .iter()
+                .map(|layer| {
+                    0.5 * layer.l2 * dot(layer.weights.as_slice(), layer.weights.as_slice())
+                })
+                .sum::<f64>();
+            network.evaluate(inputs, targets) + penalty
+        };
+        let relative_error = |analytic: f64, numeric: f64| {
+            (analytic - numeric).abs() / (analytic.abs() + numeric.abs()).max(1e-8)
+        };
+        let mut errors = Vec::with_capacity(self.layers.len());
+        for i in 0..self.layers.len() {
+            let mut max_error: f64 = 0.0;
+            for k in 0..network.layers[i].weights.as_slice().len() {
+                let original = network.layers[i].weights.as_slice()[k];
+                network.layers[i].weights.as_mut_slice()[k] = original + epsilon;
+                let plus = objective(&network);
+                network.layers[i].weights.as_mut_slice()[k] = original - epsilon;
+                let minus = objective(&network);
+                network.layers[i].weights.as_mut_slice()[k] = original;
+                let numeric = (plus - minus) / (2.0 * epsilon);
+                max_error =
+                    max_error.max(relative_error(gradients.weights[i].as_slice()[k], numeric));
+            }
+            for k in 0..network.layers[i].biases.len() {
+                let original = network.layers[i].biases[k];
+                network.layers[i].biases[k] = original + epsilon;
+                let plus = objective(&network);
+                network.layers[i].biases[k] = original - epsilon;
+                let minus = objective(&network);
+                network.layers[i].biases[k] = original;
+                let numeric = (plus - minus) / (2.0 * epsilon);
+                max_error = max_error.max(relative_error(gradients.biases[i][k], numeric));
             }
+            errors.push(max_error);
         }
+        errors
+    }
+
+    /// Performs one gradient descent step on the mean loss of the batch, without logging the loss.
+    pub fn train_batch(&mut self, inputs: &[Vec<f64>], targets: &[Vec<f64>]) -> f64 {
+        self.train_matrix(&Matrix::from_rows(inputs), &Matrix::from_rows(targets))
+    }
+
+    /// Like `train_batch`, with one sample per row of `inputs` and `targets`.
+    /// Returns the mean loss of the batch before the step.
+    pub fn train_matrix(&mut self, inputs: &Matrix, targets: &Matrix) -> f64 {
+        let mut workspace = std::mem::take(&mut self.workspace);
+        let loss = self.step(&mut workspace, inputs, targets, None);
+        self.workspace = workspace;
+        loss
+    }
+
+    /// Like `train_matrix`, with the loss of every sample scaled by its entry in `weights`,
+    /// e.g. the importance-sampling weights of prioritized replay.
+    /// Returns the weighted mean loss of the batch before the step.
+    pub fn train_matrix_weighted(
+        &mut self,
+        inputs: &Matrix,
+        targets: &Matrix,
+        weights: &[f64],
+    ) -> f64 {
+        let mut workspace = std::mem::take(&mut self.workspace);
+        let loss = self.step(&mut workspace, inputs, targets, Some(weights));
+        self.workspace = workspace;
+        loss
+    }
+
+    /// Mean loss over a batch, without dropout and regularization.
+    pub fn evaluate(&self, inputs: &Matrix, targets: &Matrix) -> f64 {
+        self.mean_loss(&self.predict_matrix(inputs), targets, None)
     }
-    pub fn train(&mut self, input: Vec<Vec<f64>>, target: Vec<Vec<f64>>) {
-        let sty: ProgressStyle = ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-        )
-        .unwrap()
-        .progress_chars("#>-");
-        let pb = ProgressBar::new(input.len() as u64);
-        pb.set_style(sty.clone());
 
-        for (x, y) in input.iter().zip(target.iter()) {
-            let cache = self.forward(x.clone());
-            self.backpropagation(cache, y, true);
-            pb.set_message("Training..");
-            pb.inc(1);
+    /// Takes one gradient descent step per sample, logging every loss into the history.
+    pub fn train(
+        &mut self,
+        input: Vec<Vec<f64>>,
+        target: Vec<Vec<f64>>,
+        callback: &mut dyn TrainingCallback,
+    ) {
+        // One sample at a time, through the reused buffers of the workspace.
+        let mut workspace = std::mem::take(&mut self.workspace);
+        let (mut inputs, mut targets) = (Matrix::default(), Matrix::default());
+        for (i, (x, y)) in input.iter().zip(target.iter()).enumerate() {
+            inputs.copy_from_row(x);
+            targets.copy_from_row(y);
+            let loss = self.step(&mut workspace, &inputs, &targets, None);
+            self.history.push(loss);
+            callback.on_batch_end(i as u64 + 1, loss);
         }
+        self.workspace = workspace;
+        callback.on_train_end();
     }
+
+    /// Moves every weight and bias towards those of `other`, `θ ← τ · θ_other + (1 − τ) · θ`,
+    /// e.g. a target network towards the policy network. `other` must have the same layers.
+    pub fn blend_from(&mut self, other: &NeuralNetwork, tau: f64) {
+        assert_eq!(
+            self.layers.len(),
+            other.layers.len(),
+            "the networks have different layers"
+        );
+        for (layer, other) in self.layers.iter_mut().zip(&other.layers) {
+            assert_eq!(
+                layer.weights.shape(),
+                other.weights.shape(),
+                "the layers have different shapes"
+            );
+            let weights = layer
+                .weights
+                .as_mut_slice()
+                .iter_mut()
+                .zip(other.weights.as_slice());
+            let biases = layer.biases.iter_mut().zip(&other.biases);
+            for (value, other) in weights.chain(biases) {
+                *value += tau * (other - *value);
+            }
+        }
+    }
+
     /// Returns the loss history of the training process.
     pub fn get_history(&self) -> &[f64] {
         &self.history
     }
 }
 
+/// What a layer computes from its inputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayerKind {
+    /// Every neuron sees every input, `z = W · x + b`.
+    #[default]
+    Dense,
+    /// A 2D convolution over planes, see `Conv2d`. The outputs are planes again,
+    /// one per output channel.
+    Conv2d(Conv2d),
+    /// Passes planes of the given shape on unchanged, as the flat inputs of the
+    /// dense layers after the convolutions. Has no weights.
+    Flatten(PlaneShape),
+    /// The head of a dueling network (Wang et al., 2016), which combines a state value `V`
+    /// and one advantage `A(a)` per action, its `n + 1` inputs in that order, into `n`
+    /// Q-values `Q(a) = V + A(a) − mean(A)`. Subtracting the mean makes `V` and `A`
+    /// identifiable. Has no weights.
+    Dueling(usize),
+}
+
+/// The matrix `M` with `Q = M · (V, A)` for a dueling head with `actions` actions.
+fn dueling_matrix(actions: usize) -> Matrix {
+    let mean = 1.0 / actions as f64;
+    Matrix::from_fn(actions, actions + 1, |a, j| match j {
+        0 => 1.0,
+        _ if j == a + 1 => 1.0 - mean,
+        _ => -mean,
+    })
+}
+
 /// Represents a single layer in the neural network.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Layer {
-    pub weights: Vec<Vec<f64>>,
+    /// One row of input weights per output neuron, or per output channel of a convolution.
+    pub weights: Matrix,
     pub biases: Vec<f64>,
+    /// Overrides the activation the network uses for this layer.
+    #[serde(default)]
+    pub activation: Option<ActivationFunction>,
+    /// Coefficient λ of the L2 penalty `λ/2 · ‖W‖²` on the weights, 0 to disable.
+    #[serde(default)]
+    pub l2: f64,
+    /// Fraction of the outputs that are dropped during training, 0 to disable.
+    /// Ignored for the output layer.
+    #[serde(default)]
+    pub dropout: f64,
+    #[serde(default)]
+    pub kind: LayerKind,
 }
 
 impl Layer {
     /// Creates a new Layer with the given input and output sizes.
     /// Weights are randomly initialized using a uniform distribution; biases are set to zero.
     pub fn new(input_size: usize, output_size: usize) -> Self {
-        let mut rng = rand::rng();
+        Self::with_initializer(
+            input_size,
+            output_size,
+            Initializer::default(),
+            &mut rand::rng(),
+        )
+    }
 
-        let weights: Vec<Vec<f64>> = (0..output_size)
-            .map(|_| (0..input_size).map(|_| rng.random_range(-1.0..1.0)).collect())
-            .collect();
+    /// Creates a new Layer with weights drawn by `initializer`; biases are set to zero.
+    pub fn with_initializer(
+        input_size: usize,
+        output_size: usize,
+        initializer: Initializer,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let weights = initializer.weights(input_size, output_size, rng);
         let biases = vec![0.0; output_size];
-        Layer { weights, biases }
+        Layer {
+            weights,
+            biases,
+            activation: None,
+            l2: 0.0,
+            dropout: 0.0,
+            kind: LayerKind::Dense,
+        }
+    }
+
+    /// Creates a convolutional layer with one row of weights per output channel drawn
+    /// by `initializer`; biases are set to zero. Panics if `conv` is not valid.
+    pub fn conv2d(conv: Conv2d, initializer: Initializer, rng: &mut impl Rng) -> Self {
+        assert!(conv.is_valid(), "invalid convolution {conv:?}");
+        let mut layer =
+            Self::with_initializer(conv.patch_size(), conv.out_channels, initializer, rng);
+        layer.kind = LayerKind::Conv2d(conv);
+        layer
+    }
+
+    /// Creates a layer that passes planes of `shape` on as they are.
+    pub fn flatten(shape: PlaneShape) -> Self {
+        Layer {
+            weights: Matrix::default(),
+            biases: Vec::new(),
+            activation: Some(ActivationFunction::Linear),
+            l2: 0.0,
+            dropout: 0.0,
+            kind: LayerKind::Flatten(shape),
+        }
+    }
+
+    /// Creates the head of a dueling network with `actions` outputs, see `LayerKind::Dueling`.
+    pub fn dueling(actions: usize) -> Self {
+        Layer {
+            weights: Matrix::default(),
+            biases: Vec::new(),
+            activation: Some(ActivationFunction::Linear),
+            l2: 0.0,
+            dropout: 0.0,
+            kind: LayerKind::Dueling(actions),
+        }
+    }
+
+    pub fn input_size(&self) -> usize {
+        match self.kind {
+            LayerKind::Dense => self.weights.cols(),
+            LayerKind::Conv2d(conv) => conv.input.size(),
+            LayerKind::Flatten(shape) => shape.size(),
+            LayerKind::Dueling(actions) => actions + 1,
+        }
+    }
+
+    pub fn output_size(&self) -> usize {
+        match self.kind {
+            LayerKind::Dense => self.weights.rows(),
+            LayerKind::Conv2d(conv) if conv.is_valid() => conv.output_shape().size(),
+            LayerKind::Conv2d(_) => 0,
+            LayerKind::Flatten(shape) => shape.size(),
+            LayerKind::Dueling(actions) => actions,
+        }
+    }
+
+    /// Checks that the layer has neurons and one bias per neuron. `index` is only used in the error.
+    fn validate(&self, index: usize) -> Result<(), NetworkError> {
+        match self.kind {
+            LayerKind::Dense => {}
+            LayerKind::Conv2d(conv) => {
+                if !conv.is_valid()
+                    || self.weights.shape() != (conv.out_channels, conv.patch_size())
+                {
+                    return Err(NetworkError::InvalidConvolution { layer: index });
+                }
+            }
+            LayerKind::Flatten(_) | LayerKind::Dueling(_) => {
+                return if self.output_size() == 0 {
+                    Err(NetworkError::EmptyLayer { layer: index })
+                } else {
+                    Ok(())
+                };
+            }
+        }
+        if self.output_size() == 0 || self.input_size() == 0 {
+            return Err(NetworkError::EmptyLayer { layer: index });
+        }
+        if self.biases.len() != self.weights.rows() {
+            return Err(NetworkError::BiasMismatch {
+                layer: index,
+                expected: self.weights.rows(),
+                found: self.biases.len(),
+            });
+        }
+        Ok(())
     }
 
     /// Performs a forward pass for this layer.
     ///
     /// Given an input vector, computes the weighted sum (z) and applies the activation function to produce output.
-    /// Returns a tuple containing (z, activated_output).
     pub fn forward(&self, input: &[f64], activation_func: &ActivationFunction) -> Vec<f64> {
-        let mut output = Vec::with_capacity(self.weights.len());
-        for (i, weights_row) in self.weights.iter().enumerate() {
-            let sum: f64 = input.iter().zip(weights_row).map(|(inp, w)| inp * w).sum();
-            output.push(activation_func.apply(sum + self.biases[i]));
-        }
+        let mut output = Vec::with_capacity(self.biases.len());
+        self.forward_into(input, activation_func, &mut output);
         output
     }
 
+    fn forward_into(
+        &self,
+        input: &[f64],
+        activation_func: &ActivationFunction,
+        output: &mut Vec<f64>,
+    ) {
+        if self.kind != LayerKind::Dense {
+            let mut batch = Matrix::default();
+            self.forward_batch(
+                &Matrix::row_vector(input.to_vec()),
+                activation_func,
+                &mut batch,
+            );
+            *output = batch.into_vec();
+            return;
+        }
+        output.clear();
+        output.extend(
+            self.weights
+                .iter_rows()
+                .zip(&self.biases)
+                .map(|(weights_row, bias)| dot(input, weights_row) + bias),
+        );
+        activation_func.apply_slice(output);
+    }
+
+    /// Performs a forward pass for a batch with one sample per row, `output = f(input · Wᵀ + b)`.
+    pub fn forward_batch(
+        &self,
+        input: &Matrix,
+        activation_func: &ActivationFunction,
+        output: &mut Matrix,
+    ) {
+        self.weighted_sums(input, output);
+        for row in output.iter_rows_mut() {
+            activation_func.apply_slice(row);
+        }
+    }
+
+    /// The outputs of the layer before the activation, one sample per row.
+    fn weighted_sums(&self, input: &Matrix, sums: &mut Matrix) {
+        match self.kind {
+            LayerKind::Dense => {
+                matmul_transpose_b(input, &self.weights, sums);
+                sums.add_row(&self.biases);
+            }
+            LayerKind::Conv2d(conv) => conv.forward(&self.weights, &self.biases, input, sums),
+            LayerKind::Flatten(_) => sums.copy_from(input),
+            LayerKind::Dueling(actions) => {
+                matmul_transpose_b(input, &dueling_matrix(actions), sums)
+            }
+        }
+    }
+
+    /// Records the weighted sums of the layer for `input` on `tape`. Returns them together
+    /// with the weights and biases, which are recorded as variables.
+    fn record(&self, tape: &mut Tape, input: Var) -> (Var, Option<(Var, Var)>) {
+        match self.kind {
+            LayerKind::Dense | LayerKind::Conv2d(_) => {
+                let weights = tape.variable(&self.weights);
+                let biases = tape.variable_row(&self.biases);
+                let sums = match self.kind {
+                    LayerKind::Conv2d(conv) => tape.conv2d(input, weights, biases, conv),
+                    _ => {
+                        let product = tape.matmul_transpose_b(input, weights);
+                        tape.add_row(product, biases)
+                    }
+                };
+                (sums, Some((weights, biases)))
+            }
+            LayerKind::Flatten(_) => (input, None),
+            LayerKind::Dueling(actions) => {
+                let combine = tape.constant(&dueling_matrix(actions));
+                (tape.matmul_transpose_b(input, combine), None)
+            }
+        }
+    }
+
     pub fn set_weights(&mut self, next_neuron: usize, current_neuron: usize, value: f64) {
         self.weights[next_neuron][current_neuron] = value;
     }
@@ -316,6 +1342,7 @@ impl Layer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::callback::{Metrics, Silent};
 
     #[test]
     fn test_neural_network_creation() {
@@ -333,8 +1360,8 @@ mod tests {
     #[test]
     fn test_layer_creation() {
         let layer = Layer::new(3, 2);
-        assert_eq!(layer.weights.len(), 2); // should have 2 rows for 2 outputs.
-        assert_eq!(layer.weights[0].len(), 3); // each row has 3 weights.
+        assert_eq!(layer.weights.rows(), 2); // should have 2 rows for 2 outputs.
+        assert_eq!(layer.weights.cols(), 3); // each row has 3 weights.
     }
 
     #[test]
@@ -347,10 +1374,10 @@ mod tests {
         );
         nn.add_layers(&[2, 3, 2]);
         let input = vec![1.0, 2.0];
-        let output = nn.forward(input);
+        let activations = nn.forward(&Matrix::row_vector(input));
         // Check that output length equals the size of the final layer.
-        assert_eq!(output.len(), 3);
-        assert_eq!(output.last().unwrap().len(), 2); // Final layer should have 2 outputs.
+        assert_eq!(activations.len(), 3);
+        assert_eq!(activations.last().unwrap().cols(), 2); // Final layer should have 2 outputs.
     }
 
     #[test]
@@ -366,7 +1393,10 @@ mod tests {
         let target = vec![vec![1.0, 0.0], vec![1.0, 0.0], vec![1.0, 0.0]];
 
         // Run training
-        nn.train(input, target);
+        let mut metrics = Metrics::default();
+        nn.train(input, target, &mut metrics);
+        assert_eq!(metrics.batch_losses, nn.get_history());
+        assert!(metrics.finished);
     }
 
     #[test]
@@ -403,13 +1433,13 @@ mod tests {
         // L3-N0 ==> 0.3 * 0.45 + 0.2 * 0.55 + 0.1 = 0.345 
         
         // Perform Forward pass
-        let cache = nn.forward(input.clone());
+        let activations = nn.forward(&Matrix::row_vector(input.clone()));
 
         // Assert that the output of the last layer is approximately 0.345
-        assert!((cache.last().unwrap().first().unwrap() - 0.345).abs() < 1e-3);
+        assert!((activations.last().unwrap()[0][0] - 0.345).abs() < 1e-3);
         
         // Perform Backward Pass
-        nn.backpropagation(cache, &target, true);
+        nn.train(vec![input], vec![target], &mut Silent);
 
         // delta = -2 * (target - prediction) => -2 * (0.5 - 0.345) = -0.31
         // dError/dW ==> delta x (In * Wn + Ik +Wk) 
@@ -421,17 +1451,20 @@ mod tests {
         // L3-W_5 = 0.3 - 0.5 * (-0.31) * 0.45 = 0.36975
         assert!((nn.layers[1].weights[0][0] - 0.36975).abs() < 0.01);
 
-        // L2-W_4 = 1.0 - 0.5 * (-0.31 x 0.1 x 1.0 x 0.2) = 1.0031
-        assert!((nn.layers[0].weights[1][1] - 1.0031).abs() < 0.01);
+        // The hidden deltas use the outgoing weights W_5 = 0.3 and W_6 = 0.2
+        // from before the update, and ReLU'(z) = 1.0 for both hidden neurons.
+
+        // L2-W_4 = 1.0 - 0.5 * (-0.31 x 0.2 x 1.0 x 0.2) = 1.0062
+        assert!((nn.layers[0].weights[1][1] - 1.0062).abs() < 0.01);
         
-        // L2-W_3 = 0.5 - 0.5 * (-0.31 x 0.1 x 1.0 x 0.2) = 0.5031
-        assert!((nn.layers[0].weights[0][1] - 0.5031).abs() < 0.01);
+        // L2-W_3 = 0.5 - 0.5 * (-0.31 x 0.3 x 1.0 x 0.2) = 0.5093
+        assert!((nn.layers[0].weights[0][1] - 0.5093).abs() < 0.01);
 
-        // L2-W_2 = 0.5 - 0.5 * (-0.31 x 0.1 x 1.0 x 0.5) = 0.5031
-        assert!((nn.layers[0].weights[1][0] - 0.5031).abs() < 0.01);
+        // L2-W_2 = 0.5 - 0.5 * (-0.31 x 0.2 x 1.0 x 0.5) = 0.5155
+        assert!((nn.layers[0].weights[1][0] - 0.5155).abs() < 0.01);
 
-        // L2-W_1 = 0.5 - 0.5 * (-0.31 x 0.1 x 1.0 x 0.5) = 0.50465
-        assert!((nn.layers[0].weights[0][0] - 0.50465).abs() < 0.01);
+        // L2-W_1 = 0.5 - 0.5 * (-0.31 x 0.3 x 1.0 x 0.5) = 0.52325
+        assert!((nn.layers[0].weights[0][0] - 0.52325).abs() < 0.01);
     
     }
 
@@ -448,10 +1481,724 @@ mod tests {
         let input = vec![0.2];
         let target = vec![0.5];
 
-        let cache = nn.forward(input.clone());
+        nn.train(vec![input], vec![target], &mut Silent);
+        assert_eq!(nn.get_history().len(), 1);
+
+    }
+
+    #[test]
+    fn test_batch_step_averages_sample_gradients() {
+        let mut batched = NeuralNetwork::new(
+            0.1,
+            ActivationFunction::Tanh,
+            ActivationFunction::Linear,
+            LossFunction::MeanSquaredError,
+        );
+        batched.add_layers(&[2, 3, 2]);
+        let inputs = vec![vec![0.5, -0.5], vec![0.1, 0.9]];
+        let targets = vec![vec![1.0, 0.0], vec![-1.0, 0.5]];
+
+        // Two steps with half the learning rate on separate copies, and the
+        // updates summed, equal one step on the mean of the batch.
+        let mut expected = batched.layers.clone();
+        for (x, y) in inputs.iter().zip(&targets) {
+            let mut single = batched.clone();
+            single.learning_rate = 0.05;
+            single.train_batch(std::slice::from_ref(x), std::slice::from_ref(y));
+            for (e, (s, b)) in expected
+                .iter_mut()
+                .zip(single.layers.iter().zip(&batched.layers))
+            {
+                e.weights.add_scaled(1.0, &s.weights);
+                e.weights.add_scaled(-1.0, &b.weights);
+            }
+        }
+        batched.train_batch(&inputs, &targets);
+        for (e, b) in expected.iter().zip(&batched.layers) {
+            for (x, y) in e.weights.as_slice().iter().zip(b.weights.as_slice()) {
+                assert!((x - y).abs() < 1e-12);
+            }
+        }
+
+        // The batched forward pass agrees with the one for single samples.
+        let predictions = batched.predict_batch(inputs.clone());
+        for (x, p) in inputs.into_iter().zip(predictions) {
+            assert_eq!(batched.predict(x), p);
+        }
+    }
+
+    #[test]
+    fn test_loads_legacy_model_json() {
+        // Weights used to be stored as one vector per output neuron.
+        let json = r#"{
+            "layers": [{"weights": [[0.5, 0.5], [0.5, 1.0]], "biases": [0.1, 0.1]},
+                       {"weights": [[0.3, 0.2]], "biases": [0.1]}],
+            "learning_rate": 0.5,
+            "activation_function": "ReLU",
+            "final_activation": "Linear",
+            "loss_function": "MeanSquaredError",
+            "history": []
+        }"#;
+        let nn: NeuralNetwork = serde_json::from_str(json).unwrap();
+        assert_eq!(nn.layers[0].weights.shape(), (2, 2));
+        assert!((nn.predict(vec![0.5, 0.2])[0] - 0.345).abs() < 1e-9);
+
+        // Saving writes the row-major format, which loads back to the same network.
+        let saved = serde_json::to_string(&nn).unwrap();
+        assert!(saved.contains(r#""rows":2,"cols":2"#));
+        let reloaded: NeuralNetwork = serde_json::from_str(&saved).unwrap();
+        assert_eq!(reloaded.layers[1].weights, nn.layers[1].weights);
+    }
+
+    /// Central finite difference of `f` at `x`.
+    fn numeric_derivative(f: impl Fn(f64) -> f64, x: f64) -> f64 {
+        let h = 1e-6;
+        (f(x + h) - f(x - h)) / (2.0 * h)
+    }
 
-        nn.backpropagation(cache, &target, true);
+    #[test]
+    fn test_activation_derivatives_match_finite_differences() {
+        let activations = [
+            ActivationFunction::ReLU,
+            ActivationFunction::Sigmoid,
+            ActivationFunction::Tanh,
+            ActivationFunction::Linear,
+            ActivationFunction::LeakyReLU(0.1),
+            ActivationFunction::ELU(1.5),
+            ActivationFunction::GELU,
+        ];
+        for activation in &activations {
+            // Away from 0, where ReLU and its variants have a kink.
+            for x in [-2.5, -0.7, -0.1, 0.3, 1.5, 4.0] {
+                let numeric = numeric_derivative(|x| activation.apply(x), x);
+                let analytic = activation.derivative(x, activation.apply(x));
+                assert!(
+                    (numeric - analytic).abs() < 1e-6,
+                    "{activation:?} at {x}: {numeric} != {analytic}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_softmax_gradient_matches_finite_differences() {
+        let z = [0.5, -1.0, 2.0, 0.1];
+        let upstream = [0.3, -0.2, 0.7, 0.1];
+        // L(z) = upstream · softmax(z)
+        let loss = |z: &[f64]| {
+            let mut p = z.to_vec();
+            ActivationFunction::Softmax.apply_slice(&mut p);
+            dot(&upstream, &p)
+        };
+        let mut p = z.to_vec();
+        ActivationFunction::Softmax.apply_slice(&mut p);
+        assert!((p.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        let mut gradient = upstream.to_vec();
+        ActivationFunction::Softmax.backward_slice(&z, &p, &mut gradient);
+        for i in 0..z.len() {
+            let numeric = numeric_derivative(
+                |x| {
+                    let mut z = z.to_vec();
+                    z[i] = x;
+                    loss(&z)
+                },
+                z[i],
+            );
+            assert!((numeric - gradient[i]).abs() < 1e-6);
+        }
 
+        // Large sums do not overflow.
+        let mut large = vec![1000.0, 1001.0];
+        ActivationFunction::Softmax.apply_slice(&mut large);
+        assert!(large.iter().all(|p| p.is_finite()));
     }
 
+    /// The gradient of the loss recorded on a tape with respect to the weighted sums `z` of one sample.
+    fn loss_gradient(
+        loss: &LossFunction,
+        activation: &ActivationFunction,
+        z: &[f64],
+        target: &[f64],
+    ) -> Vec<f64> {
+        let mut tape = Tape::new();
+        let sums = tape.variable_row(z);
+        let output = tape.activation(sums, activation);
+        let target = tape.constant(&Matrix::row_vector(target.to_vec()));
+        let value = loss.record(&mut tape, sums, output, activation, target, None);
+        tape.backward(value);
+        tape.grad(sums).as_slice().to_vec()
+    }
+
+    #[test]
+    fn test_loss_gradients_match_finite_differences() {
+        let predicted = [0.9, 0.7, 0.4];
+        let target = [0.0, 1.0, 0.5];
+        let losses = [
+            LossFunction::MeanSquaredError,
+            LossFunction::BinaryCrossEntropy,
+            LossFunction::CategoricalCrossEntropy,
+            // Errors of 0.9 (linear part) and 0.3, 0.1 (quadratic part).
+            LossFunction::Huber(0.5),
+        ];
+        for loss in &losses {
+            let gradient = loss_gradient(loss, &ActivationFunction::Linear, &predicted, &target);
+            for i in 0..predicted.len() {
+                let numeric = numeric_derivative(
+                    |x| {
+                        let mut p = predicted.to_vec();
+                        p[i] = x;
+                        loss.loss(&p, &target)
+                    },
+                    predicted[i],
+                );
+                assert!(
+                    (numeric - gradient[i]).abs() < 1e-6,
+                    "{loss:?} at {i}: {numeric} != {}",
+                    gradient[i]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy_gradient() {
+        let z = [0.2, -0.4, 1.1];
+        let target = [0.0, 1.0, 0.0];
+        let loss = LossFunction::CategoricalCrossEntropy;
+        let mut p = z.to_vec();
+        ActivationFunction::Softmax.apply_slice(&mut p);
+        // Recorded through log_softmax, the gradient is `p − t`.
+        let gradient = loss_gradient(&loss, &ActivationFunction::Softmax, &z, &target);
+        for i in 0..z.len() {
+            let numeric = numeric_derivative(
+                |x| {
+                    let mut p = z.to_vec();
+                    p[i] = x;
+                    ActivationFunction::Softmax.apply_slice(&mut p);
+                    loss.loss(&p, &target)
+                },
+                z[i],
+            );
+            assert!((numeric - gradient[i]).abs() < 1e-6);
+            assert!((p[i] - target[i] - gradient[i]).abs() < 1e-9);
+        }
+
+        // A small softmax classifier learns to pick the class of the larger input.
+        let mut nn = NeuralNetwork::builder(2)
+            .dense(2, ActivationFunction::Softmax)
+            .loss(LossFunction::CategoricalCrossEntropy)
+            .learning_rate(0.5)
+            .seed(3)
+            .build()
+            .unwrap();
+        let inputs = vec![
+            vec![1.0, 0.0],
+            vec![0.0, 1.0],
+            vec![0.8, 0.1],
+            vec![0.2, 0.9],
+        ];
+        let targets = vec![
+            vec![1.0, 0.0],
+            vec![0.0, 1.0],
+            vec![1.0, 0.0],
+            vec![0.0, 1.0],
+        ];
+        for _ in 0..200 {
+            nn.train_batch(&inputs, &targets);
+        }
+        assert!(nn.predict(vec![0.9, 0.0])[0] > 0.9);
+        assert!(nn.predict(vec![0.0, 0.9])[1] > 0.9);
+    }
+
+    #[test]
+    fn test_gradient_check_every_activation_and_loss() {
+        use crate::agents::network::builder::LayerSpec;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let hidden = [
+            ActivationFunction::ReLU,
+            ActivationFunction::Sigmoid,
+            ActivationFunction::Tanh,
+            ActivationFunction::Linear,
+            ActivationFunction::LeakyReLU(0.1),
+            ActivationFunction::ELU(1.0),
+            ActivationFunction::GELU,
+            ActivationFunction::Softmax,
+        ];
+        // Every loss with the output activations it is used with.
+        let bounded = [ActivationFunction::Sigmoid, ActivationFunction::Softmax];
+        let any = [
+            ActivationFunction::Linear,
+            ActivationFunction::Tanh,
+            ActivationFunction::Sigmoid,
+            ActivationFunction::Softmax,
+        ];
+        let losses: [(LossFunction, &[ActivationFunction]); 4] = [
+            (LossFunction::MeanSquaredError, &any),
+            (LossFunction::Huber(0.3), &any),
+            (LossFunction::BinaryCrossEntropy, &bounded),
+            (LossFunction::CategoricalCrossEntropy, &bounded),
+        ];
+        let mut rng = StdRng::seed_from_u64(11);
+        for sizes in [&[3, 5, 4, 3][..], &[4, 2, 3], &[2, 1]] {
+            let (input_size, output_size) = (sizes[0], sizes[sizes.len() - 1]);
+            let inputs = Matrix::from_fn(4, input_size, |_, _| rng.random_range(-2.0..2.0));
+            let mut targets = Matrix::from_fn(4, output_size, |_, _| rng.random_range(0.05..0.95));
+            // Categorical targets are distributions.
+            for row in targets.iter_rows_mut() {
+                let sum: f64 = row.iter().sum();
+                row.iter_mut().for_each(|t| *t /= sum);
+            }
+            for activation in &hidden {
+                for (loss, outputs) in &losses {
+                    for output in outputs.iter() {
+                        let mut builder = NeuralNetwork::builder(input_size)
+                            .loss(loss.clone())
+                            .seed(5);
+                        for &size in &sizes[1..sizes.len() - 1] {
+                            builder =
+                                builder.layer(LayerSpec::dense(size, activation.clone()).l2(0.01));
+                        }
+                        let nn = builder.dense(output_size, output.clone()).build().unwrap();
+                        let errors = nn.gradient_check(&inputs, &targets, 1e-5);
+                        assert_eq!(errors.len(), sizes.len() - 1);
+                        for (layer, error) in errors.iter().enumerate() {
+                            assert!(
+                                *error < 1e-5,
+                                "{sizes:?} {activation:?} {output:?} {loss:?}: layer {layer} has error {error}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dropout_only_during_training() {
+        use crate::agents::network::builder::LayerSpec;
+
+        let nn = NeuralNetwork::builder(4)
+            .layer(LayerSpec::dense(200, ActivationFunction::ReLU).dropout(0.5))
+            .dense(1, ActivationFunction::Linear)
+            .seed(1)
+            .build()
+            .unwrap();
+        let x = vec![0.5, -0.2, 0.1, 0.9];
+        let mut without_dropout = nn.clone();
+        without_dropout.layers[0].dropout = 0.0;
+        assert_eq!(nn.predict(x.clone()), without_dropout.predict(x.clone()));
+        assert_eq!(
+            nn.predict_batch(vec![x.clone(); 2]),
+            without_dropout.predict_batch(vec![x.clone(); 2])
+        );
+
+        // A training pass drops about half of the hidden units and doubles the others.
+        let (mut tape, mut graph) = (Tape::new(), Graph::default());
+        nn.record(
+            &mut tape,
+            &mut graph,
+            &Matrix::row_vector(x),
+            Some(&mut StdRng::seed_from_u64(2)),
+        );
+        let mask = tape.value(graph.masks[0].unwrap()).clone();
+        let mask = mask.as_slice();
+        let dropped = mask.iter().filter(|m| **m == 0.0).count();
+        assert!((60..140).contains(&dropped));
+        assert!(mask.iter().all(|m| *m == 0.0 || *m == 2.0));
+        let (activated, sums) = (tape.value(graph.activations[1]), tape.value(graph.sums[0]));
+        for ((a, z), m) in activated.as_slice().iter().zip(sums.as_slice()).zip(mask) {
+            assert_eq!(*a, z.max(0.0) * m);
+        }
+
+        // Dropped units get no gradient.
+        let mut gradients = Gradients::default();
+        nn.compute_gradients(
+            &mut tape,
+            &graph,
+            &Matrix::row_vector(vec![1.0]),
+            None,
+            &mut gradients,
+        );
+        for (row, m) in gradients.weights[0].iter_rows().zip(mask) {
+            if *m == 0.0 {
+                assert!(row.iter().all(|g| *g == 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_gradient_clipping_limits_the_step() {
+        let build = |clipping: GradientClipping| {
+            let mut nn = NeuralNetwork::builder(3)
+                .dense(4, ActivationFunction::Tanh)
+                .dense(2, ActivationFunction::Linear)
+                .learning_rate(1.0)
+                .seed(4)
+                .build()
+                .unwrap();
+            nn.set_clipping(clipping);
+            nn
+        };
+        let parameters = |nn: &NeuralNetwork| {
+            nn.layers
+                .iter()
+                .flat_map(|layer| {
+                    layer
+                        .weights
+                        .as_slice()
+                        .iter()
+                        .chain(&layer.biases)
+                        .copied()
+                })
+                .collect::<Vec<_>>()
+        };
+        // Far off targets, so that the unclipped gradients are large.
+        let (inputs, targets) = (vec![vec![3.0, -2.0, 1.0]], vec![vec![50.0, -50.0]]);
+        let before = parameters(&build(GradientClipping::default()));
+
+        let mut by_value = build(GradientClipping {
+            max_value: Some(0.01),
+            max_norm: None,
+        });
+        by_value.train_batch(&inputs, &targets);
+        let steps = parameters(&by_value)
+            .iter()
+            .zip(&before)
+            .map(|(a, b)| a - b)
+            .collect::<Vec<_>>();
+        assert!(steps.iter().all(|step| step.abs() <= 0.01 + 1e-12));
+        assert!(steps.iter().any(|step| (step.abs() - 0.01).abs() < 1e-12));
+
+        let mut by_norm = build(GradientClipping {
+            max_value: None,
+            max_norm: Some(0.1),
+        });
+        by_norm.train_batch(&inputs, &targets);
+        let norm = parameters(&by_norm)
+            .iter()
+            .zip(&before)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        assert!((norm - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_weight_decay_shrinks_weights() {
+        let mut nn = NeuralNetwork::builder(2)
+            .dense(2, ActivationFunction::Linear)
+            .learning_rate(0.1)
+            .seed(6)
+            .build()
+            .unwrap();
+        nn.set_weight_decay(0.5);
+        let before = nn.layers[0].weights.clone();
+        // The targets are the predictions, so only the penalty has a gradient.
+        let input = vec![0.3, -0.7];
+        let target = nn.predict(input.clone());
+        nn.train_batch(&[input], &[target]);
+        for (after, before) in nn.layers[0]
+            .weights
+            .as_slice()
+            .iter()
+            .zip(before.as_slice())
+        {
+            assert!((after - before * (1.0 - 0.1 * 0.5)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_schedule_resumes_after_loading() {
+        let mut nn = NeuralNetwork::builder(2)
+            .dense(1, ActivationFunction::Linear)
+            .learning_rate(0.1)
+            .schedule(
+                LearningRateSchedule::Exponential { decay: 0.5 },
+                ScheduleInterval::Batch,
+            )
+            .seed(7)
+            .build()
+            .unwrap();
+        let input = Matrix::row_vector(vec![1.0, 2.0]);
+        let target = Matrix::row_vector(vec![0.5]);
+        nn.train_matrix(&input, &target);
+        nn.train_matrix(&input, &target);
+        assert!((nn.current_learning_rate() - 0.025).abs() < 1e-12);
+        // Epochs do not step a per-batch schedule.
+        nn.end_epoch();
+        assert_eq!(nn.scheduler().steps, 2);
+
+        let mut loaded: NeuralNetwork =
+            serde_json::from_str(&serde_json::to_string(&nn).unwrap()).unwrap();
+        assert_eq!(loaded.scheduler(), nn.scheduler());
+        // One step with the scheduled rate moves the weights like the original network.
+        let before = loaded.layers[0].weights.clone();
+        loaded.train_matrix(&input, &target);
+        nn.train_matrix(&input, &target);
+        assert_ne!(loaded.layers[0].weights, before);
+        assert_eq!(loaded.layers[0].weights, nn.layers[0].weights);
+        assert!((loaded.current_learning_rate() - 0.0125).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_convolutional_network() {
+        use crate::agents::network::builder::LayerSpec;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let input = PlaneShape::new(2, 4, 4);
+        let first = Conv2d::new(input, 3, 3).padding(1);
+        let second = Conv2d::new(first.output_shape(), 2, 2).stride(2);
+        let nn = NeuralNetwork::builder(input.size())
+            .layer(LayerSpec::conv2d(first, ActivationFunction::Tanh).l2(0.01))
+            .layer(LayerSpec::conv2d(second, ActivationFunction::ELU(1.0)))
+            .layer(LayerSpec::flatten(second.output_shape()))
+            .dense(3, ActivationFunction::Softmax)
+            .loss(LossFunction::CategoricalCrossEntropy)
+            .seed(8)
+            .build()
+            .unwrap();
+        let sizes: Vec<_> = nn
+            .layers
+            .iter()
+            .map(|l| (l.input_size(), l.output_size()))
+            .collect();
+        assert_eq!(sizes, [(32, 48), (48, 8), (8, 8), (8, 3)]);
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let inputs = Matrix::from_fn(3, input.size(), |_, _| rng.random_range(-1.0..1.0));
+        let targets = Matrix::from_rows(&[
+            vec![1.0, 0.0, 0.0],
+            vec![0.0, 1.0, 0.0],
+            vec![0.2, 0.3, 0.5],
+        ]);
+        for (layer, error) in nn
+            .gradient_check(&inputs, &targets, 1e-5)
+            .iter()
+            .enumerate()
+        {
+            assert!(*error < 1e-5, "layer {layer} has error {error}");
+        }
+
+        // The kind of every layer survives a round trip, and single samples predict like batches.
+        let loaded: NeuralNetwork =
+            serde_json::from_str(&serde_json::to_string(&nn).unwrap()).unwrap();
+        assert!(loaded.validate().is_ok());
+        assert_eq!(loaded.layers[0].kind, LayerKind::Conv2d(first));
+        assert_eq!(
+            loaded.predict(inputs[0].to_vec()),
+            nn.predict_matrix(&inputs)[0].to_vec()
+        );
+
+        // The first convolution has to take the inputs of the network.
+        let wrong = NeuralNetwork::builder(input.size() + 1)
+            .layer(LayerSpec::conv2d(first, ActivationFunction::Tanh))
+            .build();
+        assert!(matches!(
+            wrong,
+            Err(NetworkError::ShapeMismatch { layer: 0, .. })
+        ));
+        let too_large = Conv2d::new(input, 1, 5);
+        let invalid = NeuralNetwork::builder(input.size())
+            .layer(LayerSpec::conv2d(too_large, ActivationFunction::Tanh))
+            .build();
+        assert_eq!(
+            invalid.unwrap_err(),
+            NetworkError::InvalidConvolution { layer: 0 }
+        );
+    }
+
+    #[test]
+    fn test_threads_split_batches_reproducibly() {
+        use crate::agents::network::builder::LayerSpec;
+
+        let build = |threads: usize| {
+            NeuralNetwork::builder(3)
+                .layer(LayerSpec::dense(16, ActivationFunction::Tanh).dropout(0.2))
+                .dense(2, ActivationFunction::Linear)
+                .threads(threads)
+                .seed(5)
+                .build()
+                .unwrap()
+        };
+        let inputs = Matrix::from_fn(64, 3, |i, j| ((i * 5 + j * 3) % 13) as f64 / 13.0 - 0.5);
+        let targets = Matrix::from_fn(64, 2, |i, j| ((i + j) % 3) as f64 - 1.0);
+        assert_eq!(split_rows(64, 4), vec![0..16, 16..32, 32..48, 48..64]);
+        assert_eq!(split_rows(10, 4), vec![0..10]);
+
+        // Every row is predicted on its own, so the threads do not change predictions.
+        let (serial, mut parallel) = (build(1), build(4));
+        assert_eq!(
+            parallel.predict_matrix(&inputs),
+            serial.predict_matrix(&inputs)
+        );
+
+        let mut again = build(4);
+        for _ in 0..5 {
+            let loss = parallel.train_matrix(&inputs, &targets);
+            assert_eq!(again.train_matrix(&inputs, &targets), loss);
+        }
+        assert_eq!(
+            parallel.predict_matrix(&inputs),
+            again.predict_matrix(&inputs)
+        );
+
+        // Without dropout, the split only changes the rounding of the mean gradient.
+        let (mut serial, mut parallel) = (build(1), build(4));
+        serial.layers[0].dropout = 0.0;
+        parallel.layers[0].dropout = 0.0;
+        for _ in 0..5 {
+            let loss = serial.train_matrix(&inputs, &targets);
+            assert!((parallel.train_matrix(&inputs, &targets) - loss).abs() < 1e-12);
+        }
+        let (a, b) = (
+            serial.predict_matrix(&inputs),
+            parallel.predict_matrix(&inputs),
+        );
+        assert!(a
+            .as_slice()
+            .iter()
+            .zip(b.as_slice())
+            .all(|(a, b)| (a - b).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_weighted_samples_count_like_repeated_ones() {
+        use crate::agents::network::builder::LayerSpec;
+
+        let build = |threads: usize| {
+            NeuralNetwork::builder(3)
+                .layer(LayerSpec::dense(8, ActivationFunction::Tanh))
+                .dense(2, ActivationFunction::Linear)
+                .loss(LossFunction::Huber(0.5))
+                .threads(threads)
+                .seed(9)
+                .build()
+                .unwrap()
+        };
+        let inputs = Matrix::from_fn(16, 3, |i, j| ((i * 7 + j * 2) % 11) as f64 / 11.0 - 0.5);
+        let targets = Matrix::from_fn(16, 2, |i, j| ((i + 2 * j) % 5) as f64 - 2.0);
+        // Weighing every even row twice and dropping the odd rows is the same as
+        // training on every even row twice.
+        let weights: Vec<f64> = (0..16)
+            .map(|i| if i % 2 == 0 { 2.0 } else { 0.0 })
+            .collect();
+        let repeated = |m: &Matrix| Matrix::from_fn(m.rows(), m.cols(), |i, j| m[i - i % 2][j]);
+        let (repeated_inputs, repeated_targets) = (repeated(&inputs), repeated(&targets));
+        for threads in [1, 2] {
+            let (mut weighted, mut plain) = (build(threads), build(threads));
+            for _ in 0..3 {
+                let loss = weighted.train_matrix_weighted(&inputs, &targets, &weights);
+                assert!(
+                    (plain.train_matrix(&repeated_inputs, &repeated_targets) - loss).abs() < 1e-12
+                );
+            }
+            let (a, b) = (
+                weighted.predict_matrix(&inputs),
+                plain.predict_matrix(&inputs),
+            );
+            assert!(a
+                .as_slice()
+                .iter()
+                .zip(b.as_slice())
+                .all(|(a, b)| (a - b).abs() < 1e-12));
+        }
+    }
+
+    #[test]
+    fn test_dueling_head_combines_value_and_advantages() {
+        use crate::agents::network::builder::LayerSpec;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        // The value and the advantages are passed through unchanged: V = 1, A = (2, 4, 6).
+        let mut head = NeuralNetwork::builder(4)
+            .layer(
+                LayerSpec::dense(4, ActivationFunction::Linear)
+                    .initializer(Initializer::Constant(0.0)),
+            )
+            .layer(LayerSpec::dueling(3))
+            .build()
+            .unwrap();
+        for i in 0..4 {
+            head.layers[0].set_weights(i, i, 1.0);
+        }
+        let input = vec![1.0, 2.0, 4.0, 6.0];
+        let batch = head
+            .predict_matrix(&Matrix::row_vector(input.clone()))
+            .into_vec();
+        for q in [head.predict(input), batch] {
+            let expected = [-1.0, 1.0, 3.0];
+            assert!(
+                q.iter().zip(expected).all(|(q, e)| (q - e).abs() < 1e-12),
+                "{q:?}"
+            );
+        }
+        // A head that does not fit the layer before it is an error.
+        let error = NeuralNetwork::builder(4)
+            .dense(3, ActivationFunction::Linear)
+            .layer(LayerSpec::dueling(3))
+            .build();
+        assert!(matches!(
+            error,
+            Err(NetworkError::ShapeMismatch {
+                layer: 1,
+                expected: 3,
+                found: 4
+            })
+        ));
+
+        let nn = NeuralNetwork::builder(3)
+            .dense(5, ActivationFunction::Tanh)
+            .dense(4, ActivationFunction::Linear)
+            .layer(LayerSpec::dueling(3))
+            .seed(3)
+            .build()
+            .unwrap();
+        let mut rng = StdRng::seed_from_u64(4);
+        let inputs = Matrix::from_fn(4, 3, |_, _| rng.random_range(-1.0..1.0));
+        let targets = Matrix::from_fn(4, 3, |_, _| rng.random_range(-1.0..1.0));
+        let errors = nn.gradient_check(&inputs, &targets, 1e-5);
+        assert!(errors.iter().all(|&error| error < 1e-5), "{errors:?}");
+    }
+
+    #[test]
+    fn test_blending_moves_towards_the_other_network() {
+        let mut target = NeuralNetwork::new(
+            0.1,
+            ActivationFunction::ReLU,
+            ActivationFunction::Linear,
+            LossFunction::MeanSquaredError,
+        );
+        target.add_layers(&[2, 3, 1]);
+        let mut policy = target.clone();
+        for layer in &mut policy.layers {
+            layer
+                .weights
+                .as_mut_slice()
+                .iter_mut()
+                .for_each(|w| *w += 1.0);
+            layer.biases.iter_mut().for_each(|b| *b = 2.0);
+        }
+        let original = target.clone();
+        target.blend_from(&policy, 0.25);
+        for ((blended, original), policy) in target
+            .layers
+            .iter()
+            .zip(&original.layers)
+            .zip(&policy.layers)
+        {
+            for ((b, o), p) in blended
+                .weights
+                .as_slice()
+                .iter()
+                .zip(original.weights.as_slice())
+                .zip(policy.weights.as_slice())
+            {
+                assert!((b - (0.75 * o + 0.25 * p)).abs() < 1e-12);
+            }
+            assert!(blended.biases.iter().all(|&b| (b - 0.5).abs() < 1e-12));
+        }
+        target.blend_from(&policy, 1.0);
+        assert_eq!(target.layers[1].weights, policy.layers[1].weights);
+    }
 }
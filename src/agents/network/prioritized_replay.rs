@@ -0,0 +1,311 @@
+//! Proportional prioritized experience replay (Schaul et al., 2016).
+//!
+//! Experience `i` is sampled with probability
+//!
+//! ```math
+//! P(i) = pᵢ^α / Σₖ pₖ^α,    pᵢ = |δᵢ| + ε
+//! ```
+//!
+//! where `δᵢ` is its last TD error, and its update is scaled by the importance-sampling
+//! weight `wᵢ = (N · P(i))^−β / maxⱼ wⱼ`, which corrects the bias of the non-uniform
+//! sampling as β is annealed towards 1.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// A complete binary tree whose leaves hold priorities and whose inner nodes hold the
+/// sums of their children, so that updating a priority and finding the leaf at a
+/// cumulative priority both take O(log n).
+#[derive(Debug, Clone, Default)]
+pub struct SumTree {
+    /// `nodes[1]` is the root, the children of node `k` are `2k` and `2k + 1`, and the
+    /// leaves start at `leaves`.
+    nodes: Vec<f64>,
+    leaves: usize,
+}
+
+impl SumTree {
+    pub fn new(capacity: usize) -> Self {
+        let leaves = capacity.next_power_of_two();
+        SumTree {
+            nodes: vec![0.0; 2 * leaves],
+            leaves,
+        }
+    }
+
+    /// Number of leaves, at least the capacity it was created with.
+    pub fn capacity(&self) -> usize {
+        self.leaves
+    }
+
+    pub fn total(&self) -> f64 {
+        self.nodes.get(1).copied().unwrap_or(0.0)
+    }
+
+    pub fn get(&self, i: usize) -> f64 {
+        self.nodes[self.leaves + i]
+    }
+
+    /// Sets the priority of leaf `i`. The sums above it are recomputed from their
+    /// children, so that rounding errors do not accumulate.
+    pub fn set(&mut self, i: usize, priority: f64) {
+        let mut node = self.leaves + i;
+        self.nodes[node] = priority;
+        while node > 1 {
+            node /= 2;
+            self.nodes[node] = self.nodes[2 * node] + self.nodes[2 * node + 1];
+        }
+    }
+
+    /// The leaf at which the cumulative priority, summed from the first leaf, exceeds `mass`.
+    /// Leaves with zero priority are never returned while the total is positive.
+    pub fn find(&self, mut mass: f64) -> usize {
+        let mut node = 1;
+        while node < self.leaves {
+            let (left, right) = (2 * node, 2 * node + 1);
+            if mass < self.nodes[left] || self.nodes[right] <= 0.0 {
+                node = left;
+            } else {
+                mass -= self.nodes[left];
+                node = right;
+            }
+        }
+        node - self.leaves
+    }
+}
+
+/// The settings and the priorities of prioritized replay, see the module documentation.
+///
+/// The priorities are not saved. A buffer restored from a replay file starts with the
+/// same priority for every experience.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrioritizedReplay {
+    /// How strongly the priorities shape sampling, `0` samples uniformly.
+    pub alpha: f64,
+    /// The importance-sampling exponent, annealed towards 1 as batches are sampled.
+    pub beta: f64,
+    /// Added to `beta` after every sampled batch, until it reaches 1.
+    pub beta_increment: f64,
+    /// Added to every |TD error|, so that no experience stops being sampled.
+    pub epsilon: f64,
+    #[serde(skip)]
+    tree: SumTree,
+    /// The largest priority so far, before `alpha`, given to new experiences.
+    #[serde(skip)]
+    max_priority: f64,
+    /// The capacity of the buffer, the number of slots of the tree that are used.
+    #[serde(skip)]
+    slots: usize,
+    /// The slot of the tree that holds the oldest experience of the buffer.
+    #[serde(skip)]
+    first_slot: usize,
+}
+
+pub const ALPHA_DEFAULT: f64 = 0.6;
+pub const BETA_DEFAULT: f64 = 0.4;
+pub const BETA_INCREMENT_DEFAULT: f64 = 1e-4;
+pub const EPSILON_DEFAULT: f64 = 1e-6;
+
+impl Default for PrioritizedReplay {
+    fn default() -> Self {
+        Self::new(ALPHA_DEFAULT, BETA_DEFAULT, BETA_INCREMENT_DEFAULT)
+    }
+}
+
+impl PrioritizedReplay {
+    pub fn new(alpha: f64, beta: f64, beta_increment: f64) -> Self {
+        assert!(alpha >= 0.0, "alpha must not be negative");
+        assert!((0.0..=1.0).contains(&beta), "beta must be in [0, 1]");
+        PrioritizedReplay {
+            alpha,
+            beta,
+            beta_increment,
+            epsilon: EPSILON_DEFAULT,
+            tree: SumTree::default(),
+            max_priority: 1.0,
+            slots: 0,
+            first_slot: 0,
+        }
+    }
+
+    /// The slot of the tree that holds the experience at position `i` of the buffer.
+    fn slot(&self, i: usize) -> usize {
+        (self.first_slot + i) % self.slots
+    }
+
+    /// Sets up the tree for a buffer of `capacity` that already holds `len` experiences,
+    /// all with the same priority. Called lazily, as the tree is not saved.
+    fn rebuild(&mut self, capacity: usize, len: usize) {
+        self.tree = SumTree::new(capacity);
+        self.slots = capacity;
+        self.first_slot = 0;
+        self.max_priority = self.max_priority.max(1.0);
+        let priority = self.max_priority.powf(self.alpha);
+        for i in 0..len {
+            self.tree.set(i, priority);
+        }
+    }
+
+    /// Forgets all priorities, for a buffer that was cleared. The tree is rebuilt by the
+    /// next `push`.
+    pub(crate) fn clear(&mut self) {
+        self.slots = 0;
+        self.max_priority = 1.0;
+    }
+
+    /// Records that an experience was appended to a buffer of `capacity` that now holds
+    /// `len` experiences, after the oldest one was dropped if `evicted`.
+    pub(crate) fn push(&mut self, capacity: usize, len: usize, evicted: bool) {
+        if self.slots != capacity {
+            // The tree starts out with the experiences that are already in the buffer.
+            self.rebuild(capacity, len - 1);
+        } else if evicted {
+            // The new experience takes the slot of the oldest one.
+            self.first_slot = (self.first_slot + 1) % capacity;
+        }
+        let slot = self.slot(len - 1);
+        self.tree.set(slot, self.max_priority.powf(self.alpha));
+    }
+
+    /// Pushes `batch_size` positions of a buffer holding `len` experiences, one from each
+    /// of `batch_size` equal segments of the total priority, to `indices`, and their weights
+    /// to `weights`. Anneals `beta`.
+    pub(crate) fn sample(
+        &mut self,
+        capacity: usize,
+        len: usize,
+        batch_size: usize,
+        rng: &mut impl Rng,
+        indices: &mut Vec<usize>,
+        weights: &mut Vec<f64>,
+    ) {
+        if self.slots != capacity {
+            self.rebuild(capacity, len);
+        }
+        let total = self.tree.total();
+        let segment = total / batch_size as f64;
+        for k in 0..batch_size {
+            let mass = (k as f64 + rng.random::<f64>()) * segment;
+            let slot = self.tree.find(mass.min(total));
+            let i = (slot % self.slots + self.slots - self.first_slot) % self.slots;
+            // Rounding can only land on an empty leaf at the very end of the tree.
+            let i = i.min(len - 1);
+            let probability = self.tree.get(self.slot(i)) / total;
+            indices.push(i);
+            weights.push((len as f64 * probability).powf(-self.beta));
+        }
+        let max_weight = weights.iter().copied().fold(f64::MIN_POSITIVE, f64::max);
+        for weight in weights.iter_mut() {
+            *weight /= max_weight;
+        }
+        self.beta = (self.beta + self.beta_increment).min(1.0);
+    }
+
+    /// Sets the priorities of the experiences at `indices` of a buffer of `capacity` holding
+    /// `len` experiences from their TD errors.
+    pub(crate) fn update(
+        &mut self,
+        capacity: usize,
+        len: usize,
+        indices: &[usize],
+        td_errors: &[f64],
+    ) {
+        assert_eq!(
+            indices.len(),
+            td_errors.len(),
+            "one TD error per index is needed"
+        );
+        if self.slots != capacity {
+            self.rebuild(capacity, len);
+        }
+        for (&i, error) in indices.iter().zip(td_errors) {
+            let priority = error.abs() + self.epsilon;
+            self.max_priority = self.max_priority.max(priority);
+            let slot = self.slot(i);
+            self.tree.set(slot, priority.powf(self.alpha));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::network::memory_buffer::{Batch, Experience, MemoryBuffer};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn experience(reward: f32) -> Experience {
+        Experience::new(vec![0.0], 0, reward, vec![0.0], false)
+    }
+
+    #[test]
+    fn test_sum_tree_finds_leaves_by_cumulative_priority() {
+        let mut tree = SumTree::new(5);
+        assert_eq!(tree.capacity(), 8);
+        for (i, p) in [1.0, 0.0, 3.0, 2.0, 4.0].into_iter().enumerate() {
+            tree.set(i, p);
+        }
+        assert_eq!(tree.total(), 10.0);
+        assert_eq!(tree.find(0.5), 0);
+        // The empty leaf 1 is skipped.
+        assert_eq!(tree.find(1.0), 2);
+        assert_eq!(tree.find(3.99), 2);
+        assert_eq!(tree.find(4.0), 3);
+        assert_eq!(tree.find(9.99), 4);
+        assert_eq!(tree.find(10.0), 4);
+        tree.set(2, 0.5);
+        assert_eq!(tree.total(), 7.5);
+    }
+
+    #[test]
+    fn test_sampling_follows_priorities_and_weights_correct_for_it() {
+        let mut buffer = MemoryBuffer::prioritized(5, PrioritizedReplay::new(1.0, 0.5, 0.25));
+        for reward in 0..7 {
+            buffer.add_experience(experience(reward as f32));
+        }
+        // The two oldest experiences were dropped, the rest start with the same priority.
+        let rewards: Vec<f32> = buffer.buffer.iter().map(|e| e.reward).collect();
+        assert_eq!(rewards, vec![2.0, 3.0, 4.0, 5.0, 6.0]);
+        buffer.update_priorities(&[0, 1, 2, 3, 4], &[0.0, 0.0, 0.0, 0.0, 3.0]);
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut batch = Batch::default();
+        let mut counts = [0; 5];
+        for _ in 0..1000 {
+            buffer.sample_batch_with(4, &mut batch, &mut rng);
+            let rare = batch.indices.iter().any(|&i| i != 4);
+            for (&i, &w) in batch.indices.iter().zip(&batch.weights) {
+                counts[i] += 1;
+                // The frequent experience is weighted down, relative to the rare ones.
+                assert_eq!(w < 1.0, rare && i == 4, "{i} {w}");
+            }
+        }
+        // Almost only the experience with the largest TD error is sampled.
+        assert!(counts[4] > 3900, "{counts:?}");
+        // β was annealed to 1.
+        assert_eq!(buffer.prioritized.as_ref().unwrap().beta, 1.0);
+
+        // Adding an experience gives it the largest priority so far, and replaces the oldest.
+        buffer.add_experience(experience(7.0));
+        buffer.sample_batch_with(4, &mut batch, &mut rng);
+        assert!(batch.indices.iter().all(|&i| i >= 3));
+        assert!(batch.rewards.iter().all(|&r| r >= 6.0));
+        assert_eq!(batch.len(), 4);
+    }
+
+    #[test]
+    fn test_priorities_can_be_updated_before_the_first_sample() {
+        // Like a buffer restored from a replay file, whose tree is not built yet.
+        let mut buffer = MemoryBuffer::new(4);
+        for reward in 0..4 {
+            buffer.add_experience(experience(reward as f32));
+        }
+        buffer.prioritized = Some(PrioritizedReplay::new(1.0, 0.5, 0.0));
+        buffer.update_priorities(&[2], &[5.0]);
+
+        let mut batch = Batch::default();
+        buffer.sample_batch_with(4, &mut batch, &mut StdRng::seed_from_u64(0));
+        // The experience holds 5 of the total priority of 8, the others 1 each.
+        let updated = batch.indices.iter().filter(|&&i| i == 2).count();
+        assert!(updated >= 2, "{:?}", batch.indices);
+    }
+}
@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+
+/// How the learning rate changes with the number of steps `t` taken so far,
+/// relative to the base learning rate `η₀` of the network.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LearningRateSchedule {
+    /// `η = η₀`
+    #[default]
+    Constant,
+    /// `η = η₀ · factor^⌊t / step_size⌋`, e.g. halving the rate every 10 epochs.
+    StepDecay { step_size: usize, factor: f64 },
+    /// `η = η₀ · decay^t`
+    Exponential { decay: f64 },
+    /// `η = η_min + (η₀ − η_min) · (1 + cos(π · t / period)) / 2`, which falls from
+    /// `η₀` to `min_rate` over `period` steps and stays there.
+    CosineAnnealing { period: usize, min_rate: f64 },
+    /// `η = η₀ · (t + 1) / warmup_steps` for the first `warmup_steps` steps, then
+    /// the schedule `then`, starting again from `t = 0`.
+    LinearWarmup {
+        warmup_steps: usize,
+        then: Box<LearningRateSchedule>,
+    },
+}
+
+impl LearningRateSchedule {
+    /// The learning rate after `step` steps, for the base learning rate `base_rate`.
+    pub fn rate(&self, base_rate: f64, step: usize) -> f64 {
+        match self {
+            LearningRateSchedule::Constant => base_rate,
+            LearningRateSchedule::StepDecay { step_size, factor } => {
+                base_rate * factor.powi((step / (*step_size).max(1)) as i32)
+            }
+            LearningRateSchedule::Exponential { decay } => base_rate * decay.powi(step as i32),
+            LearningRateSchedule::CosineAnnealing { period, min_rate } => {
+                let progress = step.min(*period) as f64 / (*period).max(1) as f64;
+                let cosine = (1.0 + (std::f64::consts::PI * progress).cos()) / 2.0;
+                min_rate + (base_rate - min_rate) * cosine
+            }
+            LearningRateSchedule::LinearWarmup { warmup_steps, then } => {
+                if step < *warmup_steps {
+                    base_rate * (step + 1) as f64 / *warmup_steps as f64
+                } else {
+                    then.rate(base_rate, step - warmup_steps)
+                }
+            }
+        }
+    }
+}
+
+/// When a schedule takes a step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleInterval {
+    /// After every gradient descent step.
+    #[default]
+    Batch,
+    /// When `NeuralNetwork::end_epoch` is called.
+    Epoch,
+}
+
+/// A schedule together with its progress, saved with the network so that
+/// resumed training continues where it stopped.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LearningRateScheduler {
+    pub schedule: LearningRateSchedule,
+    pub interval: ScheduleInterval,
+    /// Number of steps taken so far.
+    pub steps: usize,
+}
+
+impl LearningRateScheduler {
+    pub fn new(schedule: LearningRateSchedule, interval: ScheduleInterval) -> Self {
+        LearningRateScheduler {
+            schedule,
+            interval,
+            steps: 0,
+        }
+    }
+
+    pub fn rate(&self, base_rate: f64) -> f64 {
+        self.schedule.rate(base_rate, self.steps)
+    }
+
+    /// Takes a step if the schedule is stepped at `interval`.
+    pub fn step(&mut self, interval: ScheduleInterval) {
+        if self.interval == interval {
+            self.steps += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_rates(schedule: LearningRateSchedule, expected: &[(usize, f64)]) {
+        for &(step, rate) in expected {
+            let actual = schedule.rate(0.1, step);
+            assert!(
+                (actual - rate).abs() < 1e-12,
+                "{schedule:?} at {step}: {actual} != {rate}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_schedules() {
+        assert_rates(LearningRateSchedule::Constant, &[(0, 0.1), (1000, 0.1)]);
+        assert_rates(
+            LearningRateSchedule::StepDecay {
+                step_size: 10,
+                factor: 0.5,
+            },
+            &[(0, 0.1), (9, 0.1), (10, 0.05), (25, 0.025)],
+        );
+        assert_rates(
+            LearningRateSchedule::Exponential { decay: 0.9 },
+            &[(0, 0.1), (2, 0.081)],
+        );
+        assert_rates(
+            LearningRateSchedule::CosineAnnealing {
+                period: 100,
+                min_rate: 0.02,
+            },
+            &[(0, 0.1), (50, 0.06), (100, 0.02), (500, 0.02)],
+        );
+        assert_rates(
+            LearningRateSchedule::LinearWarmup {
+                warmup_steps: 4,
+                then: Box::new(LearningRateSchedule::Exponential { decay: 0.5 }),
+            },
+            &[(0, 0.025), (3, 0.1), (4, 0.1), (5, 0.05)],
+        );
+    }
+
+    #[test]
+    fn test_scheduler_steps_at_its_interval() {
+        let mut scheduler = LearningRateScheduler::new(
+            LearningRateSchedule::Exponential { decay: 0.5 },
+            ScheduleInterval::Epoch,
+        );
+        scheduler.step(ScheduleInterval::Batch);
+        assert_eq!(scheduler.rate(1.0), 1.0);
+        scheduler.step(ScheduleInterval::Epoch);
+        assert_eq!(scheduler.rate(1.0), 0.5);
+    }
+}
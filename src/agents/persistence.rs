@@ -0,0 +1,237 @@
+//! Crash-safe saving and loading of agent files.
+//!
+//! A save writes a temporary file next to the destination, syncs it to disk and renames it
+//! over the destination, so that an interrupted save never leaves a truncated model behind.
+//! The file it replaces is kept as `<file>.bak`, and loading falls back to that backup when
+//! the file itself is missing or cannot be read. Files that belong together, like an agent and
+//! its replay file, are saved together, so that their backups are of the same save as well.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::agents::{binary_format::invalid_data, metadata::CompatibilityError};
+
+/// The path of the backup kept for `file_path`.
+pub fn backup_path(file_path: impl AsRef<Path>) -> PathBuf {
+    with_suffix(file_path.as_ref(), ".bak")
+}
+
+/// `file_path` with `suffix` appended, e.g. for a sidecar file saved next to an agent.
+pub fn with_suffix(file_path: impl AsRef<Path>, suffix: &str) -> PathBuf {
+    let file_path = file_path.as_ref();
+    let mut name = OsString::from(file_path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Saves `file_path` with `write`, keeping the previous file as a backup.
+///
+/// Until the final rename, a crash leaves the previous file untouched; between the two
+/// renames only the backup exists, which `load_with_backup` falls back to.
+pub fn save_atomically(
+    file_path: impl AsRef<Path>,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    save_all_atomically(vec![(file_path.as_ref(), Box::new(write) as FileWriter)])
+}
+
+/// Writes one of the files of `save_all_atomically`.
+pub type FileWriter<'a> = Box<dyn FnOnce(&mut BufWriter<File>) -> io::Result<()> + 'a>;
+
+/// Saves several files like `save_atomically`, e.g. an agent and its sidecar files, so that
+/// they stay one generation: every file is written before any of them replaces the previous
+/// one, and a failed write leaves all of them, and their backups, untouched.
+pub fn save_all_atomically(files: Vec<(&Path, FileWriter)>) -> io::Result<()> {
+    let mut written = Vec::with_capacity(files.len());
+    for (file_path, write) in files {
+        match write_temp(file_path, write) {
+            Ok(temp_path) => written.push((file_path, temp_path)),
+            Err(e) => {
+                for (_, temp_path) in written {
+                    let _ = fs::remove_file(temp_path);
+                }
+                return Err(e);
+            }
+        }
+    }
+    for (file_path, temp_path) in written {
+        if file_path.exists() {
+            fs::rename(file_path, backup_path(file_path))?;
+        }
+        fs::rename(&temp_path, file_path)?;
+        sync_dir(parent_dir(file_path))?;
+    }
+    Ok(())
+}
+
+/// Writes and syncs the temporary file that is renamed over `file_path`.
+fn write_temp(file_path: &Path, write: FileWriter) -> io::Result<PathBuf> {
+    fs::create_dir_all(parent_dir(file_path))?;
+    let temp_path = with_suffix(file_path, ".tmp");
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        write(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(temp_path)
+}
+
+fn parent_dir(file_path: &Path) -> &Path {
+    match file_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Makes the renames in `dir` durable. Directories cannot be synced on every platform.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Which file `load_with_backup` loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loaded {
+    File,
+    /// The file could not be loaded and its backup was loaded instead, which callers
+    /// may want to report.
+    Backup,
+}
+
+/// Loads `file_path` with `load`, or its backup if that fails. The error of the file itself
+/// is returned when the backup cannot be loaded either.
+pub fn load_with_backup<T, E>(
+    file_path: impl AsRef<Path>,
+    load: impl Fn(&Path) -> Result<T, E>,
+) -> Result<(T, Loaded), E> {
+    let file_path = file_path.as_ref();
+    match load(file_path) {
+        Ok(value) => Ok((value, Loaded::File)),
+        Err(error) => {
+            let backup = backup_path(file_path);
+            if !backup.exists() {
+                return Err(error);
+            }
+            let value = load(&backup).map_err(|_| error)?;
+            Ok((value, Loaded::Backup))
+        }
+    }
+}
+
+/// Saves `value` as JSON with `save_atomically`.
+pub fn save_json(file_path: impl AsRef<Path>, value: &impl Serialize) -> io::Result<()> {
+    save_atomically(file_path, |file| Ok(serde_json::to_writer(file, value)?))
+}
+
+/// Loads a value saved by `save_json` with `load_with_backup`.
+pub fn load_json<T: DeserializeOwned>(file_path: impl AsRef<Path>) -> io::Result<(T, Loaded)> {
+    load_with_backup(file_path, |path| {
+        let file = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    })
+}
+
+/// Loads an agent saved by `save_json` like `load_json`, failing with `InvalidData` when
+/// `check` finds that it does not fit the environment it is loaded for.
+pub fn load_json_checked<T: DeserializeOwned>(
+    file_path: impl AsRef<Path>,
+    check: impl FnOnce(&T) -> Result<(), CompatibilityError>,
+) -> io::Result<(T, Loaded)> {
+    let (value, loaded) = load_json(file_path)?;
+    check(&value).map_err(|e| invalid_data(e.to_string()))?;
+    Ok((value, loaded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::test_utils::test_dir;
+
+    fn read(path: &Path) -> io::Result<String> {
+        let text = fs::read_to_string(path)?;
+        if text.ends_with('.') {
+            Ok(text)
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "truncated"))
+        }
+    }
+
+    #[test]
+    fn test_saves_keep_a_backup_that_loading_falls_back_to() {
+        let dir = test_dir("persistence");
+        let path = dir.join("nested").join("model.txt");
+        let save = |text: &str| save_atomically(&path, |w| w.write_all(text.as_bytes()));
+
+        save("first.").unwrap();
+        assert!(!backup_path(&path).exists());
+        save("second.").unwrap();
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "first.");
+        assert_eq!(
+            load_with_backup(&path, read).unwrap(),
+            ("second.".to_string(), Loaded::File)
+        );
+
+        // A failed save leaves both files and no temporary file behind.
+        let failed = save_atomically(&path, |_| Err(io::ErrorKind::Other.into()));
+        assert!(failed.is_err());
+        assert_eq!(
+            load_with_backup(&path, read).unwrap(),
+            ("second.".to_string(), Loaded::File)
+        );
+        assert!(!with_suffix(&path, ".tmp").exists());
+
+        // A truncated or missing file falls back to the backup.
+        fs::write(&path, "sec").unwrap();
+        assert_eq!(
+            load_with_backup(&path, read).unwrap(),
+            ("first.".to_string(), Loaded::Backup)
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            load_with_backup(&path, read).unwrap(),
+            ("first.".to_string(), Loaded::Backup)
+        );
+        fs::remove_file(backup_path(&path)).unwrap();
+        let missing = load_with_backup(&path, read).unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_files_saved_together_are_replaced_together() {
+        let dir = test_dir("persistence_all");
+        let (agent, replay) = (dir.join("agent.txt"), dir.join("agent.txt.replay"));
+        let save = |agent_text: &'static str, replay_write: FileWriter| {
+            let write_agent: FileWriter = Box::new(move |w| w.write_all(agent_text.as_bytes()));
+            save_all_atomically(vec![(&agent, write_agent), (&replay, replay_write)])
+        };
+
+        save("first.", Box::new(|w| w.write_all(b"first replay."))).unwrap();
+        // The replay file fails to write, so the agent file is not replaced either.
+        let failed = save("second.", Box::new(|_| Err(io::ErrorKind::Other.into())));
+        assert!(failed.is_err());
+        assert_eq!(fs::read_to_string(&agent).unwrap(), "first.");
+        assert_eq!(fs::read_to_string(&replay).unwrap(), "first replay.");
+        assert!(!backup_path(&agent).exists());
+        assert!(!with_suffix(&agent, ".tmp").exists());
+
+        save("second.", Box::new(|w| w.write_all(b"second replay."))).unwrap();
+        assert_eq!(fs::read_to_string(backup_path(&agent)).unwrap(), "first.");
+        assert_eq!(
+            fs::read_to_string(backup_path(&replay)).unwrap(),
+            "first replay."
+        );
+    }
+}
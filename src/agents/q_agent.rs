@@ -1,8 +1,20 @@
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::{fs::create_dir_all, path::Path, vec};
+use std::{
+    io::{BufReader, Read, Write},
+    path::Path,
+    vec,
+};
 
-use crate::{Action, Agent, Environment, Space, SpaceElem};
+use crate::{
+    agents::{
+        binary_format::{self, invalid_data, AgentKind, Format, Reader, Writer},
+        metadata::{check_spaces, CompatibilityError, ModelMetadata},
+        n_step::{NStepWindow, N_STEPS_DEFAULT},
+        persistence::{load_with_backup, save_atomically, save_json, Loaded},
+    },
+    Action, Agent, Environment, Space, SpaceElem, State,
+};
 
 /// The Agent struct represents the agent that is going to interact and learn from the environment.
 /// It contains methods for learning and acting with the environment and useful utils such as loading and saving Q-tables.
@@ -12,26 +24,50 @@ pub struct QAgent {
     pub q_table: Vec<f32>,
     /// Epsilon-greedy parameters for exploration vs exploitation ε where (0 ≤ ε ≤ 1)
     /// A higher epsilon means more exploration, while a lower epsilon means more exploitation.
-    epsilon: f32,
+    pub(crate) epsilon: f32,
     /// Learning rate α where (0 < α ≤ 1)
     /// A higher alpha means the agent learns more quickly from new information.
-    alpha: f32,
+    pub(crate) alpha: f32,
     /// Discount factor 𝛾 for future rewards where (0 ≤ γ < 1)
     /// A higher gamma means the agent values future rewards more.
-    gamma: f32,
+    pub(crate) gamma: f32,
     /// State space
-    state_space: Vec<usize>,
+    pub(crate) state_space: Vec<usize>,
     /// State space size
-    state_space_size: usize,
+    pub(crate) state_space_size: usize,
     /// Action space
-    action_space: Vec<usize>,
+    pub(crate) action_space: Vec<usize>,
     /// Action space size
-    action_space_size: usize,
+    pub(crate) action_space_size: usize,
+    /// Number of rewards summed before bootstrapping from the Q-table, n ≥ 1.
+    /// n = 1 gives the one-step Q-learning update.
+    #[serde(default = "default_n_steps")]
+    n_steps: usize,
+    /// Where and how the agent was trained, checked by `load_for`.
+    #[serde(default)]
+    pub metadata: ModelMetadata,
+    /// The transitions that are not yet n steps old, one window per player.
+    #[serde(skip)]
+    windows: Vec<NStepWindow<(usize, usize)>>,
+    /// Draws the exploratory actions, seeded by `Agent::seed`.
+    #[serde(skip, default = "exploration_rng")]
+    pub(crate) rng: StdRng,
+}
+
+fn default_n_steps() -> usize {
+    N_STEPS_DEFAULT
+}
+
+/// An unseeded generator for the exploration of agents that are not seeded.
+pub(crate) fn exploration_rng() -> StdRng {
+    StdRng::from_rng(&mut rand::rng())
 }
 
 pub const EPSILON_DEFAULT: f32 = 0.05;
 pub const ALPHA_DEFAULT: f32 = 0.1;
 pub const GAMMA_DEFAULT: f32 = 0.9;
+/// The agent kind recorded in the metadata.
+const AGENT_KIND: &str = "QAgent";
 
 impl Default for QAgent {
     fn default() -> Self {
@@ -52,25 +88,119 @@ impl QAgent {
             state_space_size: 0,
             action_space: Vec::new(),
             action_space_size: 0,
+            n_steps: N_STEPS_DEFAULT,
+            metadata: ModelMetadata::default(),
+            windows: Vec::new(),
+            rng: exploration_rng(),
         }
     }
 
+    /// Uses n-step returns instead of one-step updates.
+    pub fn with_n_steps(mut self, n_steps: usize) -> Self {
+        assert!(n_steps > 0, "n-step returns need at least one step");
+        self.n_steps = n_steps;
+        self.windows.clear();
+        self
+    }
+
+    pub fn n_steps(&self) -> usize {
+        self.n_steps
+    }
+
+    /// Saves the agent as JSON, see `save_binary` for the compact format.
+    /// The save is atomic and keeps the previous file as a backup, see [`persistence`](super::persistence).
     pub fn save_to_file(&self, file_path: impl AsRef<Path>) -> std::io::Result<()> {
-        create_dir_all(file_path.as_ref().parent().unwrap())?;
-        let mut file = std::fs::File::create(file_path)?;
-        serde_json::to_writer(&mut file, &self)?;
-        Ok(())
+        save_json(file_path, self)
+    }
+
+    /// Saves the agent in the binary format of [`binary_format`], with the Q-table
+    /// as little-endian `f32`s.
+    pub fn save_binary(&self, file_path: impl AsRef<Path>) -> std::io::Result<()> {
+        save_atomically(file_path, |file| self.write_binary(&mut Writer::new(file)))
     }
 
+    /// Loads an agent saved by `save_binary` or by `save_to_file`, whichever format the file has.
+    /// Falls back to the backup of the previous save if the file cannot be loaded.
     pub fn load_from_file(file_path: impl AsRef<Path>) -> Result<Self, std::io::Error>
     where
         Self: Sized,
     {
-        let file = std::fs::File::open(file_path)?;
-        Ok(serde_json::from_reader(file)?)
+        Self::load(file_path).map(|(agent, _)| agent)
+    }
+
+    fn load(file_path: impl AsRef<Path>) -> Result<(Self, Loaded), std::io::Error> {
+        load_with_backup(file_path, |path| {
+            let mut file = BufReader::new(std::fs::File::open(path)?);
+            match binary_format::detect(&mut file)? {
+                Format::Binary => Self::read_binary(&mut Reader::new(file)),
+                Format::Json => Ok(serde_json::from_reader(file)?),
+            }
+        })
+    }
+
+    /// Loads an agent like `load_from_file` and checks that it was trained for an environment
+    /// like `env`, failing with `InvalidData` otherwise. Also returns whether the backup was
+    /// loaded, for the caller to report.
+    pub fn load_for<E: Environment>(
+        file_path: impl AsRef<Path>,
+        env: &E,
+    ) -> Result<(Self, Loaded), std::io::Error> {
+        let (agent, loaded) = Self::load(file_path)?;
+        agent
+            .check_compatible(env)
+            .map_err(|e| invalid_data(e.to_string()))?;
+        Ok((agent, loaded))
+    }
+
+    /// Checks the metadata and the spaces of the agent against `env`.
+    pub fn check_compatible<E: Environment>(&self, env: &E) -> Result<(), CompatibilityError> {
+        self.metadata.check(AGENT_KIND, env)?;
+        check_spaces(env, &self.state_space, &[], &self.action_space)
+    }
+
+    fn write_binary(&self, writer: &mut Writer<impl Write>) -> std::io::Result<()> {
+        writer.header(AgentKind::Q, &self.metadata)?;
+        writer.usizes(&self.state_space)?;
+        writer.usizes(&self.action_space)?;
+        writer.f32(self.epsilon)?;
+        writer.f32(self.alpha)?;
+        writer.f32(self.gamma)?;
+        writer.usize(self.n_steps)?;
+        writer.f32s(&self.q_table)
     }
 
-    fn space_elem_as_int<El: SpaceElem>(elem: &El, state_space: &[usize]) -> usize {
+    fn read_binary(reader: &mut Reader<impl Read>) -> std::io::Result<Self> {
+        let header = reader.header(AgentKind::Q)?;
+        let state_space = reader.usizes()?;
+        let action_space = reader.usizes()?;
+        let (epsilon, alpha, gamma) = (reader.f32()?, reader.f32()?, reader.f32()?);
+        let n_steps = reader.usize()?;
+        let q_table = reader.f32s()?;
+        let state_space_size = state_space.iter().product();
+        let action_space_size = action_space.iter().product();
+        if q_table.len() != state_space_size * action_space_size {
+            return Err(invalid_data(format!(
+                "the Q-table has {} values, but the spaces have {state_space_size} states and {action_space_size} actions",
+                q_table.len()
+            )));
+        }
+        Ok(QAgent {
+            q_table,
+            epsilon,
+            alpha,
+            gamma,
+            state_space,
+            state_space_size,
+            action_space,
+            action_space_size,
+            n_steps: n_steps.max(1),
+            metadata: header.metadata,
+            windows: Vec::new(),
+            rng: exploration_rng(),
+        })
+    }
+
+    pub(crate) fn space_elem_as_int<El: SpaceElem>(elem: &El, state_space: &[usize]) -> usize {
         let mut state_i = 0;
         for (d, size) in state_space.iter().enumerate() {
             state_i = state_i * size + elem.discrete(d).unwrap();
@@ -78,10 +208,53 @@ impl QAgent {
         state_i
     }
 
-    fn q_val_mut(&mut self, state: &impl SpaceElem, action: &impl Action) -> &mut f32 {
-        let state_i = Self::space_elem_as_int(state, &self.state_space);
-        let action_i = Self::space_elem_as_int(action, &self.action_space);
-        &mut self.q_table[state_i * self.action_space_size + action_i]
+    /// Index of `state` in the flattened state space.
+    pub(crate) fn state_index(&self, state: &impl SpaceElem) -> usize {
+        Self::space_elem_as_int(state, &self.state_space)
+    }
+
+    /// Index of `action` in the flattened action space.
+    pub(crate) fn action_index(&self, action: &impl SpaceElem) -> usize {
+        Self::space_elem_as_int(action, &self.action_space)
+    }
+
+    /// Builds the action at index `action_i` of the flattened action space.
+    pub(crate) fn action_from_index<A: Action>(&self, mut action_i: usize) -> A {
+        let mut discrete = vec![0; self.action_space.len()];
+        for d in (0..self.action_space.len()).rev() {
+            discrete[d] = action_i % self.action_space[d];
+            action_i /= self.action_space[d];
+        }
+        A::try_build(&self.action_space.as_slice(), &discrete, &[]).unwrap()
+    }
+
+    /// Index of the action with the highest Q-value in the given state.
+    /// Ties are resolved in favour of the first action, like `predict`.
+    pub(crate) fn best_action_index(&self, state_i: usize) -> usize {
+        let row = &self.q_table[state_i * self.action_space_size..][..self.action_space_size];
+        let mut best_action = 0;
+        let mut best_value = f32::MIN;
+        for (action_i, &q_value) in row.iter().enumerate() {
+            if q_value > best_value {
+                best_value = q_value;
+                best_action = action_i;
+            }
+        }
+        best_action
+    }
+
+    /// The highest Q-value in the given state.
+    pub(crate) fn max_q(&self, state_i: usize) -> f32 {
+        self.q_table[state_i * self.action_space_size + self.best_action_index(state_i)]
+    }
+
+    /// Picks a random action with probability ε, otherwise the greedy one.
+    pub(crate) fn epsilon_greedy_index(&mut self, state_i: usize) -> usize {
+        if self.rng.random::<f32>() < self.epsilon {
+            self.rng.random_range(0..self.action_space_size)
+        } else {
+            self.best_action_index(state_i)
+        }
     }
 
     fn q_val(&self, state: &impl SpaceElem, action: &impl Action) -> f32 {
@@ -90,11 +263,17 @@ impl QAgent {
         self.q_table[state_i * self.action_space_size + action_i]
     }
 
+    /// Moves `Q(s, a)` towards `target` by the learning rate.
+    pub(crate) fn update(&mut self, state_i: usize, action_i: usize, target: f32) {
+        let q = &mut self.q_table[state_i * self.action_space_size + action_i];
+        *q += self.alpha * (target - *q);
+    }
+
     pub fn predict_all<E: Environment>(&self) -> Vec<(E::State, E::Action)> {
         let mut predictions = vec![];
         for state in all_elems_as_vec(&self.state_space) {
             let state = E::State::try_build(&self.state_space.as_slice(), &state, &[]).unwrap();
-            let prediction = <QAgent as Agent<E>>::predict(&self, &state);
+            let prediction = <QAgent as Agent<E>>::predict(self, &state);
             predictions.push((state, prediction));
         }
         predictions
@@ -107,7 +286,7 @@ impl QAgent {
     }
 }
 
-fn all_elems_as_vec(space: &[usize]) -> impl Iterator<Item = Vec<usize>> + '_ {
+pub(crate) fn all_elems_as_vec(space: &[usize]) -> impl Iterator<Item = Vec<usize>> + '_ {
     let mut indices = vec![0; space.len()];
     let max_indices: Vec<usize> = space.iter().map(|&s| s - 1).collect();
     std::iter::once(vec![0; space.len()]).chain(std::iter::from_fn(move || {
@@ -171,14 +350,15 @@ impl<E: Environment> Agent<E> for QAgent {
         }
         self.action_space_size = action_space_size;
         self.q_table = vec![0.0; state_space_size * action_space_size];
+        self.metadata = ModelMetadata::new(AGENT_KIND, env);
+        self.windows.clear();
         true
     }
 
     fn act(&mut self, state: &E::State) -> E::Action {
-        let mut rng = rand::rng();
-        if rng.random::<f32>() < self.epsilon {
+        if self.rng.random::<f32>() < self.epsilon {
             // Exploration: choose a random action
-            E::Action::gen_random(&&*self.action_space).unwrap()
+            E::Action::gen_random_with(&&*self.action_space, &mut self.rng).unwrap()
         } else {
             // Exploitation: choose the best action based on Q-values
             let mut best_action = E::Action::default();
@@ -206,6 +386,16 @@ impl<E: Environment> Agent<E> for QAgent {
     /// - **α** is the learning rate,
     /// - **γ** is the discount factor.
     ///
+    /// With n-step returns the update of `(s, a)` is delayed until `n` rewards
+    /// have been collected, and bootstraps from the state reached after them:
+    ///
+    /// ```math
+    /// Q(s, a) ← Q(s, a) + α · (r₀ + … + γⁿ⁻¹ · rₙ₋₁ + γⁿ · maxₐ' Q(sₙ, a') − Q(s, a))
+    /// ```
+    ///
+    /// When the episode ends (`next_state` is `None`) the remaining transitions
+    /// are updated with the rewards up to the end of the episode.
+    ///
     /// **Parameters:**
     ///
     /// - `state`: The current state, as a tuple `(usize, usize)`.
@@ -219,21 +409,28 @@ impl<E: Environment> Agent<E> for QAgent {
         reward: f32,
         next_state: Option<&E::State>,
     ) {
-        let mut max_q_next = f32::MIN;
+        let transition = (self.state_index(state), self.action_index(action));
+        // Players are kept apart, so that an agent playing both sides of a game
+        // does not mix the rewards of the two.
+        let player = state.current_player();
+        if self.windows.len() <= player {
+            let (n_steps, gamma) = (self.n_steps, self.gamma);
+            self.windows
+                .resize_with(player + 1, || NStepWindow::new(n_steps, gamma));
+        }
+        let window = &mut self.windows[player];
         if let Some(next_state) = next_state {
-            for a in all_actions::<E::Action>(&self.action_space) {
-                let q_val = self.q_val(next_state, &a);
-                if q_val > max_q_next {
-                    max_q_next = q_val;
-                }
+            if let Some(((state_i, action_i), ret)) = window.push(transition, reward) {
+                let discount = window.bootstrap_discount();
+                let max_q_next = self.max_q(self.state_index(next_state));
+                self.update(state_i, action_i, ret + discount * max_q_next);
             }
         } else {
-            // If there is no next state it is terminal, so we set max_q_next to 0
-            max_q_next = 0.0;
+            // If there is no next state it is terminal, so nothing is bootstrapped
+            for ((state_i, action_i), ret) in window.finish(transition, reward) {
+                self.update(state_i, action_i, ret);
+            }
         }
-
-        *self.q_val_mut(state, action) +=
-            self.alpha * (reward + self.gamma * max_q_next - self.q_val(state, action));
     }
 
     fn predict(&self, state: &E::State) -> E::Action {
@@ -248,4 +445,119 @@ impl<E: Environment> Agent<E> for QAgent {
         }
         best_action
     }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+}
+
+#[test]
+fn test_n_step_update_reaches_back_n_states() {
+    use crate::{
+        agents::test_utils::{cell, init_grid},
+        environment::move_to_center::{GridEnvironment, MoveAction},
+    };
+    let mut agent = init_grid(QAgent::new().with_n_steps(3));
+    let path = [cell(0, 2), cell(1, 2)];
+    <QAgent as Agent<GridEnvironment>>::learn(
+        &mut agent,
+        &path[0],
+        &MoveAction::Down,
+        0.0,
+        Some(&path[1]),
+    );
+    // Nothing is updated until three rewards are collected or the episode ends.
+    assert!(agent.q_table.iter().all(|&q| q == 0.0));
+    <QAgent as Agent<GridEnvironment>>::learn(&mut agent, &path[1], &MoveAction::Down, 100.0, None);
+    // α · γ · 100
+    assert!((agent.q_val(&path[0], &MoveAction::Down) - 9.0).abs() < 1e-4);
+    assert!((agent.q_val(&path[1], &MoveAction::Down) - 10.0).abs() < 1e-4);
+}
+
+#[test]
+fn test_binary_file_round_trips_and_json_still_loads() {
+    use crate::{
+        agents::test_utils::{cell, test_dir},
+        environment::move_to_center::{GridEnvironment, MoveAction},
+    };
+    let env = GridEnvironment::new(3, 3);
+    let mut agent = QAgent::new().with_n_steps(2);
+    <QAgent as Agent<GridEnvironment>>::try_init(&mut agent, &env);
+    agent.metadata.record_training(100, Some(7));
+    agent
+        .metadata
+        .evaluation
+        .insert("agreement".to_string(), 0.75);
+    for (i, q) in agent.q_table.iter_mut().enumerate() {
+        *q = i as f32 * 0.25 - 3.0;
+    }
+    let dir = test_dir("q_agent");
+    let (binary, json) = (dir.join("grid.bin"), dir.join("grid.json"));
+    agent.save_binary(&binary).unwrap();
+    agent.save_to_file(&json).unwrap();
+    assert!(std::fs::metadata(&binary).unwrap().len() < std::fs::metadata(&json).unwrap().len());
+
+    for path in [&binary, &json] {
+        let loaded = QAgent::load_from_file(path).unwrap();
+        assert_eq!(loaded.q_table, agent.q_table);
+        assert_eq!(loaded.n_steps(), 2);
+        assert_eq!(loaded.metadata, agent.metadata);
+        let board = cell(0, 1);
+        assert_eq!(
+            loaded.q_val(&board, &MoveAction::Down),
+            agent.q_val(&board, &MoveAction::Down)
+        );
+    }
+    // A truncated file is an error, not a smaller Q-table.
+    let bytes = std::fs::read(&binary).unwrap();
+    std::fs::write(&binary, &bytes[..bytes.len() - 4]).unwrap();
+    assert!(QAgent::load_from_file(&binary).is_err());
+}
+
+#[test]
+fn test_seeded_agents_explore_alike() {
+    use crate::{
+        agents::test_utils::{cell, init_grid},
+        environment::move_to_center::GridEnvironment,
+    };
+    let explore = |seed| {
+        let mut agent = init_grid(QAgent::new());
+        agent.epsilon = 1.0;
+        <QAgent as Agent<GridEnvironment>>::seed(&mut agent, seed);
+        (0..20)
+            .map(|_| <QAgent as Agent<GridEnvironment>>::act(&mut agent, &cell(0, 0)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(explore(1), explore(1));
+    assert_ne!(explore(1), explore(2));
+}
+
+#[test]
+fn test_load_for_rejects_other_environments() {
+    use crate::{
+        agents::test_utils::test_dir,
+        environment::{move_to_center::GridEnvironment, tic_tac_toe::TicTacEnvironment},
+    };
+    let env = GridEnvironment::new(3, 3);
+    let mut agent = QAgent::new();
+    <QAgent as Agent<GridEnvironment>>::try_init(&mut agent, &env);
+    let dir = test_dir("q_agent_for");
+    let path = dir.join("grid.json");
+    agent.save_to_file(&path).unwrap();
+
+    assert!(QAgent::load_for(&path, &env).is_ok());
+    let error = QAgent::load_for(&path, &TicTacEnvironment::new()).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("move_to_center"));
+    assert!(QAgent::load_for(&path, &GridEnvironment::new(3, 4)).is_err());
+    // Without metadata the spaces still have to match.
+    agent.metadata = ModelMetadata::default();
+    agent.save_to_file(&path).unwrap();
+    assert!(QAgent::load_for(&path, &env).is_ok());
+    assert!(matches!(
+        QAgent::load_from_file(&path)
+            .unwrap()
+            .check_compatible(&TicTacEnvironment::new()),
+        Err(CompatibilityError::StateSpace { .. })
+    ));
 }
@@ -4,6 +4,12 @@ pub struct RandomAgent<E: Environment> {
     action_space: <E as Environment>::ActionSpace,
 }
 
+impl<E: Environment> Default for RandomAgent<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<E: Environment> RandomAgent<E> {
     pub fn new() -> Self {
         Self {
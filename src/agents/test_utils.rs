@@ -0,0 +1,53 @@
+//! Fixtures shared by the tests of the agents.
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    environment::move_to_center::{Board, GridEnvironment},
+    Agent,
+};
+
+/// The state of the grid with the agent at `(row, col)`.
+pub(crate) fn cell(row: usize, col: usize) -> Board {
+    Board {
+        position: (row, col),
+        done: false,
+    }
+}
+
+/// Initializes `agent` for a 5x5 grid, whose center is `cell(2, 2)`.
+pub(crate) fn init_grid<A: Agent<GridEnvironment>>(mut agent: A) -> A {
+    assert!(agent.try_init(&GridEnvironment::new(5, 5)));
+    agent
+}
+
+/// A temporary directory for the files of a test, removed when it is dropped, also when
+/// the test fails.
+pub(crate) struct TestDir(PathBuf);
+
+/// Creates an empty temporary directory for the test `name`.
+pub(crate) fn test_dir(name: &str) -> TestDir {
+    let path = std::env::temp_dir().join(format!("rust_rl_{name}_{}", std::process::id()));
+    // Left behind by a run that was killed.
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    TestDir(path)
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,5 +1,6 @@
 use plotters::prelude::*;
 use rust_rl::agents::network::nn::{self, NeuralNetwork};
+use rust_rl::callback::ProgressBarCallback;
 
 fn main() {
     let mut network = NeuralNetwork::new(
@@ -17,7 +18,8 @@ fn main() {
     // Output
     let output_data = sin(&input_data);
 
-    network.train(standardize_input(&input_data), output_data);
+    let mut progress = ProgressBarCallback::batches(input_data.len() as u64, "Training..");
+    network.train(standardize_input(&input_data), output_data, &mut progress);
 
     plot_loss(network.get_history()).expect("Failed to plot loss");
     // println!("{:?}", {network.get_history()});
@@ -7,12 +7,16 @@ use actix_web::{
     App, HttpResponse, HttpResponseBuilder, HttpServer, Responder,
 };
 use rust_rl::{
-    agents::{dqn_agent::DQNAgent, q_agent::QAgent},
+    agents::{
+        dqn_agent::DQNAgent,
+        persistence::{backup_path, Loaded},
+        q_agent::QAgent,
+    },
     environment::{
         move_to_center::{self, GridEnvironment},
         tic_tac_toe::{self, TicTacEnvironment},
     },
-    Agent, Environment, DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH, GRID_AGENT_SAVE_FILE_PATH,
+    Agent, Environment, DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH, GRID_AGENT_SAVE_FILE_PATH, GRID_SIZE,
     TIC_TAC_TOE_AGENT_SAVE_FILE_PATH,
 };
 use serde::Deserialize;
@@ -23,15 +27,35 @@ struct AppState {
     tic_tac_toe_dqn_agent: DQNAgent,
 }
 
+/// Warns when the backup of an agent file was loaded because the file itself could not be.
+fn report_backup<T>(file_path: &str, (agent, loaded): (T, Loaded)) -> T {
+    if loaded == Loaded::Backup {
+        eprintln!(
+            "Could not load {file_path}, loaded the backup {} instead",
+            backup_path(file_path).display()
+        );
+    }
+    agent
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // Train the agent or load a saved Q-table.
-    let grid_agent = QAgent::load_from_file(GRID_AGENT_SAVE_FILE_PATH)
+    // Load the saved agents, checking that each was trained for the environment it serves.
+    let grid = GridEnvironment::new(GRID_SIZE.0, GRID_SIZE.1);
+    let grid_agent = QAgent::load_for(GRID_AGENT_SAVE_FILE_PATH, &grid)
+        .map(|loaded| report_backup(GRID_AGENT_SAVE_FILE_PATH, loaded))
         .expect("Failed to load Agent with Q-table");
-    let tic_tac_toe_agent = QAgent::load_from_file(TIC_TAC_TOE_AGENT_SAVE_FILE_PATH)
-        .expect("Failed to load TicTacToe Agent with Q-table");
-    let tic_tac_toe_dqn_agent = DQNAgent::load_from_file(DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH)
-        .expect("Failed to load DQN TicTacToe Agent with Q-table");
+    let tic_tac_toe_agent =
+        QAgent::load_for(TIC_TAC_TOE_AGENT_SAVE_FILE_PATH, &TicTacEnvironment::new())
+            .map(|loaded| report_backup(TIC_TAC_TOE_AGENT_SAVE_FILE_PATH, loaded))
+            .expect("Failed to load TicTacToe Agent with Q-table");
+    let mut tic_tac_toe_dqn_agent = DQNAgent::load_for(
+        DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH,
+        &TicTacEnvironment::new(),
+    )
+    .map(|loaded| report_backup(DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH, loaded))
+    .expect("Failed to load DQN TicTacToe Agent with Q-table");
+    tic_tac_toe_dqn_agent.set_threads(std::thread::available_parallelism().map_or(1, usize::from));
     let app_state = AppState {
         grid_agent,
         tic_tac_toe_agent,
@@ -100,17 +124,17 @@ async fn predict(
     };
     match env {
         EnvironmentType::TicTacToe => {
-            let obj = serde_json::from_str::<tic_tac_toe::Board>(&state).unwrap();
+            let obj = serde_json::from_str::<tic_tac_toe::Board>(state).unwrap();
             let res = <QAgent as Agent<TicTacEnvironment>>::predict(&agent.tic_tac_toe_agent, &obj);
             HttpResponse::Ok().json(res)
         }
         EnvironmentType::Grid => {
-            let obj = serde_json::from_str::<move_to_center::Board>(&state).unwrap();
+            let obj = serde_json::from_str::<move_to_center::Board>(state).unwrap();
             let res = <QAgent as Agent<GridEnvironment>>::predict(&agent.grid_agent, &obj);
             HttpResponse::Ok().json(res)
         }
         EnvironmentType::TicTacDQN => {
-            let obj = serde_json::from_str::<tic_tac_toe::Board>(&state).unwrap();
+            let obj = serde_json::from_str::<tic_tac_toe::Board>(state).unwrap();
             let res =
                 <DQNAgent as Agent<TicTacEnvironment>>::predict(&agent.tic_tac_toe_dqn_agent, &obj);
             HttpResponse::Ok().json(res)
@@ -1,62 +1,100 @@
 use std::{cell::RefCell, env::args, rc::Rc, time::Instant};
 
 use rust_rl::{
-    agents::{dqn_agent::DQNAgent, q_agent::QAgent},
-    environment::{move_to_center::GridEnvironment, tic_tac_toe::TicTacEnvironment},
-    train, Agent, DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH, GRID_AGENT_SAVE_FILE_PATH,
+    agents::{
+        dp_solver::DpSolver,
+        dqn_agent::DQNAgent,
+        dyna_agent::DynaQAgent,
+        network::{
+            builder::LayerSpec,
+            hindsight::{GoalStrategy, HindsightReplay},
+            nn::ActivationFunction,
+        },
+        q_agent::QAgent,
+    },
+    callback::{ProgressBarCallback, TrainingCallback},
+    environment::{
+        move_to_center::GridEnvironment,
+        reach_goal::{GoalGridEnvironment, GOAL_REWARD},
+        tic_tac_toe::TicTacEnvironment,
+    },
+    train, Agent, Environment, DQN_REACH_GOAL_AGENT_SAVE_FILE_PATH,
+    DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH, GRID_AGENT_SAVE_FILE_PATH, GRID_SIZE,
     TIC_TAC_TOE_AGENT_SAVE_FILE_PATH,
 };
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::MultiProgress;
 
-const GRID_SIZE: (usize, usize) = (9, 9);
 const EPISODES: u64 = 1_000_000;
+const DQN_BUFFER_CAPACITY: usize = 10_000;
+/// Dyna-Q plans with its model, so it needs far fewer real episodes.
+const DYNA_EPISODES: u64 = 10_000;
+const DYNA_PLANNING_STEPS: usize = 20;
+/// Hindsight relabeling makes every episode informative, despite the sparse rewards.
+const REACH_GOAL_EPISODES: u64 = 5_000;
+/// Explores more than the default, as the goals change every episode.
+const REACH_GOAL_EPSILON: f32 = 0.2;
+/// Compares trained grid agents with the optimal policy, which takes a solve of the grid.
+const REPORT_FLAG: &str = "--report";
+/// Seeds the exploration of the agents, followed by the seed, e.g. `--seed 7`.
+const SEED_FLAG: &str = "--seed";
 fn main() {
     let a = args().nth(1).unwrap_or_else(|| "0".to_string());
+    let report = args().skip(2).any(|arg| arg == REPORT_FLAG);
+    let seed = args()
+        .skip_while(|arg| arg != SEED_FLAG)
+        .nth(1)
+        .map(|seed| seed.parse().expect("The seed must be a number"));
     let start = Instant::now();
-    let sty: ProgressStyle = ProgressStyle::with_template(
-        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-    )
-    .unwrap()
-    .progress_chars("#>-");
-    let pb = ProgressBar::new(EPISODES);
-    pb.set_style(sty.clone());
     match a.as_str() {
         "grid" => {
-            pb.set_message("Training Grid Agent");
-            train_grid_agent(EPISODES, pb);
+            let mut progress = ProgressBarCallback::episodes(EPISODES, "Training Grid Agent");
+            train_grid_agent(EPISODES, seed, &mut progress, report);
+        }
+        "grid-dp" => {
+            solve_grid();
+        }
+        "grid-dyna" => {
+            let mut progress =
+                ProgressBarCallback::episodes(DYNA_EPISODES, "Training Dyna-Q Grid Agent");
+            train_grid_dyna_agent(DYNA_EPISODES, seed, &mut progress, report);
         }
         "tic-tac-toe" => {
-            pb.set_message("Training Tic Tac Toe Agent");
-            train_tic_tac_toe_agent(EPISODES, pb);
+            let mut progress =
+                ProgressBarCallback::episodes(EPISODES, "Training Tic Tac Toe Agent");
+            train_tic_tac_toe_agent(EPISODES, seed, &mut progress);
+        }
+        "dqn-tic-tac-toe" => {
+            let mut progress =
+                ProgressBarCallback::episodes(EPISODES, "Training DQN Tic Tac Toe Agent");
+            train_dqn_tic_tac_toe_agent(EPISODES, seed, &mut progress);
+        }
+        "dqn-reach-goal" => {
+            let mut progress =
+                ProgressBarCallback::episodes(REACH_GOAL_EPISODES, "Training DQN Reach Goal Agent");
+            train_dqn_reach_goal_agent(REACH_GOAL_EPISODES, seed, &mut progress);
         }
-        // "dqn-tic-tac-toe" => {
-        //     pb.set_message("Training DQN Tic Tac Toe Agent");
-        //     train_dqn_tic_tac_toe_agent(EPISODES, pb);
-        // }
         _ => {
             println!("training all agents");
             let m: MultiProgress = MultiProgress::new();
 
-            let pb = m.add(ProgressBar::new(EPISODES));
-            pb.set_style(sty.clone());
-            pb.set_message("Training Grid Agent");
-
-            let pb2 = m.add(ProgressBar::new(EPISODES));
-            pb2.set_style(sty.clone());
-            pb2.set_message("Training Tic Tac Toe Agent");
-
-            let pb3 = m.add(ProgressBar::new(EPISODES));
-            pb3.set_style(sty);
-            pb3.set_message("Training DQN Tic Tac Toe Agent");
+            let mut grid =
+                ProgressBarCallback::episodes(EPISODES, "Training Grid Agent").add_to(&m);
+            let mut tic_tac_toe =
+                ProgressBarCallback::episodes(EPISODES, "Training Tic Tac Toe Agent").add_to(&m);
+            let _dqn_tic_tac_toe =
+                ProgressBarCallback::episodes(EPISODES, "Training DQN Tic Tac Toe Agent")
+                    .add_to(&m);
 
             let mut threads = vec![];
-            threads.push(std::thread::spawn(|| train_grid_agent(EPISODES, pb)));
-            threads.push(std::thread::spawn(|| {
-                train_tic_tac_toe_agent(EPISODES, pb2)
+            threads.push(std::thread::spawn(move || {
+                train_grid_agent(EPISODES, seed, &mut grid, report)
             }));
-            // threads.push(std::thread::spawn(|| {
-            //     train_dqn_tic_tac_toe_agent(EPISODES, pb3)
+            threads.push(std::thread::spawn(move || {
+                train_tic_tac_toe_agent(EPISODES, seed, &mut tic_tac_toe)
+            }));
+            // threads.push(std::thread::spawn(move || {
+            //     train_dqn_tic_tac_toe_agent(EPISODES, seed, &mut _dqn_tic_tac_toe)
             // }));
             for thread in threads {
                 thread.join().expect("Thread panicked");
@@ -71,7 +109,12 @@ fn main() {
     );
 }
 
-fn train_grid_agent(episodes: u64, pb: ProgressBar) {
+fn train_grid_agent(
+    episodes: u64,
+    seed: Option<u64>,
+    callback: &mut dyn TrainingCallback,
+    report: bool,
+) {
     let mut env = GridEnvironment::new(GRID_SIZE.0, GRID_SIZE.1);
     let agent = Rc::new(RefCell::new(QAgent::new()));
     agent.borrow_mut().try_init(&env);
@@ -80,15 +123,79 @@ fn train_grid_agent(episodes: u64, pb: ProgressBar) {
         &mut env,
         &agents as &[Rc<RefCell<dyn Agent<GridEnvironment>>>],
         episodes,
-        pb,
+        seed,
+        callback,
     );
+    let mut agent = agent.borrow_mut();
+    agent.metadata.record_training(episodes, seed);
+    if report {
+        report_grid_policy(&env, &mut agent);
+    }
     agent
-        .borrow()
         .save_to_file(GRID_AGENT_SAVE_FILE_PATH)
         .expect("Failed to save Q-table to file");
 }
 
-fn train_tic_tac_toe_agent(episodes: u64, pb: ProgressBar) {
+/// Saves the exact optimal grid policy, found with value iteration, in place of a learned one.
+fn solve_grid() {
+    let env = GridEnvironment::new(GRID_SIZE.0, GRID_SIZE.1);
+    let solution = DpSolver::default().value_iteration(&env);
+    println!(
+        "Value iteration converged after {} sweeps",
+        solution.iterations
+    );
+    solution
+        .agent
+        .save_to_file(GRID_AGENT_SAVE_FILE_PATH)
+        .expect("Failed to save Q-table to file");
+}
+
+/// Prints how close the greedy policy of a trained grid agent is to the optimal policy,
+/// and records it in the metadata of the agent.
+fn report_grid_policy(env: &GridEnvironment, agent: &mut QAgent) {
+    let comparison = DpSolver::default().value_iteration(env).compare(agent);
+    agent.metadata.evaluation.extend([
+        ("agreement".to_string(), comparison.agreement as f64),
+        ("mean_regret".to_string(), comparison.mean_regret as f64),
+        ("max_regret".to_string(), comparison.max_regret as f64),
+    ]);
+    println!(
+        "Grid policy matches the optimal policy in {:.1}% of states (mean regret {:.3}, max regret {:.3})",
+        comparison.agreement * 100.0,
+        comparison.mean_regret,
+        comparison.max_regret
+    );
+}
+
+fn train_grid_dyna_agent(
+    episodes: u64,
+    seed: Option<u64>,
+    callback: &mut dyn TrainingCallback,
+    report: bool,
+) {
+    let mut env = GridEnvironment::new(GRID_SIZE.0, GRID_SIZE.1);
+    let agent = Rc::new(RefCell::new(DynaQAgent::new(DYNA_PLANNING_STEPS)));
+    agent.borrow_mut().try_init(&env);
+    let agents = [agent.clone() as Rc<RefCell<dyn Agent<GridEnvironment>>>];
+    train::train_q(
+        &mut env,
+        &agents as &[Rc<RefCell<dyn Agent<GridEnvironment>>>],
+        episodes,
+        seed,
+        callback,
+    );
+    let mut agent = agent.borrow_mut();
+    agent.q.metadata.record_training(episodes, seed);
+    if report {
+        report_grid_policy(&env, &mut agent.q);
+    }
+    // The saved Q-table loads as a plain QAgent, so the server can serve it.
+    agent
+        .save_to_file(GRID_AGENT_SAVE_FILE_PATH)
+        .expect("Failed to save Q-table to file");
+}
+
+fn train_tic_tac_toe_agent(episodes: u64, seed: Option<u64>, callback: &mut dyn TrainingCallback) {
     let mut env = TicTacEnvironment::new();
     let agent = Rc::new(RefCell::new(QAgent::new()));
     agent.borrow_mut().try_init(&env);
@@ -100,30 +207,93 @@ fn train_tic_tac_toe_agent(episodes: u64, pb: ProgressBar) {
         &mut env,
         &agents as &[Rc<RefCell<dyn Agent<TicTacEnvironment>>>],
         episodes,
-        pb,
+        seed,
+        callback,
     );
+    let mut agent = agent.borrow_mut();
+    agent.metadata.record_training(episodes, seed);
     agent
-        .borrow()
         .save_to_file(TIC_TAC_TOE_AGENT_SAVE_FILE_PATH)
         .expect("Failed to save Q-table to file");
 }
 
-// fn train_dqn_tic_tac_toe_agent(episodes: u64, pb: ProgressBar) {
-//     let mut env = TicTacEnvironment::new();
-//     let agent = Rc::new(RefCell::new(DQNAgent::new()));
-//     agent.borrow_mut().try_init(&env);
-//     let agents = [
-//         agent.clone() as Rc<RefCell<dyn Agent<TicTacEnvironment>>>,
-//         agent.clone() as Rc<RefCell<dyn Agent<TicTacEnvironment>>>,
-//     ];
-//     train::train_q(
-//         &mut env,
-//         &agents as &[Rc<RefCell<dyn Agent<TicTacEnvironment>>>],
-//         episodes,
-//         pb,
-//     );
-//     agent
-//         .borrow()
-//         .save_to_file(DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH)
-//         .expect("Failed to save DQN Q-table to file");
-// }
+fn train_dqn_tic_tac_toe_agent(
+    episodes: u64,
+    seed: Option<u64>,
+    callback: &mut dyn TrainingCallback,
+) {
+    let mut env = TicTacEnvironment::new();
+    let agent = Rc::new(RefCell::new(DQNAgent::new(DQN_BUFFER_CAPACITY)));
+    agent.borrow_mut().try_init(&env);
+    let agents = [
+        agent.clone() as Rc<RefCell<dyn Agent<TicTacEnvironment>>>,
+        agent.clone() as Rc<RefCell<dyn Agent<TicTacEnvironment>>>,
+    ];
+    train::train_q(
+        &mut env,
+        &agents as &[Rc<RefCell<dyn Agent<TicTacEnvironment>>>],
+        episodes,
+        seed,
+        callback,
+    );
+    let mut agent = agent.borrow_mut();
+    agent.metadata.record_training(episodes, seed);
+    agent
+        .save_to_file(DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH)
+        .expect("Failed to save DQN Q-table to file");
+}
+
+/// Trains a DQN agent to reach any cell of the grid, relabeling its episodes with the cells
+/// they reached, and records how often its greedy policy reaches the goal.
+fn train_dqn_reach_goal_agent(
+    episodes: u64,
+    seed: Option<u64>,
+    callback: &mut dyn TrainingCallback,
+) {
+    let mut env = GoalGridEnvironment::new(GRID_SIZE.0, GRID_SIZE.1);
+    let hindsight = HindsightReplay::for_env(&env, GoalStrategy::Future);
+    // Q-values grow towards the goal, which a linear output can follow.
+    let hidden_layers = vec![
+        LayerSpec::dense(64, ActivationFunction::ReLU),
+        LayerSpec::dense(64, ActivationFunction::ReLU),
+    ];
+    let mut agent = DQNAgent::new(DQN_BUFFER_CAPACITY)
+        .with_architecture(hidden_layers, ActivationFunction::Linear)
+        .with_hindsight(hindsight);
+    agent.epsilon = REACH_GOAL_EPSILON;
+    agent.try_init(&env);
+    train::train_dqn(&mut env, &mut agent, episodes, seed, callback);
+    agent.metadata.record_training(episodes, seed);
+    let success_rate = goal_success_rate(&mut env, &agent, 1000);
+    agent
+        .metadata
+        .evaluation
+        .insert("success_rate".to_string(), success_rate);
+    println!(
+        "Greedy policy reaches the goal in {:.1}% of episodes",
+        success_rate * 100.0
+    );
+    agent
+        .save_to_file(DQN_REACH_GOAL_AGENT_SAVE_FILE_PATH)
+        .expect("Failed to save DQN weights to file");
+}
+
+/// The share of `episodes` in which the greedy policy of `agent` reaches the goal.
+fn goal_success_rate(env: &mut GoalGridEnvironment, agent: &DQNAgent, episodes: usize) -> f64 {
+    let mut successes = 0;
+    for _ in 0..episodes {
+        let mut state = env.reset().clone();
+        loop {
+            let action = <DQNAgent as Agent<GoalGridEnvironment>>::predict(agent, &state);
+            let step = env.step(&action);
+            match step.next_state() {
+                Some(next_state) => state = next_state.clone(),
+                None => {
+                    successes += (step.reward()[0] == GOAL_REWARD) as usize;
+                    break;
+                }
+            }
+        }
+    }
+    successes as f64 / episodes as f64
+}
@@ -0,0 +1,206 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use crate::agents::network::fit::EpochStats;
+
+/// Observes training, e.g. to report progress or to collect metrics.
+///
+/// Reinforcement learning reports episodes, supervised training reports batches
+/// and epochs. Every hook does nothing by default.
+pub trait TrainingCallback {
+    /// Called after every episode with its number, counted from 1, and the total
+    /// reward of every player during the episode.
+    #[allow(unused_variables)]
+    fn on_episode_end(&mut self, episode: u64, rewards: &[f32]) {}
+
+    /// Called after every gradient descent step with its number, counted from 1,
+    /// and the loss of the batch.
+    #[allow(unused_variables)]
+    fn on_batch_end(&mut self, batch: u64, loss: f64) {}
+
+    /// Called after every epoch of `NeuralNetwork::fit`.
+    #[allow(unused_variables)]
+    fn on_epoch_end(&mut self, stats: &EpochStats) {}
+
+    /// Called once when training is done, including after early stopping.
+    fn on_train_end(&mut self) {}
+}
+
+/// Reports nothing, for tests and headless training.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Silent;
+
+impl TrainingCallback for Silent {}
+
+/// What the length of a `ProgressBarCallback` counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    Episodes,
+    Batches,
+}
+
+/// Shows the number of episodes or batches on an indicatif progress bar.
+#[derive(Clone, Debug)]
+pub struct ProgressBarCallback {
+    bar: ProgressBar,
+    counts: Progress,
+}
+
+impl ProgressBarCallback {
+    /// Shows progress on `bar`, whose length is the number of episodes or batches.
+    pub fn new(bar: ProgressBar, counts: Progress) -> Self {
+        ProgressBarCallback { bar, counts }
+    }
+
+    /// A progress bar of `len` episodes in the style used by the binaries.
+    pub fn episodes(len: u64, message: &'static str) -> Self {
+        Self::with_len(len, message, Progress::Episodes)
+    }
+
+    /// A progress bar of `len` batches in the style used by the binaries.
+    pub fn batches(len: u64, message: &'static str) -> Self {
+        Self::with_len(len, message, Progress::Batches)
+    }
+
+    fn with_len(len: u64, message: &'static str, counts: Progress) -> Self {
+        let bar = ProgressBar::new(len);
+        bar.set_style(Self::style());
+        bar.set_message(message);
+        ProgressBarCallback { bar, counts }
+    }
+
+    /// Shows the bar in `group`, below the bars added before it, e.g. to train several
+    /// agents at once.
+    pub fn add_to(self, group: &MultiProgress) -> Self {
+        ProgressBarCallback {
+            bar: group.add(self.bar),
+            ..self
+        }
+    }
+
+    fn style() -> ProgressStyle {
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("#>-")
+    }
+
+    pub fn bar(&self) -> &ProgressBar {
+        &self.bar
+    }
+}
+
+impl TrainingCallback for ProgressBarCallback {
+    fn on_episode_end(&mut self, episode: u64, _rewards: &[f32]) {
+        if self.counts == Progress::Episodes {
+            self.bar.set_position(episode);
+        }
+    }
+
+    fn on_batch_end(&mut self, batch: u64, _loss: f64) {
+        if self.counts == Progress::Batches {
+            self.bar.set_position(batch);
+        }
+    }
+
+    fn on_epoch_end(&mut self, stats: &EpochStats) {
+        let message = match stats.validation_loss {
+            Some(validation_loss) => format!(
+                "epoch {} loss {:.4} val {:.4}",
+                stats.epoch + 1,
+                stats.train_loss,
+                validation_loss
+            ),
+            None => format!("epoch {} loss {:.4}", stats.epoch + 1, stats.train_loss),
+        };
+        self.bar.set_message(message);
+    }
+
+    fn on_train_end(&mut self) {
+        self.bar.finish_with_message("Training completed");
+    }
+}
+
+/// Records everything that is reported, e.g. to plot learning curves.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// The total reward of every player, one entry per episode.
+    pub episode_rewards: Vec<Vec<f32>>,
+    /// The loss of every batch.
+    pub batch_losses: Vec<f64>,
+    pub epochs: Vec<EpochStats>,
+    pub finished: bool,
+}
+
+impl Metrics {
+    /// Mean total reward of `player` over the last `window` episodes.
+    pub fn mean_reward(&self, player: usize, window: usize) -> Option<f32> {
+        let recent = &self.episode_rewards[self.episode_rewards.len().saturating_sub(window)..];
+        if recent.is_empty() {
+            return None;
+        }
+        Some(recent.iter().map(|rewards| rewards[player]).sum::<f32>() / recent.len() as f32)
+    }
+}
+
+impl TrainingCallback for Metrics {
+    fn on_episode_end(&mut self, _episode: u64, rewards: &[f32]) {
+        self.episode_rewards.push(rewards.to_vec());
+    }
+
+    fn on_batch_end(&mut self, _batch: u64, loss: f64) {
+        self.batch_losses.push(loss);
+    }
+
+    fn on_epoch_end(&mut self, stats: &EpochStats) {
+        self.epochs.push(stats.clone());
+    }
+
+    fn on_train_end(&mut self) {
+        self.finished = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        agents::{dqn_agent::DQNAgent, q_agent::QAgent},
+        environment::move_to_center::GridEnvironment,
+        train::{train_dqn, train_q},
+        Agent,
+    };
+
+    #[test]
+    fn test_metrics_record_every_episode() {
+        let mut env = GridEnvironment::new(3, 3);
+        let agent = Rc::new(RefCell::new(QAgent::new()));
+        agent.borrow_mut().try_init(&env);
+        let agents = [agent as Rc<RefCell<dyn Agent<GridEnvironment>>>];
+        let mut metrics = Metrics::default();
+        train_q(&mut env, &agents, 5, None, &mut metrics);
+        assert_eq!(metrics.episode_rewards.len(), 5);
+        assert!(metrics.episode_rewards.iter().all(|r| r.len() == 1));
+        assert!(metrics.finished);
+        assert_eq!(metrics.mean_reward(0, 0), None);
+        let last = metrics.episode_rewards[4][0];
+        assert_eq!(metrics.mean_reward(0, 1), Some(last));
+    }
+
+    #[test]
+    fn test_metrics_record_the_loss_of_every_dqn_learning_step() {
+        let mut env = GridEnvironment::new(3, 3);
+        let mut agent = DQNAgent::new(1000);
+        agent.batch_size = 1;
+        <DQNAgent as Agent<GridEnvironment>>::try_init(&mut agent, &env);
+        let mut metrics = Metrics::default();
+        train_dqn(&mut env, &mut agent, 5, None, &mut metrics);
+        assert_eq!(metrics.episode_rewards.len(), 5);
+        // One experience and, with batches of one, one learning step per step.
+        assert_eq!(metrics.batch_losses.len(), agent.memory_buffer.len());
+        assert!(metrics.batch_losses.iter().all(|loss| loss.is_finite()));
+        assert!(metrics.finished);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod move_to_center;
+pub mod reach_goal;
 pub mod tic_tac_toe;
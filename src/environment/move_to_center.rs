@@ -4,23 +4,18 @@ use crate::{Space, SpaceElem, State, StateSpace};
 use rand::{prelude::*, rng};
 use serde::{Deserialize, Serialize};
 
-use crate::{Action, Environment, Step};
+use crate::{Action, Environment, ModelEnvironment, Step, Transition};
 
 /// The Action enum represents the possible actions the agent can take in the environment.
-#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq, Hash, Default)]
 pub enum MoveAction {
+    #[default]
     Up,
     Down,
     Left,
     Right,
 }
 
-impl Default for MoveAction {
-    fn default() -> Self {
-        Self::Up
-    }
-}
-
 impl SpaceElem for MoveAction {
     fn discrete(&self, d: usize) -> Option<usize> {
         match d {
@@ -189,6 +184,8 @@ impl Environment for GridEnvironment {
     type StateSpace = Shape;
     type ActionSpace = MoveActionSpace;
 
+    const ID: &'static str = "move_to_center";
+
     fn action_space(&self) -> &Self::ActionSpace {
         &MoveActionSpace
     }
@@ -197,6 +194,13 @@ impl Environment for GridEnvironment {
         &self.shape
     }
 
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![
+            ("rows", self.shape.rows as f64),
+            ("cols", self.shape.cols as f64),
+        ]
+    }
+
     /// Resets the environment and sets a new random starting position so that our agent does not always start in the top-left corner.
     fn reset(&mut self) -> &Self::State {
         self.reward = 0.0;
@@ -215,7 +219,7 @@ impl Environment for GridEnvironment {
 
     /// Steps through the environment based on the action taken by the agent.
     /// It updates the agent's position, calculates the reward, and checks if the game is finished.
-    fn step(&mut self, action: &Self::Action) -> Step<Self> {
+    fn step(&mut self, action: &Self::Action) -> Step<'_, Self> {
         match action {
             MoveAction::Up => {
                 if self.board.position.0 > 0 {
@@ -268,3 +272,24 @@ impl Environment for GridEnvironment {
         }
     }
 }
+
+impl ModelEnvironment for GridEnvironment {
+    /// Moves are deterministic, so every action has exactly one outcome.
+    /// The outcome is found by stepping a copy of the environment placed in `state`.
+    fn transitions(&self, state: &Board, action: &MoveAction) -> Vec<Transition<Board>> {
+        if state.done {
+            return vec![];
+        }
+        let mut sim = GridEnvironment {
+            shape: self.shape.clone(),
+            board: state.clone(),
+            reward: 0.0,
+        };
+        let Step { reward, next_state } = sim.step(action);
+        vec![Transition {
+            probability: 1.0,
+            reward: reward[0],
+            next_state: next_state.cloned(),
+        }]
+    }
+}
@@ -0,0 +1,182 @@
+use core::slice;
+use std::ops::Range;
+
+use crate::environment::move_to_center::{MoveAction, MoveActionSpace};
+use crate::{Space, SpaceElem, State, StateSpace};
+use rand::{prelude::*, rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{Environment, GoalEnvironment, Step};
+
+/// The reward for the step that reaches the goal. Every other step is worth nothing.
+pub const GOAL_REWARD: f32 = 1.0;
+
+/// The position of the agent and the cell it has to reach.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct GoalBoard {
+    pub position: (usize, usize),
+    pub goal: (usize, usize),
+}
+
+impl SpaceElem for GoalBoard {
+    fn discrete(&self, d: usize) -> Option<usize> {
+        match d {
+            0 => Some(self.position.0),
+            1 => Some(self.position.1),
+            2 => Some(self.goal.0),
+            3 => Some(self.goal.1),
+            _ => None,
+        }
+    }
+
+    fn continuous(&self, _d: usize) -> Option<f32> {
+        None
+    }
+
+    fn try_build(_: &impl Space, discrete: &[usize], continuous: &[f32]) -> Option<Self> {
+        if discrete.len() == 4 && continuous.is_empty() {
+            Some(Self {
+                position: (discrete[0], discrete[1]),
+                goal: (discrete[2], discrete[3]),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl State for GoalBoard {
+    fn current_player(&self) -> usize {
+        0
+    }
+}
+
+/// The rows and columns of the position, followed by those of the goal.
+#[derive(Default, Clone)]
+pub struct GoalShape {
+    rows: usize,
+    cols: usize,
+}
+
+impl Space for GoalShape {
+    fn discrete_dim(&self, d: usize) -> Option<usize> {
+        match d {
+            0 | 2 => Some(self.rows),
+            1 | 3 => Some(self.cols),
+            _ => None,
+        }
+    }
+
+    fn continuous_dim(&self, _d: usize) -> Option<Range<f32>> {
+        None
+    }
+}
+
+impl StateSpace for GoalShape {
+    fn player_count(&self) -> usize {
+        1
+    }
+}
+
+/// A goal-conditioned variant of [`GridEnvironment`](super::move_to_center::GridEnvironment):
+/// every episode places the agent and the goal on random cells, and the goal is part of the state.
+///
+/// The reward is sparse, `GOAL_REWARD` for reaching the goal and nothing otherwise, which is
+/// what [`HindsightReplay`](crate::agents::network::hindsight::HindsightReplay) learns from.
+/// Moves into the walls leave the agent where it is. An episode ends at the goal or after
+/// `max_steps` steps.
+pub struct GoalGridEnvironment {
+    pub shape: GoalShape,
+    pub board: GoalBoard,
+    pub max_steps: usize,
+    steps: usize,
+    reward: f32,
+}
+
+impl GoalGridEnvironment {
+    /// Creates a grid whose episodes last at most as many steps as it takes to cross it twice.
+    pub fn new(rows: usize, cols: usize) -> Self {
+        assert!(rows * cols > 1, "the grid needs a cell besides the goal");
+        GoalGridEnvironment {
+            shape: GoalShape { rows, cols },
+            board: GoalBoard {
+                position: (0, 0),
+                goal: (rows / 2, cols / 2),
+            },
+            max_steps: 2 * (rows + cols),
+            steps: 0,
+            reward: 0.0,
+        }
+    }
+
+    fn random_cell(&self) -> (usize, usize) {
+        (
+            rng().random_range(0..self.shape.rows),
+            rng().random_range(0..self.shape.cols),
+        )
+    }
+}
+
+impl Environment for GoalGridEnvironment {
+    type Action = MoveAction;
+    type State = GoalBoard;
+    type StateSpace = GoalShape;
+    type ActionSpace = MoveActionSpace;
+
+    const ID: &'static str = "reach_goal";
+
+    fn action_space(&self) -> &Self::ActionSpace {
+        &MoveActionSpace
+    }
+
+    fn state_space(&self) -> &Self::StateSpace {
+        &self.shape
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![
+            ("rows", self.shape.rows as f64),
+            ("cols", self.shape.cols as f64),
+            ("max_steps", self.max_steps as f64),
+        ]
+    }
+
+    /// Places the agent and the goal on two different random cells.
+    fn reset(&mut self) -> &Self::State {
+        self.steps = 0;
+        self.reward = 0.0;
+        self.board.goal = self.random_cell();
+        self.board.position = self.board.goal;
+        while self.board.position == self.board.goal {
+            self.board.position = self.random_cell();
+        }
+        &self.board
+    }
+
+    fn step(&mut self, action: &Self::Action) -> Step<'_, Self> {
+        let (row, col) = &mut self.board.position;
+        match action {
+            MoveAction::Up => *row = row.saturating_sub(1),
+            MoveAction::Down => *row = (*row + 1).min(self.shape.rows - 1),
+            MoveAction::Left => *col = col.saturating_sub(1),
+            MoveAction::Right => *col = (*col + 1).min(self.shape.cols - 1),
+        }
+        self.steps += 1;
+        let reached = self.board.position == self.board.goal;
+        self.reward = if reached { GOAL_REWARD } else { 0.0 };
+        Step {
+            reward: slice::from_ref(&self.reward),
+            next_state: (!reached && self.steps < self.max_steps).then_some(&self.board),
+        }
+    }
+}
+
+impl GoalEnvironment for GoalGridEnvironment {
+    fn goal_dims(&self) -> (Range<usize>, Range<usize>) {
+        (0..2, 2..4)
+    }
+
+    fn goal_rewards(&self) -> (f32, f32) {
+        (GOAL_REWARD, 0.0)
+    }
+}
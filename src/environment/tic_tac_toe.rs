@@ -1,4 +1,4 @@
-use crate::{Space, SpaceElem, State, StateSpace};
+use crate::{agents::network::conv::PlaneShape, Space, SpaceElem, State, StateSpace};
 use serde::{Deserialize, Serialize};
 
 use crate::{Action, Environment, Step};
@@ -110,6 +110,12 @@ impl SpaceElem for Board {
     }
 }
 
+impl Board {
+    /// The shape of `State::planes`: one plane each for the X, O and empty
+    /// cells, and one that is all ones if X is to move and all zeros if O is.
+    pub const PLANES: PlaneShape = PlaneShape::new(4, 3, 3);
+}
+
 impl State for Board {
     fn current_player(&self) -> usize {
         match self.player {
@@ -117,6 +123,24 @@ impl State for Board {
             TicTacPlayer::O => 1,
         }
     }
+
+    fn planes(&self) -> Option<Vec<f64>> {
+        let mut planes = vec![0.0; Self::PLANES.size()];
+        let plane_size = Self::PLANES.plane_size();
+        for (i, cell) in self.cells.iter().flatten().enumerate() {
+            let plane = match cell {
+                CellState::X => 0,
+                CellState::O => 1,
+                CellState::Empty => 2,
+            };
+            planes[plane * plane_size + i] = 1.0;
+        }
+        // The last plane is the player to move.
+        if self.player == TicTacPlayer::X {
+            planes[(Self::PLANES.channels - 1) * plane_size..].fill(1.0);
+        }
+        Some(planes)
+    }
 }
 
 #[derive(Default, Clone)]
@@ -172,6 +196,12 @@ pub struct TicTacEnvironment {
     pub reward: [f32; 2],
 }
 
+impl Default for TicTacEnvironment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TicTacEnvironment {
     pub fn new() -> Self {
         TicTacEnvironment {
@@ -232,7 +262,7 @@ impl TicTacEnvironment {
                     self.reward = [-1.0, 1.0]; // O wins
                     self.board.done = true;
                 }
-                CellState::Empty => return, // No winner yet
+                CellState::Empty => {} // No winner yet
             }
         }
     }
@@ -250,6 +280,8 @@ impl Environment for TicTacEnvironment {
     type StateSpace = Shape;
     type ActionSpace = TicTacActionSpace;
 
+    const ID: &'static str = "tic_tac_toe";
+
     fn action_space(&self) -> &Self::ActionSpace {
         &TicTacActionSpace
     }
@@ -266,7 +298,7 @@ impl Environment for TicTacEnvironment {
 
     /// Steps through the environment based on the action taken by the agent.
     /// It updates the agent's position, calculates the reward, and checks if the game is finished.
-    fn step(&mut self, action: &Self::Action) -> Step<Self> {
+    fn step(&mut self, action: &Self::Action) -> Step<'_, Self> {
         match self.board.player {
             TicTacPlayer::X => {
                 if self.board.cells[action.0][action.1] == CellState::Empty {
@@ -1,14 +1,18 @@
-use rand::random_range;
+use rand::Rng;
 use serde::Serialize;
 use std::ops::Range;
 
 pub mod agents;
+pub mod callback;
 pub mod environment;
 pub mod train;
 
 pub const GRID_AGENT_SAVE_FILE_PATH: &str = "data/q_tables/grid.json";
 pub const TIC_TAC_TOE_AGENT_SAVE_FILE_PATH: &str = "data/q_tables/tic_tac_toe.json";
 pub const DQN_TIC_TAC_TOE_AGENT_SAVE_FILE_PATH: &str = "data/weights/dqn_tic_tac_toe.json";
+pub const DQN_REACH_GOAL_AGENT_SAVE_FILE_PATH: &str = "data/weights/dqn_reach_goal.json";
+/// The rows and columns of the grid that the saved grid agent is trained and served on.
+pub const GRID_SIZE: (usize, usize) = (9, 9);
 
 /// A Generalization of spaces, both state and action spaces
 pub trait Space: Default + Clone {
@@ -58,16 +62,21 @@ pub trait SpaceElem: Sized + Serialize {
 // In that case we should return a Option<Box<Self>> instead of Option<Self>
 pub trait Action: SpaceElem + Default {
     fn gen_random(space: &impl Space) -> Option<Self> {
+        Self::gen_random_with(space, &mut rand::rng())
+    }
+
+    /// Like `gen_random`, drawing from `rng`, e.g. a seeded one.
+    fn gen_random_with(space: &impl Space, rng: &mut impl Rng) -> Option<Self> {
         let mut discrete_dims = vec![];
         let mut d = 0;
         while let Some(dim) = space.discrete_dim(d) {
-            discrete_dims.push(random_range(0..dim));
+            discrete_dims.push(rng.random_range(0..dim));
             d += 1;
         }
         let mut continuous_dims = vec![];
         let mut d = 0;
         while let Some(range) = space.continuous_dim(d) {
-            continuous_dims.push(random_range(range.start..range.end));
+            continuous_dims.push(rng.random_range(range.start..range.end));
             d += 1;
         }
         let r = Self::try_build(space, &discrete_dims, &continuous_dims);
@@ -104,12 +113,31 @@ pub struct Step<'a, E: Environment + ?Sized> {
     next_state: Option<&'a E::State>,
 }
 
+impl<'a, E: Environment + ?Sized> Step<'a, E> {
+    /// The reward of every player for the step.
+    pub fn reward(&self) -> &'a [f32] {
+        self.reward
+    }
+
+    /// The state after the step, `None` if it ended the episode.
+    pub fn next_state(&self) -> Option<&'a E::State> {
+        self.next_state
+    }
+}
+
 pub trait StateSpace: Space {
     fn player_count(&self) -> usize;
 }
 
-trait State: SpaceElem + Clone {
+pub trait State: SpaceElem + Clone {
     fn current_player(&self) -> usize;
+
+    /// The state as one-hot planes, laid out as described by
+    /// [`PlaneShape`](agents::network::conv::PlaneShape), for convolutional networks.
+    /// `None` if the state is not board-shaped.
+    fn planes(&self) -> Option<Vec<f64>> {
+        None
+    }
 }
 
 pub trait Environment {
@@ -118,9 +146,17 @@ pub trait Environment {
     type State: State;
     type Action: Action;
 
+    /// Identifies the environment in the files of saved agents.
+    const ID: &'static str = "";
+
     fn state_space(&self) -> &Self::StateSpace;
     fn action_space(&self) -> &Self::ActionSpace;
 
+    /// The settings of the environment that saved agents record, e.g. the size of a grid.
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        Vec::new()
+    }
+
     /// Resets environment, returning the initial state
     fn reset(&mut self) -> &Self::State;
 
@@ -128,6 +164,41 @@ pub trait Environment {
     fn step<'a>(&'a mut self, action: &Self::Action) -> Step<'a, Self>;
 }
 
+/// One possible outcome of taking an action, see [`ModelEnvironment`].
+#[derive(Debug, Clone)]
+pub struct Transition<S> {
+    pub probability: f32,
+    pub reward: f32,
+    /// `None` if the outcome ends the episode.
+    pub next_state: Option<S>,
+}
+
+/// An environment that exposes its transition model, so that it can be solved
+/// exactly with dynamic programming instead of being learned from experience.
+pub trait ModelEnvironment: Environment {
+    /// Returns every possible outcome of taking `action` in `state`, with
+    /// probabilities that sum to one.
+    /// Terminal states, in which no action can be taken, return no outcomes.
+    fn transitions(
+        &self,
+        state: &Self::State,
+        action: &Self::Action,
+    ) -> Vec<Transition<Self::State>>;
+}
+
+/// An environment whose states hold the goal the agent has to reach next to what it has
+/// reached so far, so that experiences can be relabeled with the goals that were reached
+/// instead, see [`HindsightReplay`](agents::network::hindsight::HindsightReplay).
+/// Every step is rewarded by whether it reaches the goal alone.
+pub trait GoalEnvironment: Environment {
+    /// The state dimensions that hold what was reached, and those that hold the goal,
+    /// in the same order and the same units.
+    fn goal_dims(&self) -> (Range<usize>, Range<usize>);
+
+    /// The reward for a step that reaches the goal, which ends the episode, and for any other step.
+    fn goal_rewards(&self) -> (f32, f32);
+}
+
 pub trait Agent<E: Environment> {
     /// Reads spaces and initializes agent
     /// Returns false if the agent does not support the given spaces.
@@ -160,6 +231,11 @@ pub trait Agent<E: Environment> {
     ) {
     }
 
+    /// Seeds the randomness of `act`, e.g. the exploration of ε-greedy agents, so that
+    /// a training run can be repeated. Agents that do not explore ignore it.
+    #[allow(unused_variables)]
+    fn seed(&mut self, seed: u64) {}
+
     /// Selects the most preferred action, as opposed to `act`, which may
     /// do something worse to learn.
     /// # Arguments
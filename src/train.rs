@@ -1,26 +1,35 @@
 use std::{cell::RefCell, rc::Rc};
 
-use indicatif::ProgressBar;
-
-use crate::{Agent, Environment, State, StateSpace, Step};
+use crate::{
+    agents::dqn_agent::DQNAgent, callback::TrainingCallback, Agent, Environment, State, StateSpace,
+    Step,
+};
 
 /// Trains the agent by running a specified number of episodes in the environment.
 /// Each episode consists of the agent taking actions in the environment until a terminal state is reached. e.g. the agent either won or lost.
+/// A `seed` seeds the exploration of the agent of every player, offset by the player.
 pub fn train_q<E: Environment>(
     env: &mut E,
     agents: &[Rc<RefCell<dyn Agent<E>>>],
     episodes: u64,
-    pb: ProgressBar,
+    seed: Option<u64>,
+    callback: &mut dyn TrainingCallback,
 ) {
     assert!(
         env.state_space().player_count() == agents.len(),
         "Number of agents must match the number of players in the environment."
     );
+    if let Some(seed) = seed {
+        for (player, agent) in agents.iter().enumerate() {
+            agent.borrow_mut().seed(seed.wrapping_add(player as u64));
+        }
+    }
 
     for episode in 1..=episodes {
         let state = env.reset().clone();
         let mut o_state = Some(state);
         let mut rewards = vec![0.0; agents.len()];
+        let mut total_rewards = vec![0.0; agents.len()];
         let mut prev = vec![];
         prev.resize_with(agents.len(), || None);
         while let Some(state) = o_state {
@@ -42,6 +51,7 @@ pub fn train_q<E: Environment>(
             // Update the rewards
             for player in 0..agents.len() {
                 rewards[player] += reward[player];
+                total_rewards[player] += reward[player];
             }
             // Remember the action and the state
             prev[current_player] = Some((state.clone(), action));
@@ -57,25 +67,51 @@ pub fn train_q<E: Environment>(
                     .learn(prev_state, action, rewards[player], None);
             }
         }
-        pb.set_position(episode);
+        callback.on_episode_end(episode, &total_rewards);
     }
-    pb.finish_with_message("Training completed");
+    callback.on_train_end();
 }
 
+/// Trains a DQN agent in a single-player environment. Besides every episode, the loss of
+/// every learning step is reported to `callback`. A `seed` seeds the exploration of the agent.
 pub fn train_dqn<E: Environment>(
     env: &mut E,
-    agent: &mut dyn Agent<E>,
+    agent: &mut DQNAgent,
     episodes: u64,
-    pb: ProgressBar,
+    seed: Option<u64>,
+    callback: &mut dyn TrainingCallback,
 ) {
     assert!(
         env.state_space().player_count() == 1,
         "DQN agent can only be used in single-player environments."
     );
+    if let Some(seed) = seed {
+        <DQNAgent as Agent<E>>::seed(agent, seed);
+    }
 
     for episode in 1..=episodes {
-        todo!("Implement DQN training logic here");
-        pb.set_position(episode);
+        let mut state = env.reset().clone();
+        let mut total_reward = 0.0;
+        loop {
+            let action = <DQNAgent as Agent<E>>::act(agent, &state);
+            let Step { reward, next_state } = env.step(&action);
+            let next_state = next_state.cloned();
+            total_reward += reward[0];
+            // The agent stores the transition and learns from a sampled batch,
+            // a terminal transition flushes its pending n-step returns.
+            agent.learn_with_callback::<E>(
+                &state,
+                &action,
+                reward[0],
+                next_state.as_ref(),
+                callback,
+            );
+            match next_state {
+                Some(next_state) => state = next_state,
+                None => break,
+            }
+        }
+        callback.on_episode_end(episode, &[total_reward]);
     }
-    pb.finish_with_message("Training completed");
+    callback.on_train_end();
 }